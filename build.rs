@@ -0,0 +1,63 @@
+//! Compiles every shader under `shaders/` to SPIR-V and generates
+//! `$OUT_DIR/shaders.rs`, which embeds them into the binary.
+
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+#[path = "src/shader_compiler.rs"]
+mod shader_compiler;
+
+const SHADER_DIR: &str = "shaders";
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", SHADER_DIR);
+    println!("cargo:rerun-if-env-changed=GLSLC");
+    println!("cargo:rerun-if-env-changed=VULKAN_SDK");
+
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let mut sources = Vec::new();
+    collect_sources(Path::new(SHADER_DIR), &mut sources);
+    sources.sort();
+
+    let mut failed = false;
+    let mut generated = String::from("/// SPIR-V of the shaders under `shaders/`, keyed by their path relative to it.\n");
+    generated.push_str("pub const EMBEDDED_SHADERS: &[(&str, &[u8])] = &[\n");
+    for source in &sources {
+        println!("cargo:rerun-if-changed={}", source.display());
+        let name = source.strip_prefix(SHADER_DIR).unwrap().to_string_lossy().replace('\\', "/");
+        match shader_compiler::compile(source) {
+            Ok(words) => {
+                let output = out_dir.join(format!("{}.spv", name.replace('/', "_")));
+                let bytes = words.iter().flat_map(|w| w.to_le_bytes()).collect::<Vec<_>>();
+                fs::write(&output, bytes).unwrap();
+                writeln!(generated, "    ({:?}, include_bytes!({:?})),", name, output).unwrap();
+            }
+            Err(diagnostics) => {
+                eprintln!("error: failed to compile shader `{}`:\n{}", source.display(), diagnostics);
+                failed = true;
+            }
+        }
+    }
+    generated.push_str("];\n");
+
+    if failed {
+        process::exit(1);
+    }
+    fs::write(out_dir.join("shaders.rs"), generated).unwrap();
+}
+
+fn collect_sources(dir: &Path, sources: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else { return };
+    for entry in entries {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            println!("cargo:rerun-if-changed={}", path.display());
+            collect_sources(&path, sources);
+        } else if shader_compiler::is_shader_source(&path) {
+            sources.push(path);
+        }
+    }
+}
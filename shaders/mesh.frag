@@ -0,0 +1,21 @@
+#version 450
+
+// naga does not accept combined `sampler2D` uniforms, so the texture and
+// sampler are bound separately.
+layout(set = 0, binding = 1) uniform texture2D diffuseTexture;
+layout(set = 0, binding = 2) uniform sampler diffuseSampler;
+
+layout(location = 0) in vec3 fragNormal;
+layout(location = 1) in vec2 fragUv;
+layout(location = 2) in vec3 fragColor;
+
+layout(location = 0) out vec4 outColor;
+
+const vec3 lightDirection = vec3(0.4, 0.8, 0.45);
+const float ambient = 0.2;
+
+void main() {
+    vec4 texel = texture(sampler2D(diffuseTexture, diffuseSampler), fragUv);
+    float diffuse = max(dot(normalize(fragNormal), normalize(lightDirection)), 0.0);
+    outColor = vec4(texel.rgb * fragColor * (ambient + (1.0 - ambient) * diffuse), texel.a);
+}
@@ -0,0 +1,28 @@
+#version 450
+
+layout(set = 0, binding = 0) uniform Camera {
+    mat4 view;
+    mat4 proj;
+    vec4 eye;
+} camera;
+
+layout(push_constant) uniform Push {
+    mat4 model;
+} push;
+
+layout(location = 0) in vec3 inPosition;
+layout(location = 1) in vec3 inNormal;
+layout(location = 2) in vec2 inUv;
+layout(location = 3) in vec3 inColor;
+
+layout(location = 0) out vec3 fragNormal;
+layout(location = 1) out vec2 fragUv;
+layout(location = 2) out vec3 fragColor;
+
+void main() {
+    gl_Position = camera.proj * camera.view * push.model * vec4(inPosition, 1.0);
+    // Assumes the model matrix scales uniformly.
+    fragNormal = mat3(push.model) * inNormal;
+    fragUv = inUv;
+    fragColor = inColor;
+}
@@ -0,0 +1,428 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+
+use vulkanalia::prelude::v1_0::*;
+
+use crate::camera::{CameraMode, ProjectionMode};
+use crate::golden::{GoldenOptions, Tolerance};
+use crate::input::Bindings;
+use crate::msaa::parse_sample_count;
+use crate::model::NormalMode;
+use crate::pipeline_cache::default_cache_dir;
+use crate::swapchain::VsyncPolicy;
+use crate::sync::DEFAULT_FRAMES_IN_FLIGHT;
+use crate::texture::DEFAULT_ANISOTROPY;
+
+/// Environment variable consulted when `--device` is not given.
+pub const DEVICE_ENV: &str = "VULKAN_WORKS_DEVICE";
+
+/// The shader sources the binary was built from.
+const SHADER_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/shaders");
+
+/// Command line options for the app.
+#[derive(Clone, Debug)]
+pub struct Args {
+    /// Forces a specific physical device instead of the best scoring one.
+    pub device: Option<DeviceSelector>,
+    /// Prints the available physical devices instead of opening a window.
+    pub list_devices: bool,
+    /// Output format of `--list-devices`.
+    pub format: OutputFormat,
+    /// Renders into an offscreen image without creating a window or surface.
+    pub headless: bool,
+    /// Size of the window or offscreen image.
+    pub size: vk::Extent2D,
+    /// Present mode preference of the swapchain.
+    pub vsync: VsyncPolicy,
+    /// Number of frames the CPU may record ahead of the GPU.
+    pub frames_in_flight: usize,
+    /// Writes the last rendered frame to this PNG file before exiting.
+    pub screenshot: Option<PathBuf>,
+    /// Exits after rendering this many frames.
+    pub frames: Option<u32>,
+    /// Runs the golden-image scenes against the references in this directory.
+    pub golden: Option<PathBuf>,
+    /// Rewrites the golden references instead of comparing against them.
+    pub golden_update: bool,
+    /// Where actual and diff images of failed golden scenes are written.
+    pub golden_output: PathBuf,
+    pub golden_tolerance: Tolerance,
+    /// Recompiles changed shaders and rebuilds the pipelines using them.
+    pub hot_reload: bool,
+    /// Directory of the shader sources watched by `--hot-reload`.
+    pub shader_dir: PathBuf,
+    /// Directory the pipeline cache is loaded from and saved to.
+    pub pipeline_cache: Option<PathBuf>,
+    /// `.obj` model loaded at startup.
+    pub model: Option<PathBuf>,
+    /// How normals are generated for meshes of `model` that have none.
+    pub normals: NormalMode,
+    /// PNG texture loaded at startup instead of the model's diffuse texture.
+    pub texture: Option<PathBuf>,
+    /// Requested sampler anisotropy, clamped to the device limit.
+    pub anisotropy: f32,
+    /// Controller of the camera looking at the model.
+    pub camera: CameraMode,
+    pub projection: ProjectionMode,
+    /// Buttons of the camera controls, changed with `--bind action=buttons`.
+    pub bindings: Bindings,
+    /// Requested samples per pixel, clamped to what the device supports.
+    pub msaa: u32,
+    /// Minimum fraction of samples shaded individually, if sample shading
+    /// is requested.
+    pub sample_shading: Option<f32>,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            device: None,
+            list_devices: false,
+            format: OutputFormat::default(),
+            headless: false,
+            size: vk::Extent2D { width: 1024, height: 768 },
+            vsync: VsyncPolicy::default(),
+            frames_in_flight: DEFAULT_FRAMES_IN_FLIGHT,
+            screenshot: None,
+            frames: None,
+            golden: None,
+            golden_update: false,
+            golden_output: PathBuf::from("target/golden"),
+            golden_tolerance: Tolerance::default(),
+            hot_reload: false,
+            shader_dir: PathBuf::from(SHADER_DIR),
+            pipeline_cache: default_cache_dir(),
+            model: None,
+            normals: NormalMode::default(),
+            texture: None,
+            anisotropy: DEFAULT_ANISOTROPY,
+            camera: CameraMode::default(),
+            projection: ProjectionMode::default(),
+            bindings: Bindings::default(),
+            msaa: 1,
+            sample_shading: None,
+        }
+    }
+}
+
+impl Args {
+    /// Parses the process arguments, falling back to the environment.
+    pub fn from_env() -> Result<Self> {
+        let env = std::env::var(DEVICE_ENV).ok();
+        Self::parse(std::env::args().skip(1), env.as_deref())
+    }
+
+    /// Parses `args` (without the program name); `device_env` is the value of
+    /// [`DEVICE_ENV`] and only applies when `--device` is absent.
+    pub fn parse<I>(args: I, device_env: Option<&str>) -> Result<Self>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut result = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+                _ => (arg, None),
+            };
+            let mut value = || {
+                inline
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| anyhow!("Missing value for `{}`.", flag))
+            };
+            match flag.as_str() {
+                "--device" => result.device = Some(value()?.parse()?),
+                "--list-devices" => result.list_devices = true,
+                "--format" => result.format = value()?.parse()?,
+                "--headless" => result.headless = true,
+                "--size" => result.size = parse_size(&value()?)?,
+                "--vsync" => result.vsync = value()?.parse()?,
+                "--frames-in-flight" => {
+                    let frames = value()?;
+                    result.frames_in_flight = frames
+                        .parse()
+                        .ok()
+                        .filter(|f| *f > 0)
+                        .ok_or_else(|| anyhow!("Invalid frames in flight `{}`.", frames))?;
+                }
+                "--screenshot" => result.screenshot = Some(value()?.into()),
+                "--frames" => {
+                    let frames = value()?;
+                    result.frames = Some(
+                        frames
+                            .parse()
+                            .ok()
+                            .filter(|f| *f > 0)
+                            .ok_or_else(|| anyhow!("Invalid frame count `{}`.", frames))?,
+                    );
+                }
+                "--golden" => result.golden = Some(value()?.into()),
+                "--golden-update" => result.golden_update = true,
+                "--golden-output" => result.golden_output = value()?.into(),
+                "--golden-tolerance" => result.golden_tolerance.channel = parse_number(&flag, &value()?)?,
+                "--golden-mismatch" => result.golden_tolerance.mismatched_fraction = parse_number(&flag, &value()?)?,
+                "--golden-delta-e" => result.golden_tolerance.mean_delta_e = parse_number(&flag, &value()?)?,
+                "--hot-reload" => result.hot_reload = true,
+                "--shader-dir" => result.shader_dir = value()?.into(),
+                "--pipeline-cache" => result.pipeline_cache = Some(value()?.into()),
+                "--no-pipeline-cache" => result.pipeline_cache = None,
+                "--model" => result.model = Some(value()?.into()),
+                "--normals" => result.normals = value()?.parse()?,
+                "--texture" => result.texture = Some(value()?.into()),
+                "--anisotropy" => result.anisotropy = parse_number(&flag, &value()?)?,
+                "--camera" => result.camera = value()?.parse()?,
+                "--projection" => result.projection = value()?.parse()?,
+                "--bind" => result.bindings.bind(&value()?)?,
+                "--msaa" => result.msaa = parse_sample_count(&value()?)?,
+                "--sample-shading" => {
+                    let fraction = value()?;
+                    result.sample_shading = Some(
+                        fraction
+                            .parse()
+                            .ok()
+                            .filter(|f| (0.0..=1.0).contains(f))
+                            .ok_or_else(|| anyhow!("Invalid sample shading fraction `{}`.", fraction))?,
+                    );
+                }
+                _ => return Err(anyhow!("Unknown argument `{}`.", flag)),
+            }
+        }
+
+        if result.device.is_none() {
+            if let Some(env) = device_env.filter(|e| !e.is_empty()) {
+                result.device = Some(env.parse()?);
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Options of the golden-image mode, if requested.
+    pub fn golden_options(&self) -> Option<GoldenOptions> {
+        self.golden.as_ref().map(|dir| GoldenOptions {
+            reference_dir: dir.clone(),
+            output_dir: self.golden_output.clone(),
+            update: self.golden_update,
+            tolerance: self.golden_tolerance,
+        })
+    }
+
+    /// Number of frames to render before exiting, if limited. A screenshot
+    /// without `--frames` is taken of the first frame (and always in headless
+    /// mode, which renders a single frame by default).
+    pub fn frame_limit(&self) -> Option<u32> {
+        if self.headless || self.screenshot.is_some() {
+            Some(self.frames.unwrap_or(1))
+        } else {
+            self.frames
+        }
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(flag: &str, s: &str) -> Result<T> {
+    s.parse().map_err(|_| anyhow!("Invalid value `{}` for `{}`.", s, flag))
+}
+
+/// Parses a `WIDTHxHEIGHT` size such as `1024x768`.
+fn parse_size(s: &str) -> Result<vk::Extent2D> {
+    let (width, height) = s
+        .split_once('x')
+        .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+        .filter(|(w, h)| *w > 0 && *h > 0)
+        .ok_or_else(|| anyhow!("Invalid size `{}` (expected e.g. `1024x768`).", s))?;
+    Ok(vk::Extent2D { width, height })
+}
+
+/// Output format of diagnostic modes.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(anyhow!("Unknown output format `{}` (expected `text` or `json`).", s)),
+        }
+    }
+}
+
+/// Identifies a physical device by enumeration index, name or PCI IDs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceSelector {
+    /// Position in `vkEnumeratePhysicalDevices` (e.g. `1`).
+    Index(usize),
+    /// Case-insensitive substring of the device name (e.g. `llvmpipe`).
+    Name(String),
+    /// Hexadecimal vendor ID with an optional device ID (e.g. `10de:1b80`).
+    Id { vendor: u32, device: Option<u32> },
+}
+
+impl DeviceSelector {
+    pub fn matches(&self, index: usize, properties: &vk::PhysicalDeviceProperties) -> bool {
+        match self {
+            Self::Index(i) => *i == index,
+            Self::Name(name) => properties
+                .device_name
+                .to_string_lossy()
+                .to_lowercase()
+                .contains(&name.to_lowercase()),
+            Self::Id { vendor, device } => {
+                *vendor == properties.vendor_id && device.map_or(true, |d| d == properties.device_id)
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for DeviceSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(anyhow!("Empty device selector."));
+        }
+
+        if let Ok(index) = s.parse() {
+            return Ok(Self::Index(index));
+        }
+
+        let hex = |v: &str| u32::from_str_radix(v.trim_start_matches("0x").trim_start_matches("0X"), 16);
+        if let Some((vendor, device)) = s.split_once(':') {
+            if let (Ok(vendor), Ok(device)) = (hex(vendor), hex(device)) {
+                return Ok(Self::Id { vendor, device: Some(device) });
+            }
+        } else if s.starts_with("0x") || s.starts_with("0X") {
+            if let Ok(vendor) = hex(s) {
+                return Ok(Self::Id { vendor, device: None });
+            }
+        }
+
+        Ok(Self::Name(s.to_string()))
+    }
+}
+
+impl fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Index(index) => write!(f, "#{}", index),
+            Self::Name(name) => write!(f, "{}", name),
+            Self::Id { vendor, device: Some(device) } => write!(f, "{:04x}:{:04x}", vendor, device),
+            Self::Id { vendor, device: None } => write!(f, "0x{:04x}", vendor),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str], device_env: Option<&str>) -> Result<Args> {
+        Args::parse(args.iter().map(|a| a.to_string()), device_env)
+    }
+
+    fn properties(name: &str, vendor_id: u32, device_id: u32) -> vk::PhysicalDeviceProperties {
+        let device_name = vk::StringArray::from_bytes(name.as_bytes());
+        vk::PhysicalDeviceProperties { device_name, vendor_id, device_id, ..Default::default() }
+    }
+
+    #[test]
+    fn defaults() {
+        let args = parse(&[], None).unwrap();
+        assert_eq!(args.device, None);
+        assert!(!args.list_devices);
+        assert_eq!(args.format, OutputFormat::Text);
+        assert!(!args.headless);
+        assert_eq!((args.size.width, args.size.height), (1024, 768));
+        assert_eq!(args.screenshot, None);
+        assert_eq!(args.frames_in_flight, DEFAULT_FRAMES_IN_FLIGHT);
+        assert_eq!(args.frame_limit(), None);
+    }
+
+    #[test]
+    fn separate_and_inline_values() {
+        let args = parse(&["--device", "1"], None).unwrap();
+        assert_eq!(args.device, Some(DeviceSelector::Index(1)));
+        let args = parse(&["--device=llvmpipe"], None).unwrap();
+        assert_eq!(args.device, Some(DeviceSelector::Name("llvmpipe".into())));
+        let args = parse(&["--list-devices", "--format=json"], None).unwrap();
+        assert!(args.list_devices);
+        assert_eq!(args.format, OutputFormat::Json);
+        let args = parse(&["--headless", "--size", "640x480"], None).unwrap();
+        assert!(args.headless);
+        assert_eq!((args.size.width, args.size.height), (640, 480));
+        let args = parse(&["--frames=3", "--headless"], None).unwrap();
+        assert_eq!(args.frames, Some(3));
+        assert_eq!(args.frame_limit(), Some(3));
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        assert!(parse(&["--bogus"], None).is_err());
+        assert!(parse(&["--device"], None).is_err());
+        assert!(parse(&["--device="], None).is_err());
+        assert!(parse(&["--format", "xml"], None).is_err());
+        assert!(parse(&["--size"], None).is_err());
+        assert!(parse(&["--size", "640"], None).is_err());
+        assert!(parse(&["--size", "0x480"], None).is_err());
+        assert!(parse(&["--frames-in-flight", "0"], None).is_err());
+        assert!(parse(&["--frames", "-1"], None).is_err());
+        assert!(parse(&["--sample-shading", "1.5"], None).is_err());
+    }
+
+    #[test]
+    fn device_flag_overrides_environment() {
+        let args = parse(&[], Some("llvmpipe")).unwrap();
+        assert_eq!(args.device, Some(DeviceSelector::Name("llvmpipe".into())));
+        let args = parse(&["--device", "1"], Some("llvmpipe")).unwrap();
+        assert_eq!(args.device, Some(DeviceSelector::Index(1)));
+        let args = parse(&[], Some("")).unwrap();
+        assert_eq!(args.device, None);
+    }
+
+    #[test]
+    fn screenshot_defaults_to_first_frame() {
+        let args = parse(&["--screenshot", "out.png"], None).unwrap();
+        assert_eq!(args.frame_limit(), Some(1));
+    }
+
+    #[test]
+    fn device_selectors() {
+        assert_eq!("2".parse::<DeviceSelector>().unwrap(), DeviceSelector::Index(2));
+        assert_eq!(
+            "10de:1b80".parse::<DeviceSelector>().unwrap(),
+            DeviceSelector::Id { vendor: 0x10de, device: Some(0x1b80) },
+        );
+        assert_eq!("0x10DE".parse::<DeviceSelector>().unwrap(), DeviceSelector::Id { vendor: 0x10de, device: None });
+        assert_eq!(" llvmpipe ".parse::<DeviceSelector>().unwrap(), DeviceSelector::Name("llvmpipe".into()));
+        assert_eq!("AMD:Radeon".parse::<DeviceSelector>().unwrap(), DeviceSelector::Name("AMD:Radeon".into()));
+        assert!("  ".parse::<DeviceSelector>().is_err());
+    }
+
+    #[test]
+    fn device_selector_matching() {
+        let properties = properties("llvmpipe (LLVM 15.0.7, 256 bits)", 0x10005, 0);
+        assert!(DeviceSelector::Index(1).matches(1, &properties));
+        assert!(!DeviceSelector::Index(0).matches(1, &properties));
+        assert!(DeviceSelector::Name("LLVMpipe".into()).matches(0, &properties));
+        assert!(DeviceSelector::Id { vendor: 0x10005, device: None }.matches(0, &properties));
+        assert!(DeviceSelector::Id { vendor: 0x10005, device: Some(0) }.matches(0, &properties));
+        assert!(!DeviceSelector::Id { vendor: 0x10005, device: Some(1) }.matches(0, &properties));
+    }
+
+    #[test]
+    fn device_selector_display_round_trips() {
+        for s in ["10de:1b80", "0x10de", "llvmpipe"] {
+            assert_eq!(s.parse::<DeviceSelector>().unwrap().to_string(), s);
+        }
+    }
+}
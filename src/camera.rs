@@ -0,0 +1,360 @@
+use std::f32::consts::FRAC_PI_2;
+
+use anyhow::{anyhow, Result};
+use nalgebra_glm as glm;
+
+/// Pitch is kept this far from straight up or down so the view never
+/// becomes parallel to [`up`].
+const PITCH_LIMIT: f32 = FRAC_PI_2 - 0.01;
+
+/// Closest an orbit camera gets to its target.
+const MIN_DISTANCE: f32 = 1e-3;
+
+/// World space up; the scene is Y-up and right-handed.
+pub fn up() -> glm::Vec3 {
+    glm::vec3(0.0, 1.0, 0.0)
+}
+
+/// The unit vector `yaw` radians right of `-Z` and `pitch` radians above the
+/// horizon.
+pub fn direction(yaw: f32, pitch: f32) -> glm::Vec3 {
+    glm::vec3(pitch.cos() * yaw.sin(), pitch.sin(), -pitch.cos() * yaw.cos())
+}
+
+/// How view space is projected to clip space.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Projection {
+    /// `fov_y` is the vertical field of view in radians.
+    Perspective { fov_y: f32, near: f32, far: f32 },
+    /// `height` is the extent of the view volume along view space Y.
+    Orthographic { height: f32, near: f32, far: f32 },
+}
+
+impl Projection {
+    /// The projection matrix for a target with `aspect` = width / height,
+    /// following Vulkan's conventions: clip space Y points down and depth
+    /// maps `near..far` to `0..1`.
+    pub fn matrix(&self, aspect: f32) -> glm::Mat4 {
+        let mut matrix = match *self {
+            Self::Perspective { fov_y, near, far } => glm::perspective_rh_zo(aspect, fov_y, near, far),
+            Self::Orthographic { height, near, far } => {
+                let (x, y) = (height * aspect / 2.0, height / 2.0);
+                glm::ortho_rh_zo(-x, x, -y, y, near, far)
+            }
+        };
+        matrix[(1, 1)] *= -1.0;
+        matrix
+    }
+}
+
+/// The view matrix of an eye at `eye` looking at `target`.
+pub fn look_at(eye: &glm::Vec3, target: &glm::Vec3) -> glm::Mat4 {
+    glm::look_at_rh(eye, target, &up())
+}
+
+/// Circles a target at a distance.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct OrbitController {
+    pub target: glm::Vec3,
+    pub distance: f32,
+    pub yaw: f32,
+    pub pitch: f32,
+}
+
+impl OrbitController {
+    pub fn eye(&self) -> glm::Vec3 {
+        self.target - direction(self.yaw, self.pitch) * self.distance
+    }
+
+    /// Moves around the target, `yaw` to the right and `pitch` up.
+    pub fn rotate(&mut self, yaw: f32, pitch: f32) {
+        self.yaw += yaw;
+        self.pitch = (self.pitch + pitch).clamp(-PITCH_LIMIT, PITCH_LIMIT);
+    }
+
+    /// Multiplies the distance to the target by `factor`.
+    pub fn zoom(&mut self, factor: f32) {
+        self.distance = (self.distance * factor).max(MIN_DISTANCE);
+    }
+
+    /// Moves the target across the view, in multiples of the distance to it.
+    pub fn pan(&mut self, right: f32, up: f32) {
+        let forward = direction(self.yaw, self.pitch);
+        let right_axis = forward.cross(&self::up()).normalize();
+        let up_axis = right_axis.cross(&forward);
+        self.target += (right_axis * right + up_axis * up) * self.distance;
+    }
+}
+
+/// Moves freely, looking around from its position.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FlyController {
+    pub position: glm::Vec3,
+    pub yaw: f32,
+    pub pitch: f32,
+}
+
+impl FlyController {
+    pub fn forward(&self) -> glm::Vec3 {
+        direction(self.yaw, self.pitch)
+    }
+
+    /// Turns `yaw` to the right and `pitch` up.
+    pub fn look(&mut self, yaw: f32, pitch: f32) {
+        self.yaw += yaw;
+        self.pitch = (self.pitch + pitch).clamp(-PITCH_LIMIT, PITCH_LIMIT);
+    }
+
+    /// Moves along the horizontal right and forward directions of the view
+    /// and along world up.
+    pub fn translate(&mut self, right: f32, up: f32, forward: f32) {
+        let horizontal = direction(self.yaw, 0.0);
+        let right_axis = horizontal.cross(&self::up());
+        self.position += right_axis * right + self::up() * up + horizontal * forward;
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CameraController {
+    Orbit(OrbitController),
+    Fly(FlyController),
+}
+
+/// Which controller `--camera` selects.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum CameraMode {
+    #[default]
+    Orbit,
+    Fly,
+}
+
+impl std::str::FromStr for CameraMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "orbit" => Ok(Self::Orbit),
+            "fly" => Ok(Self::Fly),
+            _ => Err(anyhow!("Unknown camera `{}` (expected `orbit` or `fly`).", s)),
+        }
+    }
+}
+
+/// Which projection `--projection` selects.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ProjectionMode {
+    #[default]
+    Perspective,
+    Orthographic,
+}
+
+impl std::str::FromStr for ProjectionMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "perspective" => Ok(Self::Perspective),
+            "orthographic" => Ok(Self::Orthographic),
+            _ => Err(anyhow!("Unknown projection `{}` (expected `perspective` or `orthographic`).", s)),
+        }
+    }
+}
+
+/// The uniform block `Camera` of `mesh.vert`, laid out as std140.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CameraUniform {
+    pub view: glm::Mat4,
+    pub proj: glm::Mat4,
+    /// The eye position, `w` is unused.
+    pub eye: glm::Vec4,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Camera {
+    pub controller: CameraController,
+    pub projection: Projection,
+    /// Size of the framed scene, which movement speeds are relative to.
+    pub scale: f32,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self::framing(CameraMode::default(), ProjectionMode::default(), &glm::Vec3::zeros(), 1.0)
+    }
+}
+
+impl Camera {
+    /// Vertical field of view of perspective cameras.
+    pub const FOV_Y: f32 = std::f32::consts::FRAC_PI_4;
+
+    /// A camera looking down `-Z` at a sphere around `center`, far enough
+    /// back that all of it is in view.
+    pub fn framing(mode: CameraMode, projection: ProjectionMode, center: &glm::Vec3, radius: f32) -> Self {
+        let radius = radius.max(MIN_DISTANCE);
+        let distance = radius / (Self::FOV_Y / 2.0).sin() * 1.1;
+        let (near, far) = (distance * 1e-3, distance + radius * 10.0);
+        let projection = match projection {
+            ProjectionMode::Perspective => Projection::Perspective { fov_y: Self::FOV_Y, near, far },
+            ProjectionMode::Orthographic => Projection::Orthographic { height: radius * 2.2, near, far },
+        };
+        let controller = match mode {
+            CameraMode::Orbit => CameraController::Orbit(OrbitController { target: *center, distance, yaw: 0.0, pitch: 0.0 }),
+            CameraMode::Fly => CameraController::Fly(FlyController {
+                position: center + glm::vec3(0.0, 0.0, distance),
+                yaw: 0.0,
+                pitch: 0.0,
+            }),
+        };
+        Self { controller, projection, scale: radius }
+    }
+
+    pub fn eye(&self) -> glm::Vec3 {
+        match &self.controller {
+            CameraController::Orbit(orbit) => orbit.eye(),
+            CameraController::Fly(fly) => fly.position,
+        }
+    }
+
+    pub fn view(&self) -> glm::Mat4 {
+        match &self.controller {
+            CameraController::Orbit(orbit) => look_at(&orbit.eye(), &orbit.target),
+            CameraController::Fly(fly) => look_at(&fly.position, &(fly.position + fly.forward())),
+        }
+    }
+
+    /// The uniform data for a `width` x `height` render target.
+    pub fn uniform(&self, width: u32, height: u32) -> CameraUniform {
+        let aspect = width as f32 / height.max(1) as f32;
+        CameraUniform {
+            view: self.view(),
+            proj: self.projection.matrix(aspect),
+            eye: glm::vec3_to_vec4(&self.eye()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERSPECTIVE: Projection = Projection::Perspective { fov_y: FRAC_PI_2, near: 0.1, far: 100.0 };
+    const ORTHOGRAPHIC: Projection = Projection::Orthographic { height: 4.0, near: 1.0, far: 11.0 };
+
+    /// Normalized device coordinates of the view space `point`.
+    fn project(projection: &Projection, aspect: f32, point: glm::Vec3) -> glm::Vec3 {
+        let clip = projection.matrix(aspect) * glm::vec4(point.x, point.y, point.z, 1.0);
+        assert!(clip.w > 0.0, "{:?} is behind the eye", point);
+        clip.xyz() / clip.w
+    }
+
+    fn assert_close(a: glm::Vec3, b: glm::Vec3) {
+        assert!(glm::distance(&a, &b) < 1e-4, "{:?} != {:?}", a, b);
+    }
+
+    fn assert_xy(ndc: glm::Vec3, x: f32, y: f32) {
+        assert_close(glm::vec3(ndc.x, ndc.y, 0.0), glm::vec3(x, y, 0.0));
+    }
+
+    #[test]
+    fn perspective_y_points_down() {
+        let ndc = project(&PERSPECTIVE, 1.0, glm::vec3(0.5, 0.5, -1.0));
+        assert!(ndc.x > 0.0);
+        assert!(ndc.y < 0.0);
+        // A 90 degree field of view reaches the edges at 45 degrees.
+        assert_xy(project(&PERSPECTIVE, 1.0, glm::vec3(1.0, 1.0, -1.0)), 1.0, -1.0);
+    }
+
+    #[test]
+    fn perspective_depth_is_zero_to_one() {
+        assert!(project(&PERSPECTIVE, 1.0, glm::vec3(0.0, 0.0, -0.1)).z.abs() < 1e-5);
+        assert!((project(&PERSPECTIVE, 1.0, glm::vec3(0.0, 0.0, -100.0)).z - 1.0).abs() < 1e-5);
+        let middle = project(&PERSPECTIVE, 1.0, glm::vec3(0.0, 0.0, -10.0)).z;
+        assert!(middle > 0.0 && middle < 1.0);
+        // Most of the depth range goes to what is close to the eye.
+        assert!(middle > 0.9);
+    }
+
+    #[test]
+    fn perspective_aspect_narrows_x() {
+        assert_xy(project(&PERSPECTIVE, 2.0, glm::vec3(1.0, 1.0, -1.0)), 0.5, -1.0);
+    }
+
+    #[test]
+    fn orthographic_y_points_down() {
+        assert_xy(project(&ORTHOGRAPHIC, 1.0, glm::vec3(2.0, 2.0, -5.0)), 1.0, -1.0);
+        assert_xy(project(&ORTHOGRAPHIC, 2.0, glm::vec3(2.0, -1.0, -5.0)), 0.5, 0.5);
+    }
+
+    #[test]
+    fn orthographic_depth_is_linear() {
+        assert!(project(&ORTHOGRAPHIC, 1.0, glm::vec3(0.0, 0.0, -1.0)).z.abs() < 1e-6);
+        assert!((project(&ORTHOGRAPHIC, 1.0, glm::vec3(0.0, 0.0, -6.0)).z - 0.5).abs() < 1e-6);
+        assert!((project(&ORTHOGRAPHIC, 1.0, glm::vec3(0.0, 0.0, -11.0)).z - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn directions() {
+        assert_close(direction(0.0, 0.0), glm::vec3(0.0, 0.0, -1.0));
+        assert_close(direction(FRAC_PI_2, 0.0), glm::vec3(1.0, 0.0, 0.0));
+        assert_close(direction(0.0, FRAC_PI_2), up());
+    }
+
+    #[test]
+    fn framed_scene_is_centered_and_in_depth_range() {
+        let center = glm::vec3(1.0, 2.0, 3.0);
+        for mode in [CameraMode::Orbit, CameraMode::Fly] {
+            for projection in [ProjectionMode::Perspective, ProjectionMode::Orthographic] {
+                let camera = Camera::framing(mode, projection, &center, 2.0);
+                let uniform = camera.uniform(800, 600);
+                let project = |p: glm::Vec3| {
+                    let clip = uniform.proj * uniform.view * glm::vec4(p.x, p.y, p.z, 1.0);
+                    clip.xyz() / clip.w
+                };
+                let ndc = project(center);
+                assert_xy(ndc, 0.0, 0.0);
+                assert!(ndc.z > 0.0 && ndc.z < 1.0, "{:?}", ndc);
+                // The top of the sphere is in view, in the upper half.
+                let top = project(center + glm::vec3(0.0, 2.0, 0.0));
+                assert!(top.y < 0.0 && top.y > -1.0, "{:?} {:?}: {:?}", mode, projection, top);
+            }
+        }
+    }
+
+    #[test]
+    fn orbit() {
+        let mut orbit = OrbitController { target: glm::vec3(0.0, 0.0, 0.0), distance: 2.0, yaw: 0.0, pitch: 0.0 };
+        assert_close(orbit.eye(), glm::vec3(0.0, 0.0, 2.0));
+        orbit.rotate(FRAC_PI_2, 0.0);
+        assert_close(orbit.eye(), glm::vec3(-2.0, 0.0, 0.0));
+        orbit.rotate(0.0, 10.0);
+        assert_eq!(orbit.pitch, PITCH_LIMIT);
+        orbit.zoom(0.0);
+        assert_eq!(orbit.distance, MIN_DISTANCE);
+    }
+
+    #[test]
+    fn orbit_pan_moves_the_target_across_the_view() {
+        let mut orbit = OrbitController { target: glm::vec3(0.0, 0.0, 0.0), distance: 2.0, yaw: 0.0, pitch: 0.0 };
+        orbit.pan(1.0, 0.5);
+        assert_close(orbit.target, glm::vec3(2.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn fly_moves_horizontally() {
+        let mut fly = FlyController { position: glm::vec3(0.0, 0.0, 0.0), yaw: 0.0, pitch: 0.0 };
+        fly.look(0.0, -10.0);
+        assert_eq!(fly.pitch, -PITCH_LIMIT);
+        // Looking down does not make forward movement sink.
+        fly.translate(1.0, 2.0, 3.0);
+        assert_close(fly.position, glm::vec3(1.0, 2.0, -3.0));
+    }
+
+    #[test]
+    fn parse_modes() {
+        assert_eq!("fly".parse::<CameraMode>().unwrap(), CameraMode::Fly);
+        assert_eq!("orthographic".parse::<ProjectionMode>().unwrap(), ProjectionMode::Orthographic);
+        assert!("free".parse::<CameraMode>().is_err());
+        assert!("isometric".parse::<ProjectionMode>().is_err());
+    }
+}
@@ -0,0 +1,334 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::ptr::copy_nonoverlapping as memcpy;
+
+use anyhow::{anyhow, Context, Result};
+
+use vulkanalia::prelude::v1_0::*;
+
+use crate::commands::{begin_single_time_commands, end_single_time_commands};
+use crate::memory::{create_buffer, destroy_buffer, Allocation, AllocationRequest};
+use crate::AppData;
+
+/// A rendered frame read back to the host as tightly packed sRGB RGBA8.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CapturedFrame {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl CapturedFrame {
+    /// Writes the frame as an 8-bit RGBA PNG tagged as sRGB.
+    pub fn write_png<W: Write>(&self, w: W) -> Result<()> {
+        let mut encoder = png::Encoder::new(w, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_source_srgb(png::SrgbRenderingIntent::Perceptual);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.pixels)?;
+        Ok(())
+    }
+
+    pub fn save_png(&self, path: &Path) -> Result<()> {
+        let file = File::create(path).with_context(|| format!("Failed to create `{}`.", path.display()))?;
+        self.write_png(BufWriter::new(file))
+            .with_context(|| format!("Failed to write `{}`.", path.display()))
+    }
+}
+
+/// Checks that frames can be copied out of the render target images.
+pub fn check_capture_support(data: &AppData) -> Result<()> {
+    if data.target_usage.contains(vk::ImageUsageFlags::TRANSFER_SRC) {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "The render target images do not support TRANSFER_SRC usage, so frames cannot be captured \
+             (try `--headless`)."
+        ))
+    }
+}
+
+/// Copies `image` (currently in `layout`) into host memory and converts it
+/// to sRGB RGBA8. The image must have been created with `TRANSFER_SRC` usage.
+pub unsafe fn capture_image(
+    device: &Device,
+    data: &mut AppData,
+    image: vk::Image,
+    layout: vk::ImageLayout,
+) -> Result<CapturedFrame> {
+    check_capture_support(data)?;
+
+    let extent = data.target_extent;
+    let (buffer, allocation) = create_readback_buffer(device, data, extent)?;
+
+    let result = (|| {
+        let command_buffer = begin_single_time_commands(device, data)?;
+        cmd_copy_to_buffer(device, command_buffer, image, layout, buffer, extent);
+        end_single_time_commands(device, data, command_buffer)?;
+        read_back(&allocation, extent, data.target_format)
+    })();
+
+    destroy_buffer(device, data, buffer, &allocation);
+    result
+}
+
+/// A readback buffer the next rendered frame copies its swapchain image into,
+/// before the image is handed over for presentation.
+#[derive(Copy, Clone, Debug)]
+pub struct PendingCapture {
+    pub buffer: vk::Buffer,
+    pub allocation: Allocation,
+    pub extent: vk::Extent2D,
+    pub format: vk::Format,
+    /// The fence of the submission containing the copy, once recorded.
+    pub fence: Option<vk::Fence>,
+}
+
+/// Makes the frames recorded from now on copy their image for a capture.
+pub unsafe fn request_capture(device: &Device, data: &mut AppData) -> Result<()> {
+    check_capture_support(data)?;
+    destroy_pending_capture(device, data);
+    let extent = data.target_extent;
+    let (buffer, allocation) = create_readback_buffer(device, data, extent)?;
+    data.pending_capture = Some(PendingCapture { buffer, allocation, extent, format: data.target_format, fence: None });
+    Ok(())
+}
+
+/// Records the copy of a requested capture out of `image`, which the render
+/// pass just left in its final layout.
+pub unsafe fn cmd_record_capture(device: &Device, data: &AppData, command_buffer: vk::CommandBuffer, image: vk::Image) {
+    if let Some(capture) = &data.pending_capture {
+        let layout = data.render_pass_final_layout;
+        cmd_copy_to_buffer(device, command_buffer, image, layout, capture.buffer, capture.extent);
+    }
+}
+
+/// Waits for the frame a capture was recorded into and reads it back.
+pub unsafe fn finish_capture(device: &Device, data: &mut AppData) -> Result<CapturedFrame> {
+    let capture = data.pending_capture.ok_or_else(|| anyhow!("No capture was requested."))?;
+    let result = match capture.fence {
+        Some(fence) => device
+            .wait_for_fences(&[fence], true, u64::MAX)
+            .map_err(|e| anyhow!(e))
+            .and_then(|_| read_back(&capture.allocation, capture.extent, capture.format)),
+        None => Err(anyhow!("No rendered frame to capture.")),
+    };
+    destroy_pending_capture(device, data);
+    result
+}
+
+pub unsafe fn destroy_pending_capture(device: &Device, data: &mut AppData) {
+    if let Some(capture) = data.pending_capture.take() {
+        destroy_buffer(device, data, capture.buffer, &capture.allocation);
+    }
+}
+
+/// Creates a host visible buffer holding `extent` worth of 4-byte texels.
+unsafe fn create_readback_buffer(
+    device: &Device,
+    data: &mut AppData,
+    extent: vk::Extent2D,
+) -> Result<(vk::Buffer, Allocation)> {
+    create_buffer(
+        device,
+        data,
+        extent.width as u64 * extent.height as u64 * 4,
+        vk::BufferUsageFlags::TRANSFER_DST,
+        AllocationRequest { preferred: vk::MemoryPropertyFlags::HOST_CACHED, ..AllocationRequest::host_visible() },
+    )
+}
+
+/// Records a copy of `image` (last written as a color attachment and now in
+/// `layout`) into `buffer`, made visible to host reads.
+unsafe fn cmd_copy_to_buffer(
+    device: &Device,
+    command_buffer: vk::CommandBuffer,
+    image: vk::Image,
+    layout: vk::ImageLayout,
+    buffer: vk::Buffer,
+    extent: vk::Extent2D,
+) {
+    transition(
+        device,
+        command_buffer,
+        image,
+        (layout, vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT, vk::AccessFlags::COLOR_ATTACHMENT_WRITE),
+        (vk::ImageLayout::TRANSFER_SRC_OPTIMAL, vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_READ),
+    );
+
+    let subresource = vk::ImageSubresourceLayers::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .mip_level(0)
+        .base_array_layer(0)
+        .layer_count(1);
+    let region = vk::BufferImageCopy::builder()
+        .buffer_offset(0)
+        .buffer_row_length(0)
+        .buffer_image_height(0)
+        .image_subresource(subresource)
+        .image_offset(vk::Offset3D::default())
+        .image_extent(vk::Extent3D { width: extent.width, height: extent.height, depth: 1 });
+    device.cmd_copy_image_to_buffer(
+        command_buffer,
+        image,
+        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+        buffer,
+        &[region],
+    );
+
+    let barrier = vk::BufferMemoryBarrier::builder()
+        .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+        .dst_access_mask(vk::AccessFlags::HOST_READ)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .buffer(buffer)
+        .offset(0)
+        .size(vk::WHOLE_SIZE as u64);
+    device.cmd_pipeline_barrier(
+        command_buffer,
+        vk::PipelineStageFlags::TRANSFER,
+        vk::PipelineStageFlags::HOST,
+        vk::DependencyFlags::empty(),
+        &[] as &[vk::MemoryBarrier],
+        &[barrier],
+        &[] as &[vk::ImageMemoryBarrier],
+    );
+
+    if layout != vk::ImageLayout::TRANSFER_SRC_OPTIMAL {
+        transition(
+            device,
+            command_buffer,
+            image,
+            (vk::ImageLayout::TRANSFER_SRC_OPTIMAL, vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_READ),
+            (layout, vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT, vk::AccessFlags::COLOR_ATTACHMENT_WRITE),
+        );
+    }
+}
+
+/// Reads `extent` worth of `format` texels back from a mapped `allocation`.
+unsafe fn read_back(allocation: &Allocation, extent: vk::Extent2D, format: vk::Format) -> Result<CapturedFrame> {
+    let mut texels = vec![0u8; extent.width as usize * extent.height as usize * 4];
+    let source = allocation.mapped.ok_or_else(|| anyhow!("Capture buffer is not mapped."))?;
+    memcpy(source.as_ptr(), texels.as_mut_ptr(), texels.len());
+
+    Ok(CapturedFrame {
+        width: extent.width,
+        height: extent.height,
+        pixels: to_srgb_rgba8(format, &texels)?,
+    })
+}
+
+/// Records an image barrier from one `(layout, stage, access)` use to another.
+unsafe fn transition(
+    device: &Device,
+    command_buffer: vk::CommandBuffer,
+    image: vk::Image,
+    (old_layout, src_stage_mask, src_access_mask): (vk::ImageLayout, vk::PipelineStageFlags, vk::AccessFlags),
+    (new_layout, dst_stage_mask, dst_access_mask): (vk::ImageLayout, vk::PipelineStageFlags, vk::AccessFlags),
+) {
+    let subresource = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(1)
+        .base_array_layer(0)
+        .layer_count(1);
+    let barrier = vk::ImageMemoryBarrier::builder()
+        .old_layout(old_layout)
+        .new_layout(new_layout)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .image(image)
+        .subresource_range(subresource)
+        .src_access_mask(src_access_mask)
+        .dst_access_mask(dst_access_mask);
+    device.cmd_pipeline_barrier(
+        command_buffer,
+        src_stage_mask,
+        dst_stage_mask,
+        vk::DependencyFlags::empty(),
+        &[] as &[vk::MemoryBarrier],
+        &[] as &[vk::BufferMemoryBarrier],
+        &[barrier],
+    );
+}
+
+/// Converts 4-byte texels in `format` to sRGB encoded RGBA8.
+///
+/// `*_SRGB` formats already hold sRGB encoded values and are only swizzled;
+/// `*_UNORM` formats hold linear values which are encoded to sRGB.
+pub fn to_srgb_rgba8(format: vk::Format, texels: &[u8]) -> Result<Vec<u8>> {
+    let (bgra, linear) = match format {
+        vk::Format::R8G8B8A8_SRGB => (false, false),
+        vk::Format::B8G8R8A8_SRGB => (true, false),
+        vk::Format::R8G8B8A8_UNORM => (false, true),
+        vk::Format::B8G8R8A8_UNORM => (true, true),
+        _ => return Err(anyhow!("Unsupported capture format {:?}.", format)),
+    };
+
+    let encode: [u8; 256] = std::array::from_fn(|i| if linear { linear_to_srgb(i as u8) } else { i as u8 });
+
+    Ok(texels
+        .chunks_exact(4)
+        .flat_map(|t| {
+            let (r, g, b) = if bgra { (t[2], t[1], t[0]) } else { (t[0], t[1], t[2]) };
+            [encode[r as usize], encode[g as usize], encode[b as usize], t[3]]
+        })
+        .collect())
+}
+
+/// Encodes a linear 8-bit channel value with the sRGB transfer function.
+pub fn linear_to_srgb(value: u8) -> u8 {
+    let linear = value as f32 / 255.0;
+    let srgb = if linear <= 0.003_130_8 {
+        linear * 12.92
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    };
+    (srgb * 255.0).round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn srgb_targets_are_only_swizzled() {
+        let texels = [10, 20, 30, 40, 50, 60, 70, 80];
+        assert_eq!(to_srgb_rgba8(vk::Format::R8G8B8A8_SRGB, &texels).unwrap(), texels);
+        assert_eq!(to_srgb_rgba8(vk::Format::B8G8R8A8_SRGB, &texels).unwrap(), [30, 20, 10, 40, 70, 60, 50, 80]);
+    }
+
+    #[test]
+    fn linear_targets_are_encoded() {
+        let texels = [0, 128, 255, 128];
+        let encoded = [0, linear_to_srgb(128), 255, 128];
+        assert_eq!(to_srgb_rgba8(vk::Format::R8G8B8A8_UNORM, &texels).unwrap(), encoded);
+        assert_eq!(to_srgb_rgba8(vk::Format::B8G8R8A8_UNORM, &texels).unwrap(), [255, linear_to_srgb(128), 0, 128]);
+    }
+
+    #[test]
+    fn capture_needs_transfer_source_targets() {
+        let mut data = AppData { target_usage: vk::ImageUsageFlags::COLOR_ATTACHMENT, ..Default::default() };
+        assert!(check_capture_support(&data).is_err());
+        data.target_usage |= vk::ImageUsageFlags::TRANSFER_SRC;
+        assert!(check_capture_support(&data).is_ok());
+    }
+
+    #[test]
+    fn unsupported_formats() {
+        assert!(to_srgb_rgba8(vk::Format::R16G16B16A16_SFLOAT, &[0; 8]).is_err());
+    }
+
+    #[test]
+    fn linear_to_srgb_curve() {
+        assert_eq!(linear_to_srgb(0), 0);
+        assert_eq!(linear_to_srgb(255), 255);
+        // 0.5 linear is about 0.735 in sRGB
+        assert_eq!(linear_to_srgb(128), 188);
+        // Dark values are brightened the most
+        assert_eq!(linear_to_srgb(1), 13);
+        assert!((1..=255).all(|v| linear_to_srgb(v) >= linear_to_srgb(v - 1)));
+    }
+}
@@ -0,0 +1,144 @@
+use std::mem::size_of;
+
+use anyhow::Result;
+use nalgebra_glm as glm;
+
+use vulkanalia::prelude::v1_0::*;
+
+use crate::capture::cmd_record_capture;
+use crate::device::QueueFamilyIndices;
+use crate::model::Model;
+use crate::swapchain::{cmd_release_to_present, needs_ownership_transfer};
+use crate::AppData;
+
+/// Default color the render target is cleared to at the start of every frame.
+pub const CLEAR_COLOR: [f32; 4] = [0.0, 0.0, 0.0, 1.0];
+
+pub unsafe fn create_command_pool(instance: &Instance, device: &Device, data: &mut AppData) -> Result<()> {
+    let indices = QueueFamilyIndices::get(instance, data, data.physical_device)?;
+    let info = vk::CommandPoolCreateInfo::builder()
+        .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+        .queue_family_index(indices.graphics);
+    data.command_pool = device.create_command_pool(&info, None)?;
+    Ok(())
+}
+
+pub unsafe fn allocate_command_buffers(device: &Device, data: &AppData, count: u32) -> Result<Vec<vk::CommandBuffer>> {
+    let info = vk::CommandBufferAllocateInfo::builder()
+        .command_pool(data.command_pool)
+        .level(vk::CommandBufferLevel::PRIMARY)
+        .command_buffer_count(count);
+    Ok(device.allocate_command_buffers(&info)?)
+}
+
+/// Records the commands rendering a frame into `data.framebuffers[image_index]`,
+/// using the uniform buffer and descriptor set of `frame`, and the copy of a
+/// requested capture.
+pub unsafe fn record_command_buffer(
+    device: &Device,
+    data: &AppData,
+    command_buffer: vk::CommandBuffer,
+    image_index: usize,
+    frame: usize,
+) -> Result<()> {
+    device.reset_command_buffer(command_buffer, vk::CommandBufferResetFlags::empty())?;
+    let info = vk::CommandBufferBeginInfo::builder().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+    device.begin_command_buffer(command_buffer, &info)?;
+
+    let render_area = vk::Rect2D::builder()
+        .offset(vk::Offset2D::default())
+        .extent(data.target_extent);
+    let color_clear_value = vk::ClearValue {
+        color: vk::ClearColorValue { float32: data.clear_color },
+    };
+    let depth_clear_value = vk::ClearValue {
+        depth_stencil: vk::ClearDepthStencilValue { depth: 1.0, stencil: 0 },
+    };
+    let clear_values = &[color_clear_value, depth_clear_value];
+    let info = vk::RenderPassBeginInfo::builder()
+        .render_pass(data.render_pass)
+        .framebuffer(data.framebuffers[image_index])
+        .render_area(render_area)
+        .clear_values(clear_values);
+    device.cmd_begin_render_pass(command_buffer, &info, vk::SubpassContents::INLINE);
+    cmd_set_viewport_and_scissor(device, data, command_buffer);
+    if data.draw_triangle {
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, data.pipelines.get(data.triangle_pipeline).pipeline);
+        device.cmd_draw(command_buffer, 3, 1, 0, 0);
+    }
+    if let Some(model) = &data.model {
+        cmd_draw_model(device, data, command_buffer, model, frame);
+    }
+    device.cmd_end_render_pass(command_buffer);
+    cmd_record_capture(device, data, command_buffer, data.target_images[image_index]);
+
+    if needs_ownership_transfer(data) {
+        cmd_release_to_present(device, data, command_buffer, data.target_images[image_index]);
+    }
+
+    device.end_command_buffer(command_buffer)?;
+    Ok(())
+}
+
+/// Draws every mesh of `model` with the mesh pipeline.
+unsafe fn cmd_draw_model(device: &Device, data: &AppData, command_buffer: vk::CommandBuffer, model: &Model, frame: usize) {
+    let pipeline = data.pipelines.get(data.mesh_pipeline);
+    device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline.pipeline);
+    device.cmd_bind_descriptor_sets(
+        command_buffer,
+        vk::PipelineBindPoint::GRAPHICS,
+        pipeline.layout,
+        0,
+        &[data.descriptor_sets[frame]],
+        &[],
+    );
+    let transform = glm::identity::<f32, 4>();
+    let transform = std::slice::from_raw_parts(transform.as_ptr().cast::<u8>(), size_of::<glm::Mat4>());
+    device.cmd_push_constants(command_buffer, pipeline.layout, vk::ShaderStageFlags::VERTEX, 0, transform);
+    device.cmd_bind_vertex_buffers(command_buffer, 0, &[model.vertex_buffer], &[0]);
+    device.cmd_bind_index_buffer(command_buffer, model.index_buffer, 0, vk::IndexType::UINT32);
+    for mesh in &model.meshes {
+        device.cmd_draw_indexed(command_buffer, mesh.index_count, 1, mesh.first_index, mesh.vertex_offset, 0);
+    }
+}
+
+/// Sets the dynamic viewport and scissor to cover the whole render target.
+unsafe fn cmd_set_viewport_and_scissor(device: &Device, data: &AppData, command_buffer: vk::CommandBuffer) {
+    let viewport = vk::Viewport::builder()
+        .x(0.0)
+        .y(0.0)
+        .width(data.target_extent.width as f32)
+        .height(data.target_extent.height as f32)
+        .min_depth(0.0)
+        .max_depth(1.0);
+    let scissor = vk::Rect2D::builder()
+        .offset(vk::Offset2D::default())
+        .extent(data.target_extent);
+    device.cmd_set_viewport(command_buffer, 0, &[viewport]);
+    device.cmd_set_scissor(command_buffer, 0, &[scissor]);
+}
+
+/// Allocates and begins a command buffer for a one-off submission.
+pub unsafe fn begin_single_time_commands(device: &Device, data: &AppData) -> Result<vk::CommandBuffer> {
+    let command_buffer = allocate_command_buffers(device, data, 1)?[0];
+    let info = vk::CommandBufferBeginInfo::builder().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+    device.begin_command_buffer(command_buffer, &info)?;
+    Ok(command_buffer)
+}
+
+/// Submits `command_buffer` to the graphics queue, waits for it and frees it.
+pub unsafe fn end_single_time_commands(
+    device: &Device,
+    data: &AppData,
+    command_buffer: vk::CommandBuffer,
+) -> Result<()> {
+    device.end_command_buffer(command_buffer)?;
+
+    let command_buffers = &[command_buffer];
+    let info = vk::SubmitInfo::builder().command_buffers(command_buffers);
+    device.queue_submit(data.graphics_queue, &[info], vk::Fence::null())?;
+    device.queue_wait_idle(data.graphics_queue)?;
+
+    device.free_command_buffers(data.command_pool, command_buffers);
+    Ok(())
+}
@@ -0,0 +1,141 @@
+use anyhow::{anyhow, Result};
+use log::*;
+
+use vulkanalia::prelude::v1_0::*;
+
+use crate::memory::allocate_image_memory;
+use crate::msaa::transient_attachment_request;
+use crate::AppData;
+
+/// Depth formats in order of preference.
+pub const DEPTH_FORMATS: &[vk::Format] = &[
+    vk::Format::D32_SFLOAT,
+    vk::Format::D32_SFLOAT_S8_UINT,
+    vk::Format::D24_UNORM_S8_UINT,
+];
+
+/// The first of `candidates` usable as an optimally tiled depth attachment,
+/// given the properties of each format.
+pub fn choose_depth_format<F>(candidates: &[vk::Format], properties: F) -> Option<vk::Format>
+where
+    F: Fn(vk::Format) -> vk::FormatProperties,
+{
+    candidates.iter().copied().find(|f| {
+        properties(*f)
+            .optimal_tiling_features
+            .contains(vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT)
+    })
+}
+
+pub fn has_stencil_component(format: vk::Format) -> bool {
+    matches!(format, vk::Format::D32_SFLOAT_S8_UINT | vk::Format::D24_UNORM_S8_UINT | vk::Format::D16_UNORM_S8_UINT)
+}
+
+/// Picks the depth format of the selected physical device.
+pub unsafe fn get_depth_format(instance: &Instance, data: &AppData) -> Result<vk::Format> {
+    let format = choose_depth_format(DEPTH_FORMATS, |f| {
+        instance.get_physical_device_format_properties(data.physical_device, f)
+    })
+    .ok_or_else(|| anyhow!("None of the depth formats {:?} is supported.", DEPTH_FORMATS))?;
+    info!("Depth format: {:?}.", format);
+    Ok(format)
+}
+
+/// Creates the depth attachment in `data.depth_format` with `data.msaa_samples`
+/// covering the render target. It depends on the target's size, so it is
+/// recreated with it.
+pub unsafe fn create_depth_objects(device: &Device, data: &mut AppData) -> Result<()> {
+    let info = vk::ImageCreateInfo::builder()
+        .image_type(vk::ImageType::_2D)
+        .extent(vk::Extent3D { width: data.target_extent.width, height: data.target_extent.height, depth: 1 })
+        .mip_levels(1)
+        .array_layers(1)
+        .format(data.depth_format)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .samples(data.msaa_samples);
+    data.depth_image = device.create_image(&info, None)?;
+
+    let request = transient_attachment_request();
+    data.depth_image_allocation = allocate_image_memory(device, data, data.depth_image, vk::ImageTiling::OPTIMAL, request)?;
+
+    let mut aspect_mask = vk::ImageAspectFlags::DEPTH;
+    if has_stencil_component(data.depth_format) {
+        aspect_mask |= vk::ImageAspectFlags::STENCIL;
+    }
+    let subresource_range = vk::ImageSubresourceRange::builder()
+        .aspect_mask(aspect_mask)
+        .base_mip_level(0)
+        .level_count(1)
+        .base_array_layer(0)
+        .layer_count(1);
+    let info = vk::ImageViewCreateInfo::builder()
+        .image(data.depth_image)
+        .view_type(vk::ImageViewType::_2D)
+        .format(data.depth_format)
+        .subresource_range(subresource_range);
+    data.depth_image_view = device.create_image_view(&info, None)?;
+    Ok(())
+}
+
+pub unsafe fn destroy_depth_objects(device: &Device, data: &mut AppData) {
+    device.destroy_image_view(data.depth_image_view, None);
+    device.destroy_image(data.depth_image, None);
+    data.allocator.free(device, &data.depth_image_allocation);
+    data.depth_image_view = vk::ImageView::null();
+    data.depth_image = vk::Image::null();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Properties of `format` on a device supporting depth attachments in
+    /// `optimal` formats, and only linearly tiled ones in `linear` formats.
+    fn properties(format: vk::Format, optimal: &[vk::Format], linear: &[vk::Format]) -> vk::FormatProperties {
+        let features = |formats: &[vk::Format]| {
+            if formats.contains(&format) {
+                vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT | vk::FormatFeatureFlags::SAMPLED_IMAGE
+            } else {
+                vk::FormatFeatureFlags::empty()
+            }
+        };
+        vk::FormatProperties {
+            optimal_tiling_features: features(optimal),
+            linear_tiling_features: features(linear),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn first_supported_format_is_preferred() {
+        let all = |f| properties(f, DEPTH_FORMATS, &[]);
+        assert_eq!(choose_depth_format(DEPTH_FORMATS, all), Some(vk::Format::D32_SFLOAT));
+        let stencil = |f| properties(f, &[vk::Format::D24_UNORM_S8_UINT, vk::Format::D32_SFLOAT_S8_UINT], &[]);
+        assert_eq!(choose_depth_format(DEPTH_FORMATS, stencil), Some(vk::Format::D32_SFLOAT_S8_UINT));
+    }
+
+    #[test]
+    fn linear_tiling_is_not_enough() {
+        let linear = |f| properties(f, &[vk::Format::D24_UNORM_S8_UINT], &[vk::Format::D32_SFLOAT]);
+        assert_eq!(choose_depth_format(DEPTH_FORMATS, linear), Some(vk::Format::D24_UNORM_S8_UINT));
+    }
+
+    #[test]
+    fn no_supported_format() {
+        assert_eq!(choose_depth_format(DEPTH_FORMATS, |f| properties(f, &[vk::Format::D16_UNORM], &[])), None);
+        assert_eq!(choose_depth_format(&[], |f| properties(f, DEPTH_FORMATS, &[])), None);
+    }
+
+    #[test]
+    fn stencil_components() {
+        assert!(!has_stencil_component(vk::Format::D32_SFLOAT));
+        assert!(!has_stencil_component(vk::Format::D16_UNORM));
+        assert!(has_stencil_component(vk::Format::D32_SFLOAT_S8_UINT));
+        assert!(has_stencil_component(vk::Format::D24_UNORM_S8_UINT));
+        assert!(has_stencil_component(vk::Format::D16_UNORM_S8_UINT));
+        assert!(!has_stencil_component(vk::Format::R8G8B8A8_UNORM));
+    }
+}
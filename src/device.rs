@@ -0,0 +1,466 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+
+use anyhow::{anyhow, Result};
+use log::*;
+use thiserror::Error;
+
+use vulkanalia::prelude::v1_0::*;
+use vulkanalia::vk::KhrSurfaceExtension;
+
+use crate::args::DeviceSelector;
+use crate::features::{feature_list, negotiate_features};
+use crate::swapchain::SwapchainSupport;
+use crate::{AppData, VALIDATION_ENABLED, VALIDATION_LAYER};
+
+/// Device extensions every selected physical device must support.
+pub const DEVICE_EXTENSIONS: &[vk::ExtensionName] = &[];
+
+/// Device extensions needed in addition to [`DEVICE_EXTENSIONS`] to present
+/// to a surface.
+pub const PRESENT_DEVICE_EXTENSIONS: &[vk::ExtensionName] = &[vk::KHR_SWAPCHAIN_EXTENSION.name];
+
+/// The device extensions required for the current configuration.
+pub fn required_device_extensions(data: &AppData) -> Vec<vk::ExtensionName> {
+    let mut extensions = DEVICE_EXTENSIONS.to_vec();
+    if !data.surface.is_null() {
+        extensions.extend_from_slice(PRESENT_DEVICE_EXTENSIONS);
+    }
+    extensions
+}
+
+/// Device features every selected physical device must support, named as in
+/// the Vulkan spec.
+pub const REQUIRED_FEATURES: &[&str] = &[];
+
+/// Device features enabled when supported; the renderer checks
+/// `AppData::enabled_features` before relying on one.
+pub const OPTIONAL_FEATURES: &[&str] = &[
+    "samplerAnisotropy",
+    "sampleRateShading",
+    "fillModeNonSolid",
+    "wideLines",
+    "multiDrawIndirect",
+];
+
+#[derive(Debug, Error)]
+#[error("Missing {0}.")]
+pub struct SuitabilityError(pub &'static str);
+
+/// The device extensions a physical device lacks, by name.
+#[derive(Debug, Error)]
+#[error("Missing device extension {}.", .0.join(", "))]
+pub struct MissingExtensionsError(pub Vec<String>);
+
+/// How desirable a physical device is, compared field by field in order.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DeviceScore {
+    /// Discrete > integrated > virtual > CPU > other.
+    pub device_type: u32,
+    /// Total size of the device-local memory heaps in bytes.
+    pub local_memory: u64,
+    /// Supported API version with the patch number masked off.
+    pub api_version: u32,
+}
+
+impl DeviceScore {
+    pub fn new(
+        properties: &vk::PhysicalDeviceProperties,
+        memory: &vk::PhysicalDeviceMemoryProperties,
+    ) -> Self {
+        let device_type = match properties.device_type {
+            vk::PhysicalDeviceType::DISCRETE_GPU => 4,
+            vk::PhysicalDeviceType::INTEGRATED_GPU => 3,
+            vk::PhysicalDeviceType::VIRTUAL_GPU => 2,
+            vk::PhysicalDeviceType::CPU => 1,
+            _ => 0,
+        };
+        let local_memory = memory.memory_heaps[..memory.memory_heap_count as usize]
+            .iter()
+            .filter(|h| h.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+            .map(|h| h.size)
+            .sum();
+        let api_version = vk::make_version(
+            vk::version_major(properties.api_version),
+            vk::version_minor(properties.api_version),
+            0,
+        );
+        Self { device_type, local_memory, api_version }
+    }
+}
+
+/// Returns the index of the highest scoring candidate, preferring the
+/// earliest enumerated device on ties.
+pub fn select_best(scores: &[DeviceScore]) -> Option<usize> {
+    scores
+        .iter()
+        .enumerate()
+        .fold(None, |best: Option<(usize, &DeviceScore)>, (index, score)| match best {
+            Some((_, b)) if score.cmp(b) != Ordering::Greater => best,
+            _ => Some((index, score)),
+        })
+        .map(|(index, _)| index)
+}
+
+pub unsafe fn pick_physical_device(
+    instance: &Instance,
+    data: &mut AppData,
+    selector: Option<&DeviceSelector>,
+) -> Result<()> {
+    if let Some(selector) = selector {
+        return pick_selected_physical_device(instance, data, selector);
+    }
+
+    let mut candidates = Vec::new();
+    let mut scores = Vec::new();
+    for physical_device in instance.enumerate_physical_devices()? {
+        let properties = instance.get_physical_device_properties(physical_device);
+        if let Err(error) = check_physical_device(instance, data, physical_device) {
+            warn!("Skipping physical device (`{}`): {}", properties.device_name, error);
+            continue;
+        }
+
+        let memory = instance.get_physical_device_memory_properties(physical_device);
+        let score = DeviceScore::new(&properties, &memory);
+        info!("Candidate physical device (`{}`): {:?}", properties.device_name, score);
+        candidates.push((physical_device, properties));
+        scores.push(score);
+    }
+
+    let index = select_best(&scores).ok_or_else(|| anyhow!("Failed to find suitable graphics device."))?;
+    let (physical_device, properties) = candidates[index];
+    info!("Selected physical device (`{}`).", properties.device_name);
+    data.physical_device = physical_device;
+    data.physical_device_properties = properties;
+    Ok(())
+}
+
+/// Uses the first device matching `selector`, failing instead of falling back
+/// to another device if it is not suitable.
+unsafe fn pick_selected_physical_device(
+    instance: &Instance,
+    data: &mut AppData,
+    selector: &DeviceSelector,
+) -> Result<()> {
+    let (physical_device, properties) = instance
+        .enumerate_physical_devices()?
+        .into_iter()
+        .enumerate()
+        .map(|(index, d)| (index, d, instance.get_physical_device_properties(d)))
+        .find(|(index, _, properties)| selector.matches(*index, properties))
+        .map(|(_, d, properties)| (d, properties))
+        .ok_or_else(|| anyhow!("No physical device matches `{}`.", selector))?;
+
+    check_physical_device(instance, data, physical_device).map_err(|e| {
+        anyhow!("Requested physical device (`{}`) is not suitable: {}", properties.device_name, e)
+    })?;
+
+    info!("Selected physical device (`{}`) matching `{}`.", properties.device_name, selector);
+    data.physical_device = physical_device;
+    data.physical_device_properties = properties;
+    Ok(())
+}
+
+pub unsafe fn check_physical_device(
+    instance: &Instance,
+    data: &AppData,
+    physical_device: vk::PhysicalDevice,
+) -> Result<()> {
+    QueueFamilyIndices::get(instance, data, physical_device)?;
+    check_physical_device_extensions(instance, data, physical_device)?;
+
+    let supported = instance.get_physical_device_features(physical_device);
+    negotiate_features(&supported, REQUIRED_FEATURES, OPTIONAL_FEATURES).map_err(|f| anyhow!(SuitabilityError(f)))?;
+
+    if !data.surface.is_null() {
+        let support = SwapchainSupport::get(instance, data, physical_device)?;
+        if support.formats.is_empty() {
+            return Err(anyhow!(SuitabilityError("surface formats")));
+        }
+        if support.present_modes.is_empty() {
+            return Err(anyhow!(SuitabilityError("surface present modes")));
+        }
+    }
+
+    Ok(())
+}
+
+unsafe fn check_physical_device_extensions(
+    instance: &Instance,
+    data: &AppData,
+    physical_device: vk::PhysicalDevice,
+) -> Result<()> {
+    let extensions = instance
+        .enumerate_device_extension_properties(physical_device, None)?
+        .iter()
+        .map(|e| e.extension_name)
+        .collect::<HashSet<_>>();
+    let missing = missing_extensions(&required_device_extensions(data), &extensions);
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(MissingExtensionsError(missing)))
+    }
+}
+
+/// The names of the `required` extensions that are not `available`.
+pub fn missing_extensions(required: &[vk::ExtensionName], available: &HashSet<vk::ExtensionName>) -> Vec<String> {
+    required.iter().filter(|e| !available.contains(e)).map(|e| e.to_string()).collect()
+}
+
+pub unsafe fn create_logical_device(instance: &Instance, data: &mut AppData) -> Result<Device> {
+    let indices = QueueFamilyIndices::get(instance, data, data.physical_device)?;
+
+    let mut unique_indices = HashSet::new();
+    unique_indices.insert(indices.graphics);
+    unique_indices.insert(indices.compute);
+    unique_indices.insert(indices.transfer);
+    unique_indices.extend(indices.present);
+
+    let queue_priorities = &[1.0];
+    let queue_infos = unique_indices
+        .iter()
+        .map(|i| {
+            vk::DeviceQueueCreateInfo::builder()
+                .queue_family_index(*i)
+                .queue_priorities(queue_priorities)
+        })
+        .collect::<Vec<_>>();
+
+    let layers = if VALIDATION_ENABLED {
+        vec![VALIDATION_LAYER.as_ptr()]
+    } else {
+        vec![]
+    };
+
+    let required_extensions = required_device_extensions(data);
+    let extensions = required_extensions
+        .iter()
+        .map(|e| e.as_ptr())
+        .collect::<Vec<_>>();
+
+    let supported = instance.get_physical_device_features(data.physical_device);
+    let features = negotiate_features(&supported, REQUIRED_FEATURES, OPTIONAL_FEATURES)
+        .map_err(|f| anyhow!(SuitabilityError(f)))?;
+    let info = vk::DeviceCreateInfo::builder()
+        .queue_create_infos(&queue_infos)
+        .enabled_layer_names(&layers)
+        .enabled_extension_names(&extensions)
+        .enabled_features(&features);
+    let device = instance.create_device(data.physical_device, &info, None)?;
+    data.enabled_features = features;
+    let enabled = feature_list(&features).into_iter().filter(|(_, e)| *e).map(|(n, _)| n).collect::<Vec<_>>();
+    info!("Enabled device features: {:?}.", enabled);
+
+    data.graphics_queue_family = indices.graphics;
+    data.graphics_queue = device.get_device_queue(indices.graphics, 0);
+    if let Some(present) = indices.present {
+        data.present_queue_family = present;
+        data.present_queue = device.get_device_queue(present, 0);
+    }
+    data.compute_queue_family = indices.compute;
+    data.compute_queue = device.get_device_queue(indices.compute, 0);
+    data.transfer_queue_family = indices.transfer;
+    data.transfer_queue = device.get_device_queue(indices.transfer, 0);
+    info!(
+        "Queue families: graphics {}, present {:?}, compute {}, transfer {}.",
+        indices.graphics, indices.present, indices.compute, indices.transfer,
+    );
+
+    Ok(device)
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct QueueFamilyIndices {
+    pub graphics: u32,
+    /// Only looked up (and required) when `AppData::surface` is set.
+    pub present: Option<u32>,
+    /// A compute family without graphics support if there is one, for async
+    /// compute; otherwise `graphics`.
+    pub compute: u32,
+    /// A transfer-only family if there is one, for uploads overlapping
+    /// rendering; otherwise `graphics`.
+    pub transfer: u32,
+}
+
+impl QueueFamilyIndices {
+    pub unsafe fn get(
+        instance: &Instance,
+        data: &AppData,
+        physical_device: vk::PhysicalDevice,
+    ) -> Result<Self> {
+        let properties = instance.get_physical_device_queue_family_properties(physical_device);
+
+        // Look for Presentation support
+        let mut present = None;
+        if !data.surface.is_null() {
+            for (index, _) in properties.iter().enumerate() {
+                if instance.get_physical_device_surface_support_khr(physical_device, index as u32, data.surface)? {
+                    present = Some(index as u32);
+                    break;
+                }
+            }
+            if present.is_none() {
+                return Err(anyhow!(SuitabilityError("a presentation queue family")));
+            }
+        }
+
+        Self::from_properties(&properties, present)
+    }
+
+    /// Picks the graphics, compute and transfer families from the queue
+    /// family properties of a device.
+    pub fn from_properties(properties: &[vk::QueueFamilyProperties], present: Option<u32>) -> Result<Self> {
+        let find = |required: vk::QueueFlags, excluded: vk::QueueFlags| {
+            properties
+                .iter()
+                .position(|p| p.queue_count > 0 && p.queue_flags.contains(required) && !p.queue_flags.intersects(excluded))
+                .map(|i| i as u32)
+        };
+
+        let graphics = find(vk::QueueFlags::GRAPHICS, vk::QueueFlags::empty())
+            .ok_or_else(|| anyhow!(SuitabilityError("a graphics queue family")))?;
+        let compute = find(vk::QueueFlags::COMPUTE, vk::QueueFlags::GRAPHICS).unwrap_or(graphics);
+        let transfer = find(vk::QueueFlags::TRANSFER, vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE)
+            .unwrap_or(graphics);
+
+        Ok(Self { graphics, present, compute, transfer })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn properties(device_type: vk::PhysicalDeviceType, api_version: u32) -> vk::PhysicalDeviceProperties {
+        vk::PhysicalDeviceProperties { device_type, api_version, ..Default::default() }
+    }
+
+    fn memory(heaps: &[(u64, vk::MemoryHeapFlags)]) -> vk::PhysicalDeviceMemoryProperties {
+        let mut memory = vk::PhysicalDeviceMemoryProperties { memory_heap_count: heaps.len() as u32, ..Default::default() };
+        for (heap, (size, flags)) in memory.memory_heaps.iter_mut().zip(heaps) {
+            *heap = vk::MemoryHeap { size: *size, flags: *flags };
+        }
+        memory
+    }
+
+    fn score(device_type: vk::PhysicalDeviceType) -> DeviceScore {
+        DeviceScore::new(&properties(device_type, vk::make_version(1, 0, 0)), &memory(&[]))
+    }
+
+    #[test]
+    fn discrete_beats_integrated() {
+        let integrated = DeviceScore::new(
+            &properties(vk::PhysicalDeviceType::INTEGRATED_GPU, vk::make_version(1, 3, 0)),
+            &memory(&[(1 << 34, vk::MemoryHeapFlags::DEVICE_LOCAL)]),
+        );
+        let discrete = score(vk::PhysicalDeviceType::DISCRETE_GPU);
+        assert_eq!(select_best(&[integrated, discrete]), Some(1));
+        assert_eq!(select_best(&[discrete, integrated]), Some(0));
+    }
+
+    #[test]
+    fn ties_break_to_earliest_device() {
+        let cpu = score(vk::PhysicalDeviceType::CPU);
+        let virtual_gpu = score(vk::PhysicalDeviceType::VIRTUAL_GPU);
+        assert_eq!(select_best(&[cpu, virtual_gpu, virtual_gpu]), Some(1));
+    }
+
+    #[test]
+    fn no_candidates() {
+        assert_eq!(select_best(&[]), None);
+    }
+
+    #[test]
+    fn only_device_local_heaps_are_summed() {
+        let memory = memory(&[
+            (1 << 30, vk::MemoryHeapFlags::DEVICE_LOCAL),
+            (1 << 32, vk::MemoryHeapFlags::empty()),
+            (1 << 31, vk::MemoryHeapFlags::DEVICE_LOCAL | vk::MemoryHeapFlags::MULTI_INSTANCE),
+        ]);
+        let score = DeviceScore::new(&properties(vk::PhysicalDeviceType::DISCRETE_GPU, 0), &memory);
+        assert_eq!(score.local_memory, (1 << 30) + (1 << 31));
+    }
+
+    #[test]
+    fn patch_version_is_ignored() {
+        let version = |patch| properties(vk::PhysicalDeviceType::DISCRETE_GPU, vk::make_version(1, 2, patch));
+        let old = DeviceScore::new(&version(131), &memory(&[]));
+        let new = DeviceScore::new(&version(204), &memory(&[]));
+        assert_eq!(old, new);
+        assert_eq!(select_best(&[old, new]), Some(0));
+    }
+
+    #[test]
+    fn missing_extensions_are_named() {
+        let available = HashSet::from([vk::KHR_SWAPCHAIN_EXTENSION.name]);
+        let required = [vk::KHR_SWAPCHAIN_EXTENSION.name, vk::KHR_MAINTENANCE1_EXTENSION.name];
+        assert_eq!(missing_extensions(&required, &available), ["VK_KHR_maintenance1"]);
+        assert!(missing_extensions(&required[..1], &available).is_empty());
+
+        let error = anyhow!(MissingExtensionsError(missing_extensions(&required, &HashSet::new())));
+        assert_eq!(error.to_string(), "Missing device extension VK_KHR_swapchain, VK_KHR_maintenance1.");
+    }
+
+    fn family(queue_flags: vk::QueueFlags) -> vk::QueueFamilyProperties {
+        vk::QueueFamilyProperties { queue_flags, queue_count: 1, ..Default::default() }
+    }
+
+    #[test]
+    fn compute_and_transfer_fall_back_to_graphics() {
+        let properties = [family(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE | vk::QueueFlags::TRANSFER)];
+        let indices = QueueFamilyIndices::from_properties(&properties, None).unwrap();
+        assert_eq!((indices.graphics, indices.compute, indices.transfer), (0, 0, 0));
+        assert_eq!(indices.present, None);
+    }
+
+    #[test]
+    fn dedicated_families_are_preferred() {
+        let properties = [
+            family(vk::QueueFlags::TRANSFER),
+            family(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE | vk::QueueFlags::TRANSFER),
+            family(vk::QueueFlags::COMPUTE | vk::QueueFlags::TRANSFER),
+        ];
+        let indices = QueueFamilyIndices::from_properties(&properties, Some(1)).unwrap();
+        assert_eq!((indices.graphics, indices.compute, indices.transfer), (1, 2, 0));
+        assert_eq!(indices.present, Some(1));
+    }
+
+    #[test]
+    fn empty_families_are_skipped() {
+        let mut empty = family(vk::QueueFlags::COMPUTE);
+        empty.queue_count = 0;
+        let properties = [family(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE), empty];
+        let indices = QueueFamilyIndices::from_properties(&properties, None).unwrap();
+        assert_eq!(indices.compute, 0);
+    }
+
+    #[test]
+    fn graphics_family_is_required() {
+        let error = QueueFamilyIndices::from_properties(&[family(vk::QueueFlags::COMPUTE)], None).unwrap_err();
+        assert!(error.is::<SuitabilityError>());
+    }
+
+    #[test]
+    fn transfer_family_must_not_support_compute() {
+        let properties = [
+            family(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE | vk::QueueFlags::TRANSFER),
+            family(vk::QueueFlags::COMPUTE | vk::QueueFlags::TRANSFER),
+        ];
+        let indices = QueueFamilyIndices::from_properties(&properties, None).unwrap();
+        assert_eq!((indices.compute, indices.transfer), (1, 0));
+    }
+
+    #[test]
+    fn first_dedicated_family_wins() {
+        let properties = [
+            family(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE | vk::QueueFlags::TRANSFER),
+            family(vk::QueueFlags::TRANSFER | vk::QueueFlags::SPARSE_BINDING),
+            family(vk::QueueFlags::TRANSFER),
+            family(vk::QueueFlags::COMPUTE),
+            family(vk::QueueFlags::COMPUTE | vk::QueueFlags::TRANSFER),
+        ];
+        let indices = QueueFamilyIndices::from_properties(&properties, None).unwrap();
+        assert_eq!((indices.compute, indices.transfer), (3, 1));
+    }
+}
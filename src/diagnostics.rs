@@ -0,0 +1,344 @@
+use anyhow::Result;
+
+use vulkanalia::prelude::v1_0::*;
+
+use crate::args::OutputFormat;
+use crate::device::{check_physical_device, DeviceScore};
+use crate::features::feature_list;
+use crate::json::{Json, ToJson};
+use crate::AppData;
+
+/// Builds a JSON object from the named fields of a struct.
+macro_rules! fields {
+    ($value:expr; $($field:ident),* $(,)?) => {
+        Json::object(vec![$((stringify!($field), $value.$field.to_json())),*])
+    };
+}
+
+/// Implements `ToJson` for a bitflags type as the array of the names of the
+/// listed flags it contains.
+macro_rules! flags_to_json {
+    ($flags:ident: $($flag:ident),* $(,)?) => {
+        impl ToJson for vk::$flags {
+            fn to_json(&self) -> Json {
+                Json::flags([$((stringify!($flag), self.contains(vk::$flags::$flag))),*])
+            }
+        }
+    };
+}
+
+flags_to_json!(SampleCountFlags: _1, _2, _4, _8, _16, _32, _64);
+flags_to_json!(MemoryHeapFlags: DEVICE_LOCAL, MULTI_INSTANCE);
+flags_to_json!(
+    MemoryPropertyFlags:
+    DEVICE_LOCAL, HOST_VISIBLE, HOST_COHERENT, HOST_CACHED, LAZILY_ALLOCATED, PROTECTED,
+    DEVICE_COHERENT_AMD, DEVICE_UNCACHED_AMD, RDMA_CAPABLE_NV,
+);
+flags_to_json!(QueueFlags: GRAPHICS, COMPUTE, TRANSFER, SPARSE_BINDING, PROTECTED);
+
+/// Prints the capabilities of every physical device to stdout.
+pub unsafe fn list_devices(instance: &Instance, data: &AppData, format: OutputFormat) -> Result<()> {
+    let devices = instance
+        .enumerate_physical_devices()?
+        .into_iter()
+        .enumerate()
+        .map(|(index, d)| describe_device(instance, data, index, d))
+        .collect::<Result<Vec<_>>>()?;
+
+    match format {
+        OutputFormat::Json => println!("{}", Json::Array(devices)),
+        OutputFormat::Text => {
+            for device in &devices {
+                let mut text = String::new();
+                write_text(&mut text, device, 0);
+                println!("{}", text);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+unsafe fn describe_device(
+    instance: &Instance,
+    data: &AppData,
+    index: usize,
+    physical_device: vk::PhysicalDevice,
+) -> Result<Json> {
+    let properties = instance.get_physical_device_properties(physical_device);
+    let memory = instance.get_physical_device_memory_properties(physical_device);
+    let features = instance.get_physical_device_features(physical_device);
+    let queue_families = instance.get_physical_device_queue_family_properties(physical_device);
+    let extensions = instance.enumerate_device_extension_properties(physical_device, None)?;
+    let suitability = check_physical_device(instance, data, physical_device);
+    let score = DeviceScore::new(&properties, &memory);
+
+    let limits = &properties.limits;
+    let limits = fields!(limits;
+        max_image_dimension_1d,
+        max_image_dimension_2d,
+        max_image_dimension_3d,
+        max_image_dimension_cube,
+        max_image_array_layers,
+        max_texel_buffer_elements,
+        max_uniform_buffer_range,
+        max_storage_buffer_range,
+        max_push_constants_size,
+        max_memory_allocation_count,
+        max_sampler_allocation_count,
+        buffer_image_granularity,
+        sparse_address_space_size,
+        max_bound_descriptor_sets,
+        max_per_stage_descriptor_samplers,
+        max_per_stage_descriptor_uniform_buffers,
+        max_per_stage_descriptor_storage_buffers,
+        max_per_stage_descriptor_sampled_images,
+        max_per_stage_descriptor_storage_images,
+        max_per_stage_descriptor_input_attachments,
+        max_per_stage_resources,
+        max_descriptor_set_samplers,
+        max_descriptor_set_uniform_buffers,
+        max_descriptor_set_uniform_buffers_dynamic,
+        max_descriptor_set_storage_buffers,
+        max_descriptor_set_storage_buffers_dynamic,
+        max_descriptor_set_sampled_images,
+        max_descriptor_set_storage_images,
+        max_descriptor_set_input_attachments,
+        max_vertex_input_attributes,
+        max_vertex_input_bindings,
+        max_vertex_input_attribute_offset,
+        max_vertex_input_binding_stride,
+        max_vertex_output_components,
+        max_tessellation_generation_level,
+        max_tessellation_patch_size,
+        max_tessellation_control_per_vertex_input_components,
+        max_tessellation_control_per_vertex_output_components,
+        max_tessellation_control_per_patch_output_components,
+        max_tessellation_control_total_output_components,
+        max_tessellation_evaluation_input_components,
+        max_tessellation_evaluation_output_components,
+        max_geometry_shader_invocations,
+        max_geometry_input_components,
+        max_geometry_output_components,
+        max_geometry_output_vertices,
+        max_geometry_total_output_components,
+        max_fragment_input_components,
+        max_fragment_output_attachments,
+        max_fragment_dual_src_attachments,
+        max_fragment_combined_output_resources,
+        max_compute_shared_memory_size,
+        max_compute_work_group_count,
+        max_compute_work_group_invocations,
+        max_compute_work_group_size,
+        sub_pixel_precision_bits,
+        sub_texel_precision_bits,
+        mipmap_precision_bits,
+        max_draw_indexed_index_value,
+        max_draw_indirect_count,
+        max_sampler_lod_bias,
+        max_sampler_anisotropy,
+        max_viewports,
+        max_viewport_dimensions,
+        viewport_bounds_range,
+        viewport_sub_pixel_bits,
+        min_memory_map_alignment,
+        min_texel_buffer_offset_alignment,
+        min_uniform_buffer_offset_alignment,
+        min_storage_buffer_offset_alignment,
+        min_texel_offset,
+        max_texel_offset,
+        min_texel_gather_offset,
+        max_texel_gather_offset,
+        min_interpolation_offset,
+        max_interpolation_offset,
+        sub_pixel_interpolation_offset_bits,
+        max_framebuffer_width,
+        max_framebuffer_height,
+        max_framebuffer_layers,
+        framebuffer_color_sample_counts,
+        framebuffer_depth_sample_counts,
+        framebuffer_stencil_sample_counts,
+        framebuffer_no_attachments_sample_counts,
+        max_color_attachments,
+        sampled_image_color_sample_counts,
+        sampled_image_integer_sample_counts,
+        sampled_image_depth_sample_counts,
+        sampled_image_stencil_sample_counts,
+        storage_image_sample_counts,
+        max_sample_mask_words,
+        timestamp_compute_and_graphics,
+        timestamp_period,
+        max_clip_distances,
+        max_cull_distances,
+        max_combined_clip_and_cull_distances,
+        discrete_queue_priorities,
+        point_size_range,
+        line_width_range,
+        point_size_granularity,
+        line_width_granularity,
+        strict_lines,
+        standard_sample_locations,
+        optimal_buffer_copy_offset_alignment,
+        optimal_buffer_copy_row_pitch_alignment,
+        non_coherent_atom_size,
+    );
+    let sparse = &properties.sparse_properties;
+    let sparse = fields!(sparse;
+        residency_standard_2d_block_shape,
+        residency_standard_2d_multisample_block_shape,
+        residency_standard_3d_block_shape,
+        residency_aligned_mip_size,
+        residency_non_resident_strict,
+    );
+
+    let heaps = memory.memory_heaps[..memory.memory_heap_count as usize]
+        .iter()
+        .map(|h| Json::object([("size", h.size.to_json()), ("flags", h.flags.to_json())]))
+        .collect();
+    let types = memory.memory_types[..memory.memory_type_count as usize]
+        .iter()
+        .map(|t| {
+            Json::object([
+                ("heap_index", t.heap_index.to_json()),
+                ("property_flags", t.property_flags.to_json()),
+            ])
+        })
+        .collect();
+    let queue_families = queue_families
+        .iter()
+        .map(|q| {
+            let granularity = q.min_image_transfer_granularity;
+            Json::object([
+                ("queue_flags", q.queue_flags.to_json()),
+                ("queue_count", q.queue_count.to_json()),
+                ("timestamp_valid_bits", q.timestamp_valid_bits.to_json()),
+                (
+                    "min_image_transfer_granularity",
+                    [granularity.width, granularity.height, granularity.depth].to_json(),
+                ),
+            ])
+        })
+        .collect();
+    let extensions = extensions
+        .iter()
+        .map(|e| {
+            Json::object([
+                ("name", e.extension_name.to_string_lossy().to_json()),
+                ("spec_version", e.spec_version.to_json()),
+            ])
+        })
+        .collect();
+    let features = Json::object(feature_list(&features).into_iter().map(|(n, e)| (n, e.to_json())));
+
+    Ok(Json::object([
+        ("index", index.to_json()),
+        ("device_name", properties.device_name.to_string_lossy().to_json()),
+        ("device_type", format!("{:?}", properties.device_type).to_json()),
+        ("api_version", version(properties.api_version).to_json()),
+        ("driver_version", properties.driver_version.to_json()),
+        ("vendor_id", format!("0x{:04x}", properties.vendor_id).to_json()),
+        ("device_id", format!("0x{:04x}", properties.device_id).to_json()),
+        ("pipeline_cache_uuid", hex(&properties.pipeline_cache_uuid[..]).to_json()),
+        ("suitable", suitability.is_ok().to_json()),
+        ("unsuitable_reason", suitability.err().map(|e| e.to_string()).to_json()),
+        (
+            "score",
+            fields!(score; device_type, local_memory, api_version),
+        ),
+        ("limits", limits),
+        ("sparse_properties", sparse),
+        ("memory_heaps", Json::Array(heaps)),
+        ("memory_types", Json::Array(types)),
+        ("queue_families", Json::Array(queue_families)),
+        ("extensions", Json::Array(extensions)),
+        ("features", features),
+    ]))
+}
+
+fn version(version: u32) -> String {
+    format!(
+        "{}.{}.{}",
+        vk::version_major(version),
+        vk::version_minor(version),
+        vk::version_patch(version),
+    )
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Renders `json` as indented `key: value` lines.
+fn write_text(out: &mut String, json: &Json, indent: usize) {
+    let pad = "  ".repeat(indent);
+    match json {
+        Json::Object(members) => {
+            for (key, value) in members {
+                match value {
+                    Json::Object(m) if !m.is_empty() => {
+                        out.push_str(&format!("{}{}:\n", pad, key));
+                        write_text(out, value, indent + 1);
+                    }
+                    Json::Array(items) if items.iter().any(|i| matches!(i, Json::Object(_))) => {
+                        out.push_str(&format!("{}{}:\n", pad, key));
+                        for (i, item) in items.iter().enumerate() {
+                            out.push_str(&format!("{}  [{}]\n", pad, i));
+                            write_text(out, item, indent + 2);
+                        }
+                    }
+                    _ => out.push_str(&format!("{}{}: {}\n", pad, key, scalar_text(value))),
+                }
+            }
+        }
+        _ => out.push_str(&format!("{}{}\n", pad, scalar_text(json))),
+    }
+}
+
+fn scalar_text(json: &Json) -> String {
+    match json {
+        Json::Null => "-".into(),
+        Json::String(s) => s.clone(),
+        Json::Array(items) => items.iter().map(scalar_text).collect::<Vec<_>>().join(", "),
+        _ => json.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::ToJson;
+
+    #[test]
+    fn text_output() {
+        let json = Json::object([
+            ("name", "llvmpipe".to_json()),
+            ("limits", Json::object([("max_samples", 4u32.to_json()), ("missing", Json::Null)])),
+            ("flags", (vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE).to_json()),
+            ("empty", Json::Object(vec![])),
+            ("queues", Json::Array(vec![Json::object([("count", 1u32.to_json())])])),
+        ]);
+        let mut text = String::new();
+        write_text(&mut text, &json, 0);
+        assert_eq!(
+            text,
+            "name: llvmpipe\n\
+             limits:\n  max_samples: 4\n  missing: -\n\
+             flags: GRAPHICS, COMPUTE\n\
+             empty: {}\n\
+             queues:\n  [0]\n    count: 1\n",
+        );
+    }
+
+    #[test]
+    fn versions() {
+        assert_eq!(version(vk::make_version(1, 3, 250)), "1.3.250");
+    }
+
+    #[test]
+    fn flags_are_named() {
+        let flags = vk::MemoryPropertyFlags::DEVICE_LOCAL | vk::MemoryPropertyFlags::HOST_COHERENT;
+        assert_eq!(flags.to_json(), Json::Array(vec!["DEVICE_LOCAL".to_json(), "HOST_COHERENT".to_json()]));
+        assert_eq!(vk::SampleCountFlags::_1.to_json(), Json::Array(vec!["_1".to_json()]));
+        assert_eq!(vk::QueueFlags::empty().to_json(), Json::Array(vec![]));
+    }
+}
@@ -0,0 +1,185 @@
+use vulkanalia::prelude::v1_0::*;
+
+macro_rules! features {
+    ($($field:ident => $name:literal,)*) => {
+        /// Names of every `vk::PhysicalDeviceFeatures` member as spelled in the Vulkan spec.
+        pub const FEATURE_NAMES: &[&str] = &[$($name),*];
+
+        /// Lists every feature together with whether it is set in `features`.
+        pub fn feature_list(features: &vk::PhysicalDeviceFeatures) -> Vec<(&'static str, bool)> {
+            vec![$(($name, features.$field == vk::TRUE)),*]
+        }
+
+        /// Whether the feature named `name` is set in `features`, or `None` if
+        /// there is no such feature.
+        pub fn get_feature(features: &vk::PhysicalDeviceFeatures, name: &str) -> Option<bool> {
+            match name {
+                $($name => Some(features.$field == vk::TRUE),)*
+                _ => None,
+            }
+        }
+
+        /// Sets the feature named `name` in `features`, returning `false` if
+        /// there is no such feature.
+        pub fn set_feature(features: &mut vk::PhysicalDeviceFeatures, name: &str, enabled: bool) -> bool {
+            match name {
+                $($name => features.$field = if enabled { vk::TRUE } else { vk::FALSE },)*
+                _ => return false,
+            }
+            true
+        }
+    };
+}
+
+features! {
+    robust_buffer_access => "robustBufferAccess",
+    full_draw_index_uint32 => "fullDrawIndexUint32",
+    image_cube_array => "imageCubeArray",
+    independent_blend => "independentBlend",
+    geometry_shader => "geometryShader",
+    tessellation_shader => "tessellationShader",
+    sample_rate_shading => "sampleRateShading",
+    dual_src_blend => "dualSrcBlend",
+    logic_op => "logicOp",
+    multi_draw_indirect => "multiDrawIndirect",
+    draw_indirect_first_instance => "drawIndirectFirstInstance",
+    depth_clamp => "depthClamp",
+    depth_bias_clamp => "depthBiasClamp",
+    fill_mode_non_solid => "fillModeNonSolid",
+    depth_bounds => "depthBounds",
+    wide_lines => "wideLines",
+    large_points => "largePoints",
+    alpha_to_one => "alphaToOne",
+    multi_viewport => "multiViewport",
+    sampler_anisotropy => "samplerAnisotropy",
+    texture_compression_etc2 => "textureCompressionETC2",
+    texture_compression_astc_ldr => "textureCompressionASTC_LDR",
+    texture_compression_bc => "textureCompressionBC",
+    occlusion_query_precise => "occlusionQueryPrecise",
+    pipeline_statistics_query => "pipelineStatisticsQuery",
+    vertex_pipeline_stores_and_atomics => "vertexPipelineStoresAndAtomics",
+    fragment_stores_and_atomics => "fragmentStoresAndAtomics",
+    shader_tessellation_and_geometry_point_size => "shaderTessellationAndGeometryPointSize",
+    shader_image_gather_extended => "shaderImageGatherExtended",
+    shader_storage_image_extended_formats => "shaderStorageImageExtendedFormats",
+    shader_storage_image_multisample => "shaderStorageImageMultisample",
+    shader_storage_image_read_without_format => "shaderStorageImageReadWithoutFormat",
+    shader_storage_image_write_without_format => "shaderStorageImageWriteWithoutFormat",
+    shader_uniform_buffer_array_dynamic_indexing => "shaderUniformBufferArrayDynamicIndexing",
+    shader_sampled_image_array_dynamic_indexing => "shaderSampledImageArrayDynamicIndexing",
+    shader_storage_buffer_array_dynamic_indexing => "shaderStorageBufferArrayDynamicIndexing",
+    shader_storage_image_array_dynamic_indexing => "shaderStorageImageArrayDynamicIndexing",
+    shader_clip_distance => "shaderClipDistance",
+    shader_cull_distance => "shaderCullDistance",
+    shader_float64 => "shaderFloat64",
+    shader_int64 => "shaderInt64",
+    shader_int16 => "shaderInt16",
+    shader_resource_residency => "shaderResourceResidency",
+    shader_resource_min_lod => "shaderResourceMinLod",
+    sparse_binding => "sparseBinding",
+    sparse_residency_buffer => "sparseResidencyBuffer",
+    sparse_residency_image_2d => "sparseResidencyImage2D",
+    sparse_residency_image_3d => "sparseResidencyImage3D",
+    sparse_residency2_samples => "sparseResidency2Samples",
+    sparse_residency4_samples => "sparseResidency4Samples",
+    sparse_residency8_samples => "sparseResidency8Samples",
+    sparse_residency16_samples => "sparseResidency16Samples",
+    sparse_residency_aliased => "sparseResidencyAliased",
+    variable_multisample_rate => "variableMultisampleRate",
+    inherited_queries => "inheritedQueries",
+}
+
+/// The features to enable on a device supporting `supported`: every one of
+/// `required`, failing with the name of the first that is not supported, and
+/// those of `optional` that are.
+pub fn negotiate_features(
+    supported: &vk::PhysicalDeviceFeatures,
+    required: &[&'static str],
+    optional: &[&'static str],
+) -> Result<vk::PhysicalDeviceFeatures, &'static str> {
+    let mut enabled = vk::PhysicalDeviceFeatures::default();
+    for name in required {
+        if get_feature(supported, name) != Some(true) {
+            return Err(name);
+        }
+        set_feature(&mut enabled, name, true);
+    }
+    for name in optional {
+        if get_feature(supported, name) == Some(true) {
+            set_feature(&mut enabled, name, true);
+        }
+    }
+    Ok(enabled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn supported(names: &[&str]) -> vk::PhysicalDeviceFeatures {
+        let mut features = vk::PhysicalDeviceFeatures::default();
+        names.iter().for_each(|n| assert!(set_feature(&mut features, n, true), "{}", n));
+        features
+    }
+
+    fn enabled(features: &vk::PhysicalDeviceFeatures) -> Vec<&'static str> {
+        feature_list(features).into_iter().filter(|(_, on)| *on).map(|(name, _)| name).collect()
+    }
+
+    #[test]
+    fn every_feature_has_a_unique_name() {
+        // `VkPhysicalDeviceFeatures` has 55 members, all of them `VkBool32`.
+        assert_eq!(FEATURE_NAMES.len(), 55);
+        assert_eq!(std::mem::size_of::<vk::PhysicalDeviceFeatures>(), 55 * std::mem::size_of::<vk::Bool32>());
+        let mut names = FEATURE_NAMES.to_vec();
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(names.len(), FEATURE_NAMES.len());
+    }
+
+    #[test]
+    fn features_are_set_individually() {
+        for name in FEATURE_NAMES {
+            let mut features = vk::PhysicalDeviceFeatures::default();
+            assert_eq!(get_feature(&features, name), Some(false));
+            assert!(set_feature(&mut features, name, true));
+            assert_eq!(get_feature(&features, name), Some(true));
+            assert_eq!(enabled(&features), [*name]);
+            assert!(set_feature(&mut features, name, false));
+            assert_eq!(features, vk::PhysicalDeviceFeatures::default());
+        }
+    }
+
+    #[test]
+    fn unknown_features() {
+        let mut features = vk::PhysicalDeviceFeatures::default();
+        assert_eq!(get_feature(&features, "meshShader"), None);
+        assert_eq!(get_feature(&features, "sampler_anisotropy"), None);
+        assert!(!set_feature(&mut features, "meshShader", true));
+        assert_eq!(features, vk::PhysicalDeviceFeatures::default());
+    }
+
+    #[test]
+    fn required_and_supported_optional_features_are_enabled() {
+        let supported = supported(&["samplerAnisotropy", "fillModeNonSolid", "wideLines", "depthClamp"]);
+        let features = negotiate_features(&supported, &["samplerAnisotropy"], &["wideLines", "depthClamp"]).unwrap();
+        assert_eq!(enabled(&features), ["depthClamp", "wideLines", "samplerAnisotropy"]);
+    }
+
+    #[test]
+    fn unsupported_optional_features_are_dropped() {
+        let supported = supported(&["samplerAnisotropy"]);
+        let features = negotiate_features(&supported, &[], &["sampleRateShading", "samplerAnisotropy"]).unwrap();
+        assert_eq!(enabled(&features), ["samplerAnisotropy"]);
+        let features = negotiate_features(&supported, &[], &["noSuchFeature"]).unwrap();
+        assert!(enabled(&features).is_empty());
+    }
+
+    #[test]
+    fn missing_required_feature_is_named() {
+        let supported = supported(&["samplerAnisotropy"]);
+        let required = ["samplerAnisotropy", "geometryShader", "tessellationShader"];
+        assert_eq!(negotiate_features(&supported, &required, &[]), Err("geometryShader"));
+        assert_eq!(negotiate_features(&supported, &["noSuchFeature"], &[]), Err("noSuchFeature"));
+    }
+}
@@ -0,0 +1,348 @@
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use log::*;
+
+use vulkanalia::loader::{LibloadingLoader, LIBRARY};
+use vulkanalia::prelude::v1_0::*;
+
+use crate::args::Args;
+use crate::capture::CapturedFrame;
+use crate::App;
+
+/// A reference scene rendered offscreen and compared against `<name>.png`.
+#[derive(Copy, Clone, Debug)]
+pub struct Scene {
+    pub name: &'static str,
+    pub size: vk::Extent2D,
+    pub clear_color: [f32; 4],
+    /// Whether the tutorial triangle is drawn over the clear color.
+    pub triangle: bool,
+}
+
+pub const SCENES: &[Scene] = &[
+    Scene {
+        name: "clear_black",
+        size: vk::Extent2D { width: 64, height: 48 },
+        clear_color: [0.0, 0.0, 0.0, 1.0],
+        triangle: false,
+    },
+    Scene {
+        name: "clear_color",
+        size: vk::Extent2D { width: 64, height: 48 },
+        clear_color: [0.2, 0.4, 0.8, 1.0],
+        triangle: false,
+    },
+    Scene {
+        name: "triangle",
+        size: vk::Extent2D { width: 64, height: 48 },
+        clear_color: [0.0, 0.0, 0.0, 1.0],
+        triangle: true,
+    },
+];
+
+/// How far a rendered frame may deviate from its reference.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Tolerance {
+    /// Largest per-channel difference for a pixel to still count as matching.
+    pub channel: u8,
+    /// Largest fraction of pixels allowed to exceed `channel`.
+    pub mismatched_fraction: f64,
+    /// Largest mean CIE76 color difference (ΔE) over the whole frame.
+    pub mean_delta_e: f64,
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Self { channel: 2, mismatched_fraction: 0.001, mean_delta_e: 1.0 }
+    }
+}
+
+/// The result of comparing a rendered frame against its reference.
+#[derive(Clone, Debug)]
+pub struct Comparison {
+    pub mismatched_pixels: usize,
+    pub total_pixels: usize,
+    pub mean_delta_e: f64,
+    pub max_delta_e: f64,
+    /// The actual frame dimmed to grayscale with mismatched pixels in red.
+    pub diff: CapturedFrame,
+}
+
+impl Comparison {
+    pub fn passed(&self, tolerance: &Tolerance) -> bool {
+        let fraction = self.mismatched_pixels as f64 / self.total_pixels.max(1) as f64;
+        fraction <= tolerance.mismatched_fraction && self.mean_delta_e <= tolerance.mean_delta_e
+    }
+}
+
+/// Compares two frames of the same size pixel by pixel.
+pub fn compare(expected: &CapturedFrame, actual: &CapturedFrame, tolerance: &Tolerance) -> Result<Comparison> {
+    if (expected.width, expected.height) != (actual.width, actual.height) {
+        return Err(anyhow!(
+            "Size mismatch: expected {}x{}, got {}x{}.",
+            expected.width,
+            expected.height,
+            actual.width,
+            actual.height,
+        ));
+    }
+
+    let mut mismatched_pixels = 0;
+    let mut total_delta_e = 0.0;
+    let mut max_delta_e = 0.0f64;
+    let mut diff = Vec::with_capacity(actual.pixels.len());
+    for (e, a) in expected.pixels.chunks_exact(4).zip(actual.pixels.chunks_exact(4)) {
+        let mismatched = e.iter().zip(a).any(|(e, a)| e.abs_diff(*a) > tolerance.channel);
+        let delta_e = delta_e(e, a);
+        total_delta_e += delta_e;
+        max_delta_e = max_delta_e.max(delta_e);
+
+        if mismatched {
+            mismatched_pixels += 1;
+            diff.extend_from_slice(&[255, 0, 0, 255]);
+        } else {
+            let luma = (0.299 * a[0] as f64 + 0.587 * a[1] as f64 + 0.114 * a[2] as f64) / 4.0;
+            diff.extend_from_slice(&[luma as u8, luma as u8, luma as u8, 255]);
+        }
+    }
+
+    let total_pixels = actual.pixels.len() / 4;
+    Ok(Comparison {
+        mismatched_pixels,
+        total_pixels,
+        mean_delta_e: total_delta_e / total_pixels.max(1) as f64,
+        max_delta_e,
+        diff: CapturedFrame { width: actual.width, height: actual.height, pixels: diff },
+    })
+}
+
+/// CIE76 color difference between two sRGB RGBA8 pixels, ignoring alpha.
+pub fn delta_e(a: &[u8], b: &[u8]) -> f64 {
+    let (a, b) = (srgb_to_lab(a), srgb_to_lab(b));
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+}
+
+/// Converts an sRGB pixel to CIELAB (D65 white point).
+fn srgb_to_lab(pixel: &[u8]) -> [f64; 3] {
+    let linear = |c: u8| {
+        let c = c as f64 / 255.0;
+        if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+    };
+    let (r, g, b) = (linear(pixel[0]), linear(pixel[1]), linear(pixel[2]));
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+
+    let f = |t: f64| if t > 216.0 / 24389.0 { t.cbrt() } else { (24389.0 / 27.0 * t + 16.0) / 116.0 };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+/// Options of the `--golden` mode.
+#[derive(Clone, Debug)]
+pub struct GoldenOptions {
+    /// Directory holding the reference `<scene>.png` files.
+    pub reference_dir: PathBuf,
+    /// Directory the actual and diff images of failed scenes are written to.
+    pub output_dir: PathBuf,
+    /// Overwrites the references with the rendered frames instead of comparing.
+    pub update: bool,
+    pub tolerance: Tolerance,
+}
+
+/// Renders every scene in [`SCENES`] and compares it to its reference.
+/// Skips (successfully) when no Vulkan implementation is available.
+pub fn run(args: &Args, options: &GoldenOptions) -> Result<()> {
+    if let Err(error) = unsafe { check_vulkan_available() } {
+        warn!("Skipping golden tests: {}", error);
+        println!("golden: skipped ({})", error);
+        return Ok(());
+    }
+
+    let mut failures = Vec::new();
+    for scene in SCENES {
+        match run_scene(args, options, scene) {
+            Ok(message) => println!("golden: {} ... {}", scene.name, message),
+            Err(error) => {
+                println!("golden: {} ... FAILED: {:#}", scene.name, error);
+                failures.push(scene.name);
+            }
+        }
+    }
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!("{} of {} golden scenes failed: {}.", failures.len(), SCENES.len(), failures.join(", ")))
+    }
+}
+
+fn run_scene(args: &Args, options: &GoldenOptions, scene: &Scene) -> Result<String> {
+    let actual = unsafe { render_scene(args, scene)? };
+    let reference = options.reference_dir.join(format!("{}.png", scene.name));
+    if options.update {
+        actual.save_png(&reference)?;
+        return Ok(format!("updated `{}`", reference.display()));
+    }
+
+    let expected = load_png(&reference)?;
+    let comparison = compare(&expected, &actual, &options.tolerance)?;
+    let summary = format!(
+        "{} of {} pixels differ, mean ΔE {:.3}, max ΔE {:.3}",
+        comparison.mismatched_pixels, comparison.total_pixels, comparison.mean_delta_e, comparison.max_delta_e,
+    );
+    if comparison.passed(&options.tolerance) {
+        return Ok(format!("ok ({})", summary));
+    }
+
+    fs::create_dir_all(&options.output_dir)
+        .with_context(|| format!("Failed to create `{}`.", options.output_dir.display()))?;
+    let actual_path = options.output_dir.join(format!("{}.actual.png", scene.name));
+    let diff_path = options.output_dir.join(format!("{}.diff.png", scene.name));
+    actual.save_png(&actual_path)?;
+    comparison.diff.save_png(&diff_path)?;
+    Err(anyhow!("{}; wrote `{}` and `{}`", summary, actual_path.display(), diff_path.display()))
+}
+
+unsafe fn render_scene(args: &Args, scene: &Scene) -> Result<CapturedFrame> {
+    let args = Args { headless: true, size: scene.size, ..args.clone() };
+    let mut app = App::create(None, &args)?;
+    app.data.clear_color = scene.clear_color;
+    app.data.draw_triangle = scene.triangle;
+    let result = app.render_offscreen().and_then(|_| app.capture());
+    app.destroy();
+    result
+}
+
+/// Fails if the Vulkan loader is missing or exposes no physical devices.
+unsafe fn check_vulkan_available() -> Result<()> {
+    let loader = LibloadingLoader::new(LIBRARY)?;
+    let entry = Entry::new(loader).map_err(|b| anyhow!("{}", b))?;
+    let info = vk::InstanceCreateInfo::builder();
+    let instance = entry.create_instance(&info, None)?;
+    let devices = instance.enumerate_physical_devices();
+    instance.destroy_instance(None);
+    if devices?.is_empty() {
+        Err(anyhow!("No Vulkan physical devices."))
+    } else {
+        Ok(())
+    }
+}
+
+/// Loads an 8-bit RGBA or RGB PNG as sRGB RGBA8.
+pub fn load_png(path: &Path) -> Result<CapturedFrame> {
+    let file = File::open(path).with_context(|| format!("Failed to open `{}`.", path.display()))?;
+    read_png(BufReader::new(file)).with_context(|| format!("Failed to read `{}`.", path.display()))
+}
+
+fn read_png<R: Read>(r: R) -> Result<CapturedFrame> {
+    let decoder = png::Decoder::new(r);
+    let mut reader = decoder.read_info()?;
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels)?;
+    pixels.truncate(info.buffer_size());
+    let pixels = match (info.color_type, info.bit_depth) {
+        (png::ColorType::Rgba, png::BitDepth::Eight) => pixels,
+        (png::ColorType::Rgb, png::BitDepth::Eight) => {
+            pixels.chunks_exact(3).flat_map(|p| [p[0], p[1], p[2], 255]).collect()
+        }
+        (color, depth) => return Err(anyhow!("Unsupported PNG format {:?} {:?}.", color, depth)),
+    };
+    Ok(CapturedFrame { width: info.width, height: info.height, pixels })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(width: u32, height: u32, pixel: [u8; 4]) -> CapturedFrame {
+        CapturedFrame { width, height, pixels: pixel.repeat((width * height) as usize) }
+    }
+
+    /// Renders the golden scenes against the checked-in references, skipping
+    /// when there is no Vulkan implementation.
+    #[test]
+    fn golden_scenes() {
+        let args = Args { pipeline_cache: None, ..Args::default() };
+        let options = GoldenOptions {
+            reference_dir: concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden").into(),
+            output_dir: concat!(env!("CARGO_MANIFEST_DIR"), "/target/golden").into(),
+            update: false,
+            tolerance: Tolerance::default(),
+        };
+        run(&args, &options).unwrap();
+    }
+
+    #[test]
+    fn every_scene_has_a_reference() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+        for scene in SCENES {
+            let reference = load_png(&dir.join(format!("{}.png", scene.name))).unwrap();
+            assert_eq!((reference.width, reference.height), (scene.size.width, scene.size.height), "{}", scene.name);
+        }
+    }
+
+    #[test]
+    fn identical_frames_match() {
+        let frame = frame(4, 3, [10, 200, 30, 255]);
+        let comparison = compare(&frame, &frame, &Tolerance::default()).unwrap();
+        assert_eq!((comparison.mismatched_pixels, comparison.total_pixels), (0, 12));
+        assert_eq!((comparison.mean_delta_e, comparison.max_delta_e), (0.0, 0.0));
+        assert!(comparison.passed(&Tolerance::default()));
+    }
+
+    #[test]
+    fn size_mismatch() {
+        assert!(compare(&frame(4, 3, [0; 4]), &frame(3, 4, [0; 4]), &Tolerance::default()).is_err());
+    }
+
+    #[test]
+    fn channel_tolerance_boundary() {
+        let tolerance = Tolerance { channel: 2, mismatched_fraction: 0.0, mean_delta_e: f64::INFINITY };
+        let expected = frame(2, 2, [100, 100, 100, 255]);
+        let comparison = compare(&expected, &frame(2, 2, [102, 100, 98, 255]), &tolerance).unwrap();
+        assert_eq!(comparison.mismatched_pixels, 0);
+        assert!(comparison.passed(&tolerance));
+        let comparison = compare(&expected, &frame(2, 2, [100, 100, 100, 252]), &tolerance).unwrap();
+        assert_eq!(comparison.mismatched_pixels, 4);
+        assert!(!comparison.passed(&tolerance));
+        assert_eq!(&comparison.diff.pixels[..4], &[255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn mismatched_fraction_boundary() {
+        let expected = frame(10, 10, [0, 0, 0, 255]);
+        let mut actual = expected.clone();
+        actual.pixels[..4].copy_from_slice(&[255, 255, 255, 255]);
+        let comparison = compare(&expected, &actual, &Tolerance::default()).unwrap();
+        assert_eq!(comparison.mismatched_pixels, 1);
+        let tolerance = Tolerance { channel: 2, mismatched_fraction: 0.01, mean_delta_e: f64::INFINITY };
+        assert!(comparison.passed(&tolerance));
+        assert!(!comparison.passed(&Tolerance { mismatched_fraction: 0.009, ..tolerance }));
+    }
+
+    #[test]
+    fn mean_delta_e_boundary() {
+        let expected = frame(2, 2, [128, 128, 128, 255]);
+        let comparison = compare(&expected, &frame(2, 2, [130, 128, 128, 255]), &Tolerance::default()).unwrap();
+        let tolerance = Tolerance { channel: 2, mismatched_fraction: 0.0, mean_delta_e: comparison.mean_delta_e };
+        assert!(comparison.passed(&tolerance));
+        assert!(!comparison.passed(&Tolerance { mean_delta_e: comparison.mean_delta_e * 0.99, ..tolerance }));
+    }
+
+    #[test]
+    fn delta_e_values() {
+        assert_eq!(delta_e(&[12, 34, 56, 255], &[12, 34, 56, 0]), 0.0);
+        // Black to white spans the whole lightness range
+        assert!((delta_e(&[0, 0, 0, 255], &[255, 255, 255, 255]) - 100.0).abs() < 0.01);
+        // Grays differ only in lightness, symmetrically
+        let (a, b) = ([100, 100, 100, 255], [110, 110, 110, 255]);
+        assert!((delta_e(&a, &b) - delta_e(&b, &a)).abs() < 1e-12);
+        assert!((srgb_to_lab(&a)[1]).abs() < 0.01 && (srgb_to_lab(&a)[2]).abs() < 0.01);
+        // A one step change of a mid gray is barely perceptible
+        assert!(delta_e(&[128, 128, 128, 255], &[129, 129, 129, 255]) < 1.0);
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Result;
+use log::*;
+
+use vulkanalia::prelude::v1_0::*;
+
+use crate::pipeline::reload_shader;
+use crate::shader::Spirv;
+use crate::shader_compiler;
+use crate::AppData;
+
+/// How often the shader sources are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Watches the shader sources under a directory by polling their
+/// modification times.
+#[derive(Clone, Debug)]
+pub struct ShaderWatcher {
+    dir: PathBuf,
+    modified: HashMap<PathBuf, SystemTime>,
+    last_poll: Instant,
+}
+
+impl ShaderWatcher {
+    /// Starts watching `dir`; sources already present count as unchanged.
+    pub fn new(dir: &Path) -> Self {
+        let mut modified = HashMap::new();
+        scan(dir, &mut modified);
+        info!("Watching {} shader source(s) in `{}`.", modified.len(), dir.display());
+        Self { dir: dir.into(), modified, last_poll: Instant::now() }
+    }
+
+    /// Returns the sources created or modified since the last poll, checking
+    /// at most once per [`POLL_INTERVAL`].
+    pub fn poll(&mut self) -> Vec<PathBuf> {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return vec![];
+        }
+        self.last_poll = Instant::now();
+
+        let mut modified = HashMap::new();
+        scan(&self.dir, &mut modified);
+        let mut changed = modified
+            .iter()
+            .filter(|(path, time)| self.modified.get(*path) != Some(*time))
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+        changed.sort();
+        self.modified = modified;
+        changed
+    }
+
+    /// The name a source is embedded under: its path relative to the
+    /// watched directory, with `/` separators.
+    pub fn shader_name(&self, path: &Path) -> String {
+        let relative = path.strip_prefix(&self.dir).unwrap_or(path);
+        relative.iter().map(|c| c.to_string_lossy()).collect::<Vec<_>>().join("/")
+    }
+}
+
+fn scan(dir: &Path, modified: &mut HashMap<PathBuf, SystemTime>) {
+    let Ok(entries) = fs::read_dir(dir) else { return };
+    for path in entries.flatten().map(|e| e.path()) {
+        if path.is_dir() {
+            scan(&path, modified);
+        } else if shader_compiler::is_shader_source(&path) {
+            if let Ok(time) = fs::metadata(&path).and_then(|m| m.modified()) {
+                modified.insert(path, time);
+            }
+        }
+    }
+}
+
+/// Recompiles the changed shader sources and rebuilds the pipelines using
+/// them. Sources that fail to compile are logged and leave the pipelines as
+/// they were. Must be called between frames.
+pub unsafe fn reload_changed_shaders(device: &Device, data: &mut AppData, watcher: &mut ShaderWatcher) -> Result<()> {
+    for path in watcher.poll() {
+        let name = watcher.shader_name(&path);
+        match shader_compiler::compile(&path) {
+            Ok(words) => {
+                let rebuilt = reload_shader(device, data, &Spirv { name: name.clone(), words })?;
+                info!("Reloaded shader `{}` ({} pipeline(s) rebuilt).", name, rebuilt);
+            }
+            Err(diagnostics) => error!("Failed to compile shader `{}`:\n{}", name, diagnostics),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::File;
+
+    use super::*;
+
+    /// A fresh directory under the system temp dir, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("vulkan-works-hot-reload-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn write(&self, name: &str) -> PathBuf {
+            let path = self.0.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, "void main() {}\n").unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Polls without waiting for the poll interval.
+    fn poll_now(watcher: &mut ShaderWatcher) -> Vec<PathBuf> {
+        watcher.last_poll -= POLL_INTERVAL;
+        watcher.poll()
+    }
+
+    /// Moves the modification time of `path` forward, as an edit would.
+    fn touch(path: &Path) {
+        let time = fs::metadata(path).unwrap().modified().unwrap() + Duration::from_secs(10);
+        File::options().write(true).open(path).unwrap().set_modified(time).unwrap();
+    }
+
+    #[test]
+    fn modified_sources_are_reported_once() {
+        let dir = TempDir::new("modified");
+        let path = dir.write("triangle.frag");
+        let mut watcher = ShaderWatcher::new(&dir.0);
+        touch(&path);
+        assert_eq!(poll_now(&mut watcher), [path]);
+        assert!(poll_now(&mut watcher).is_empty());
+    }
+
+    #[test]
+    fn unchanged_sources_are_not_reported() {
+        let dir = TempDir::new("unchanged");
+        dir.write("triangle.vert");
+        dir.write("triangle.frag");
+        let mut watcher = ShaderWatcher::new(&dir.0);
+        assert!(poll_now(&mut watcher).is_empty());
+    }
+
+    #[test]
+    fn new_sources_are_picked_up() {
+        let dir = TempDir::new("new");
+        dir.write("triangle.vert");
+        let mut watcher = ShaderWatcher::new(&dir.0);
+        let path = dir.write("post/blur.frag");
+        assert_eq!(watcher.shader_name(&path), "post/blur.frag");
+        assert_eq!(poll_now(&mut watcher), [path]);
+    }
+
+    #[test]
+    fn non_shader_files_are_ignored() {
+        let dir = TempDir::new("ignored");
+        let notes = dir.write("notes.txt");
+        let mut watcher = ShaderWatcher::new(&dir.0);
+        touch(&notes);
+        dir.write("README.md");
+        dir.write("blur.frag.bak");
+        assert!(poll_now(&mut watcher).is_empty());
+    }
+}
@@ -0,0 +1,570 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+use nalgebra_glm as glm;
+use winit::event::{DeviceEvent, ElementState, MouseButton, MouseScrollDelta, VirtualKeyCode, WindowEvent};
+
+use crate::camera::{Camera, CameraController};
+
+/// Pixels of a touchpad scroll that count as one line of a mouse wheel.
+const PIXELS_PER_LINE: f32 = 20.0;
+
+/// A key or mouse button.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Button {
+    Key(VirtualKeyCode),
+    Mouse(MouseButton),
+}
+
+/// Keys that may be named in bindings, by their `VirtualKeyCode` names.
+const KEYS: &[VirtualKeyCode] = {
+    use VirtualKeyCode::*;
+    &[
+        A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+        Key0, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9,
+        F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
+        Up, Down, Left, Right, PageUp, PageDown, Home, End, Insert, Delete,
+        Space, Tab, Return, Back, Escape,
+        LShift, RShift, LControl, RControl, LAlt, RAlt,
+    ]
+};
+
+impl std::str::FromStr for Button {
+    type Err = anyhow::Error;
+
+    /// Parses a key name such as `W`, `Space` or `LShift`, or one of `MouseLeft`,
+    /// `MouseRight` and `MouseMiddle`, ignoring case.
+    fn from_str(s: &str) -> Result<Self> {
+        let mouse = [("mouseleft", MouseButton::Left), ("mouseright", MouseButton::Right), ("mousemiddle", MouseButton::Middle)];
+        let lower = s.trim().to_lowercase();
+        if let Some((_, button)) = mouse.iter().find(|(name, _)| *name == lower) {
+            return Ok(Self::Mouse(*button));
+        }
+        KEYS.iter()
+            .find(|k| format!("{:?}", k).to_lowercase() == lower)
+            .map(|k| Self::Key(*k))
+            .ok_or_else(|| anyhow!("Unknown key or mouse button `{}`.", s))
+    }
+}
+
+impl fmt::Display for Button {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Key(key) => write!(f, "{:?}", key),
+            Self::Mouse(button) => write!(f, "Mouse{:?}", button),
+        }
+    }
+}
+
+/// The input the app reacts to, decoupled from winit so state transitions can
+/// be driven by synthetic events.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum InputEvent {
+    Button { button: Button, pressed: bool },
+    /// Raw mouse movement in pixels, independent of the cursor.
+    MouseMotion { dx: f32, dy: f32 },
+    /// Cursor position in physical pixels of the window.
+    CursorMoved { x: f32, y: f32 },
+    /// Scrolled lines, positive away from the user.
+    Scroll(f32),
+    Focused(bool),
+}
+
+impl InputEvent {
+    pub fn from_window_event(event: &WindowEvent) -> Option<Self> {
+        let pressed = |state| state == ElementState::Pressed;
+        match event {
+            WindowEvent::KeyboardInput { input, .. } => input
+                .virtual_keycode
+                .map(|key| Self::Button { button: Button::Key(key), pressed: pressed(input.state) }),
+            WindowEvent::MouseInput { state, button, .. } => {
+                Some(Self::Button { button: Button::Mouse(*button), pressed: pressed(*state) })
+            }
+            WindowEvent::MouseWheel { delta, .. } => Some(Self::Scroll(match delta {
+                MouseScrollDelta::LineDelta(_, y) => *y,
+                MouseScrollDelta::PixelDelta(p) => p.y as f32 / PIXELS_PER_LINE,
+            })),
+            WindowEvent::CursorMoved { position, .. } => {
+                Some(Self::CursorMoved { x: position.x as f32, y: position.y as f32 })
+            }
+            WindowEvent::Focused(focused) => Some(Self::Focused(*focused)),
+            _ => None,
+        }
+    }
+
+    pub fn from_device_event(event: &DeviceEvent) -> Option<Self> {
+        match event {
+            DeviceEvent::MouseMotion { delta: (dx, dy) } => Some(Self::MouseMotion { dx: *dx as f32, dy: *dy as f32 }),
+            _ => None,
+        }
+    }
+}
+
+/// What the app is told to do by the input since the last frame.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InputState {
+    down: HashSet<Button>,
+    pressed: HashSet<Button>,
+    released: HashSet<Button>,
+    /// Mouse movement since the last frame.
+    pub mouse_delta: glm::Vec2,
+    /// Scrolled lines since the last frame.
+    pub scroll: f32,
+    pub cursor_position: glm::Vec2,
+    pub focused: bool,
+    /// Whether the cursor is hidden and confined to the window, turning all
+    /// mouse movement into looking around.
+    pub cursor_grabbed: bool,
+    grab_changed: bool,
+}
+
+impl InputState {
+    pub fn new() -> Self {
+        Self { focused: true, ..Default::default() }
+    }
+
+    pub fn handle(&mut self, event: InputEvent) {
+        match event {
+            InputEvent::Button { button, pressed: true } => {
+                if self.down.insert(button) {
+                    self.pressed.insert(button);
+                }
+            }
+            InputEvent::Button { button, pressed: false } => {
+                if self.down.remove(&button) {
+                    self.released.insert(button);
+                }
+            }
+            InputEvent::MouseMotion { dx, dy } => self.mouse_delta += glm::vec2(dx, dy),
+            InputEvent::CursorMoved { x, y } => self.cursor_position = glm::vec2(x, y),
+            InputEvent::Scroll(lines) => self.scroll += lines,
+            InputEvent::Focused(focused) => {
+                self.focused = focused;
+                if !focused {
+                    // Releases are not delivered to unfocused windows.
+                    self.released.extend(self.down.drain());
+                    self.set_cursor_grabbed(false);
+                }
+            }
+        }
+    }
+
+    /// Whether `button` is held down.
+    pub fn is_down(&self, button: Button) -> bool {
+        self.down.contains(&button)
+    }
+
+    /// Whether `button` went down since the last frame.
+    pub fn was_pressed(&self, button: Button) -> bool {
+        self.pressed.contains(&button)
+    }
+
+    /// Whether `button` went up since the last frame.
+    pub fn was_released(&self, button: Button) -> bool {
+        self.released.contains(&button)
+    }
+
+    pub fn set_cursor_grabbed(&mut self, grabbed: bool) {
+        self.grab_changed |= grabbed != self.cursor_grabbed;
+        self.cursor_grabbed = grabbed;
+    }
+
+    /// The new cursor grab state if it changed since the last call, for the
+    /// window to apply.
+    pub fn take_grab_change(&mut self) -> Option<bool> {
+        std::mem::take(&mut self.grab_changed).then_some(self.cursor_grabbed)
+    }
+
+    /// Forgets what happened during the frame, keeping held buttons.
+    pub fn end_frame(&mut self) {
+        self.pressed.clear();
+        self.released.clear();
+        self.mouse_delta = glm::Vec2::zeros();
+        self.scroll = 0.0;
+    }
+}
+
+/// Something the user can do with a bound button.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    Forward,
+    Back,
+    Left,
+    Right,
+    Up,
+    Down,
+    /// Moves faster while held.
+    Fast,
+    /// Looks around (or orbits) with the mouse while held.
+    Look,
+    /// Moves an orbit camera's target with the mouse while held.
+    Pan,
+    /// Toggles the cursor grab.
+    Grab,
+}
+
+impl Action {
+    pub const ALL: &'static [Action] = &[
+        Self::Forward,
+        Self::Back,
+        Self::Left,
+        Self::Right,
+        Self::Up,
+        Self::Down,
+        Self::Fast,
+        Self::Look,
+        Self::Pan,
+        Self::Grab,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Forward => "forward",
+            Self::Back => "back",
+            Self::Left => "left",
+            Self::Right => "right",
+            Self::Up => "up",
+            Self::Down => "down",
+            Self::Fast => "fast",
+            Self::Look => "look",
+            Self::Pan => "pan",
+            Self::Grab => "grab",
+        }
+    }
+}
+
+impl std::str::FromStr for Action {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL.iter().copied().find(|a| a.name() == s).ok_or_else(|| {
+            let names = Self::ALL.iter().map(|a| a.name()).collect::<Vec<_>>();
+            anyhow!("Unknown action `{}` (expected one of {}).", s, names.join(", "))
+        })
+    }
+}
+
+/// The buttons triggering each action.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bindings(HashMap<Action, Vec<Button>>);
+
+impl Default for Bindings {
+    /// WASD to move, Space and LControl for up and down, LShift to move
+    /// faster, the left mouse button to look, the middle one to pan and G to
+    /// grab the cursor.
+    fn default() -> Self {
+        use VirtualKeyCode::*;
+        let key = Button::Key;
+        Self(HashMap::from([
+            (Action::Forward, vec![key(W), key(Up)]),
+            (Action::Back, vec![key(S), key(Down)]),
+            (Action::Left, vec![key(A), key(Left)]),
+            (Action::Right, vec![key(D), key(Right)]),
+            (Action::Up, vec![key(Space)]),
+            (Action::Down, vec![key(LControl)]),
+            (Action::Fast, vec![key(LShift)]),
+            (Action::Look, vec![Button::Mouse(MouseButton::Left)]),
+            (Action::Pan, vec![Button::Mouse(MouseButton::Middle)]),
+            (Action::Grab, vec![key(G)]),
+        ]))
+    }
+}
+
+impl Bindings {
+    /// Applies a binding such as `forward=W,Up`, replacing the buttons of the
+    /// action. An empty list unbinds it.
+    pub fn bind(&mut self, binding: &str) -> Result<()> {
+        let (action, buttons) = binding
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Invalid binding `{}` (expected e.g. `forward=W,Up`).", binding))?;
+        let buttons = buttons
+            .split(',')
+            .filter(|b| !b.trim().is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<_>>>()?;
+        self.0.insert(action.trim().parse()?, buttons);
+        Ok(())
+    }
+
+    pub fn buttons(&self, action: Action) -> &[Button] {
+        self.0.get(&action).map_or(&[], |b| b.as_slice())
+    }
+
+    pub fn is_down(&self, input: &InputState, action: Action) -> bool {
+        self.buttons(action).iter().any(|b| input.is_down(*b))
+    }
+
+    pub fn was_pressed(&self, input: &InputState, action: Action) -> bool {
+        self.buttons(action).iter().any(|b| input.was_pressed(*b))
+    }
+
+    /// `1` if only `positive` is held, `-1` if only `negative` is, else `0`.
+    fn axis(&self, input: &InputState, positive: Action, negative: Action) -> f32 {
+        self.is_down(input, positive) as i32 as f32 - self.is_down(input, negative) as i32 as f32
+    }
+}
+
+/// How strongly input moves the camera.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ControlSettings {
+    /// Radians turned per pixel of mouse movement.
+    pub look_sensitivity: f32,
+    /// Multiples of the camera's scale moved per second.
+    pub move_speed: f32,
+    /// Factor applied to the speed while [`Action::Fast`] is held.
+    pub fast_multiplier: f32,
+    /// Factor the orbit distance changes by per scrolled line.
+    pub zoom_step: f32,
+}
+
+impl Default for ControlSettings {
+    fn default() -> Self {
+        Self { look_sensitivity: 0.005, move_speed: 1.0, fast_multiplier: 4.0, zoom_step: 0.9 }
+    }
+}
+
+/// Toggles the cursor grab when [`Action::Grab`] was pressed.
+pub fn update_cursor_grab(input: &mut InputState, bindings: &Bindings) {
+    if bindings.was_pressed(input, Action::Grab) {
+        input.set_cursor_grabbed(!input.cursor_grabbed);
+    }
+}
+
+/// Moves `camera` according to the input of a frame that took `dt` seconds.
+/// The mouse turns the camera while [`Action::Look`] is held or the cursor
+/// is grabbed.
+pub fn update_camera(camera: &mut Camera, input: &InputState, bindings: &Bindings, settings: &ControlSettings, dt: f32) {
+    let looking = input.cursor_grabbed || bindings.is_down(input, Action::Look);
+    let turn = if looking { input.mouse_delta * settings.look_sensitivity } else { glm::Vec2::zeros() };
+    let speed = settings.move_speed * if bindings.is_down(input, Action::Fast) { settings.fast_multiplier } else { 1.0 };
+    let step = speed * camera.scale * dt;
+    let forward = bindings.axis(input, Action::Forward, Action::Back);
+    let right = bindings.axis(input, Action::Right, Action::Left);
+    let up = bindings.axis(input, Action::Up, Action::Down);
+
+    match &mut camera.controller {
+        CameraController::Orbit(orbit) => {
+            orbit.rotate(turn.x, -turn.y);
+            if bindings.is_down(input, Action::Pan) && !looking {
+                let pan = input.mouse_delta * settings.look_sensitivity * 0.5;
+                orbit.pan(-pan.x, pan.y);
+            }
+            orbit.rotate(right * speed * dt, up * speed * dt);
+            let zoom = input.scroll + forward * speed * dt;
+            orbit.zoom(settings.zoom_step.powf(zoom));
+        }
+        CameraController::Fly(fly) => {
+            fly.look(turn.x, -turn.y);
+            fly.translate(right * step, up * step, forward * step + input.scroll * camera.scale * 0.1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::camera::{CameraMode, ProjectionMode};
+
+    const W: Button = Button::Key(VirtualKeyCode::W);
+    const LOOK: Button = Button::Mouse(MouseButton::Left);
+
+    fn press(button: Button) -> InputEvent {
+        InputEvent::Button { button, pressed: true }
+    }
+
+    fn release(button: Button) -> InputEvent {
+        InputEvent::Button { button, pressed: false }
+    }
+
+    #[test]
+    fn button_transitions() {
+        let mut input = InputState::new();
+        input.handle(press(W));
+        assert!(input.is_down(W) && input.was_pressed(W) && !input.was_released(W));
+
+        input.end_frame();
+        assert!(input.is_down(W) && !input.was_pressed(W) && !input.was_released(W));
+
+        // Key repeats do not count as new presses.
+        input.handle(press(W));
+        assert!(!input.was_pressed(W));
+
+        input.handle(release(W));
+        assert!(!input.is_down(W) && input.was_released(W));
+        input.end_frame();
+        assert!(!input.is_down(W) && !input.was_pressed(W) && !input.was_released(W));
+    }
+
+    #[test]
+    fn tap_within_a_frame() {
+        let mut input = InputState::new();
+        input.handle(press(W));
+        input.handle(release(W));
+        assert!(!input.is_down(W) && input.was_pressed(W) && input.was_released(W));
+    }
+
+    #[test]
+    fn release_without_press_is_ignored() {
+        let mut input = InputState::new();
+        input.handle(release(W));
+        assert!(!input.was_released(W));
+    }
+
+    #[test]
+    fn mouse_motion_accumulates_until_the_frame_ends() {
+        let mut input = InputState::new();
+        input.handle(InputEvent::MouseMotion { dx: 3.0, dy: -1.0 });
+        input.handle(InputEvent::MouseMotion { dx: 2.0, dy: -4.0 });
+        input.handle(InputEvent::Scroll(1.0));
+        input.handle(InputEvent::Scroll(0.5));
+        input.handle(InputEvent::CursorMoved { x: 10.0, y: 20.0 });
+        assert_eq!(input.mouse_delta, glm::vec2(5.0, -5.0));
+        assert_eq!(input.scroll, 1.5);
+
+        input.end_frame();
+        assert_eq!(input.mouse_delta, glm::Vec2::zeros());
+        assert_eq!(input.scroll, 0.0);
+        assert_eq!(input.cursor_position, glm::vec2(10.0, 20.0));
+    }
+
+    #[test]
+    fn losing_focus_releases_everything() {
+        let mut input = InputState::new();
+        input.handle(press(W));
+        input.handle(press(LOOK));
+        input.set_cursor_grabbed(true);
+        assert_eq!(input.take_grab_change(), Some(true));
+
+        input.handle(InputEvent::Focused(false));
+        assert!(!input.focused);
+        assert!(!input.is_down(W) && input.was_released(W) && input.was_released(LOOK));
+        assert_eq!(input.take_grab_change(), Some(false));
+        assert_eq!(input.take_grab_change(), None);
+    }
+
+    #[test]
+    fn unchanged_grab_is_not_reported() {
+        let mut input = InputState::new();
+        input.set_cursor_grabbed(false);
+        assert_eq!(input.take_grab_change(), None);
+        input.set_cursor_grabbed(true);
+        input.set_cursor_grabbed(false);
+        assert_eq!(input.take_grab_change(), Some(false));
+    }
+
+    #[test]
+    fn grab_action_toggles_the_cursor() {
+        let bindings = Bindings::default();
+        let mut input = InputState::new();
+        let g = Button::Key(VirtualKeyCode::G);
+        input.handle(press(g));
+        update_cursor_grab(&mut input, &bindings);
+        assert!(input.cursor_grabbed);
+        // Holding the key does not toggle it back.
+        input.end_frame();
+        update_cursor_grab(&mut input, &bindings);
+        assert!(input.cursor_grabbed);
+    }
+
+    #[test]
+    fn mouse_motion_events() {
+        let event = DeviceEvent::MouseMotion { delta: (1.5, -2.0) };
+        assert_eq!(InputEvent::from_device_event(&event), Some(InputEvent::MouseMotion { dx: 1.5, dy: -2.0 }));
+        assert_eq!(InputEvent::from_device_event(&DeviceEvent::Added), None);
+    }
+
+    #[test]
+    fn parse_buttons() {
+        assert_eq!("w".parse::<Button>().unwrap(), W);
+        assert_eq!(" LShift ".parse::<Button>().unwrap(), Button::Key(VirtualKeyCode::LShift));
+        assert_eq!("MOUSEMIDDLE".parse::<Button>().unwrap(), Button::Mouse(MouseButton::Middle));
+        assert!("Mouse4".parse::<Button>().is_err());
+        assert!("Numpad0".parse::<Button>().is_err());
+        for button in [W, LOOK, Button::Key(VirtualKeyCode::Key1)] {
+            assert_eq!(button.to_string().parse::<Button>().unwrap(), button);
+        }
+    }
+
+    #[test]
+    fn bind_replaces_the_buttons() {
+        let mut bindings = Bindings::default();
+        bindings.bind("forward=I, Up").unwrap();
+        let forward = [Button::Key(VirtualKeyCode::I), Button::Key(VirtualKeyCode::Up)];
+        assert_eq!(bindings.buttons(Action::Forward), forward);
+        bindings.bind("look=MouseRight").unwrap();
+        assert_eq!(bindings.buttons(Action::Look), [Button::Mouse(MouseButton::Right)]);
+        bindings.bind("grab=").unwrap();
+        assert!(bindings.buttons(Action::Grab).is_empty());
+        assert_eq!(bindings.buttons(Action::Back), Bindings::default().buttons(Action::Back));
+    }
+
+    #[test]
+    fn invalid_bindings() {
+        let mut bindings = Bindings::default();
+        for binding in ["forward", "jump=Space", "forward=W,Mouse4", "=W"] {
+            assert!(bindings.bind(binding).is_err(), "{}", binding);
+        }
+        assert_eq!(bindings, Bindings::default());
+    }
+
+    #[test]
+    fn every_action_has_a_default_binding() {
+        let bindings = Bindings::default();
+        for action in Action::ALL {
+            assert!(!bindings.buttons(*action).is_empty(), "{:?}", action);
+            assert_eq!(action.name().parse::<Action>().unwrap(), *action);
+        }
+    }
+
+    #[test]
+    fn any_bound_button_triggers_the_action() {
+        let bindings = Bindings::default();
+        let mut input = InputState::new();
+        input.handle(press(Button::Key(VirtualKeyCode::Up)));
+        assert!(bindings.is_down(&input, Action::Forward));
+        assert!(bindings.was_pressed(&input, Action::Forward));
+        assert!(!bindings.is_down(&input, Action::Back));
+        assert_eq!(bindings.axis(&input, Action::Forward, Action::Back), 1.0);
+        input.handle(press(Button::Key(VirtualKeyCode::S)));
+        assert_eq!(bindings.axis(&input, Action::Forward, Action::Back), 0.0);
+    }
+
+    #[test]
+    fn mouse_turns_the_camera_only_while_looking() {
+        let bindings = Bindings::default();
+        let settings = ControlSettings::default();
+        let mut camera = Camera::framing(CameraMode::Fly, ProjectionMode::Perspective, &glm::Vec3::zeros(), 1.0);
+        let mut input = InputState::new();
+        input.handle(InputEvent::MouseMotion { dx: 100.0, dy: 0.0 });
+
+        let before = camera;
+        update_camera(&mut camera, &input, &bindings, &settings, 0.1);
+        assert_eq!(camera, before);
+
+        input.handle(press(LOOK));
+        update_camera(&mut camera, &input, &bindings, &settings, 0.1);
+        let CameraController::Fly(fly) = camera.controller else { unreachable!() };
+        assert_eq!(fly.yaw, 100.0 * settings.look_sensitivity);
+    }
+
+    #[test]
+    fn fast_moves_further() {
+        let bindings = Bindings::default();
+        let settings = ControlSettings::default();
+        let distance = |fast: bool| {
+            let mut camera = Camera::framing(CameraMode::Fly, ProjectionMode::Perspective, &glm::Vec3::zeros(), 1.0);
+            let start = camera.eye();
+            let mut input = InputState::new();
+            input.handle(press(W));
+            if fast {
+                input.handle(press(Button::Key(VirtualKeyCode::LShift)));
+            }
+            update_camera(&mut camera, &input, &bindings, &settings, 0.5);
+            glm::distance(&start, &camera.eye())
+        };
+        assert!((distance(false) - 0.5).abs() < 1e-5);
+        assert!((distance(true) - 2.0).abs() < 1e-5);
+    }
+}
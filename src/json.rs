@@ -0,0 +1,189 @@
+use std::fmt::{self, Write};
+
+/// A minimal JSON document used for machine readable diagnostics.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    /// A number, kept in its textual form so 64-bit integers stay exact.
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    /// An object; members keep their insertion order.
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Creates an object from `(key, value)` pairs.
+    pub fn object<K: Into<String>>(members: impl IntoIterator<Item = (K, Json)>) -> Self {
+        Self::Object(members.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+
+    /// Creates a string array of the names of the flags that are set, given
+    /// as `(name, set)` pairs.
+    pub fn flags<'a>(flags: impl IntoIterator<Item = (&'a str, bool)>) -> Self {
+        Self::Array(flags.into_iter().filter(|(_, set)| *set).map(|(name, _)| Self::String(name.into())).collect())
+    }
+
+    fn write(&self, f: &mut fmt::Formatter, indent: usize) -> fmt::Result {
+        match self {
+            Self::Null => f.write_str("null"),
+            Self::Bool(b) => write!(f, "{}", b),
+            Self::Number(n) => f.write_str(n),
+            Self::String(s) => write_string(f, s),
+            Self::Array(items) if items.is_empty() => f.write_str("[]"),
+            Self::Array(items) => {
+                f.write_str("[\n")?;
+                for (i, item) in items.iter().enumerate() {
+                    write!(f, "{:1$}", "", (indent + 1) * 2)?;
+                    item.write(f, indent + 1)?;
+                    f.write_str(if i + 1 < items.len() { ",\n" } else { "\n" })?;
+                }
+                write!(f, "{:1$}]", "", indent * 2)
+            }
+            Self::Object(members) if members.is_empty() => f.write_str("{}"),
+            Self::Object(members) => {
+                f.write_str("{\n")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    write!(f, "{:1$}", "", (indent + 1) * 2)?;
+                    write_string(f, key)?;
+                    f.write_str(": ")?;
+                    value.write(f, indent + 1)?;
+                    f.write_str(if i + 1 < members.len() { ",\n" } else { "\n" })?;
+                }
+                write!(f, "{:1$}}}", "", indent * 2)
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+/// Conversion into a [`Json`] value.
+pub trait ToJson {
+    fn to_json(&self) -> Json;
+}
+
+macro_rules! number {
+    ($($ty:ty),*) => {
+        $(impl ToJson for $ty {
+            fn to_json(&self) -> Json {
+                Json::Number(self.to_string())
+            }
+        })*
+    };
+}
+
+number!(i32, u32, u64, usize);
+
+impl ToJson for f32 {
+    fn to_json(&self) -> Json {
+        if self.is_finite() {
+            Json::Number(format!("{:?}", self))
+        } else {
+            Json::Null
+        }
+    }
+}
+
+impl ToJson for bool {
+    fn to_json(&self) -> Json {
+        Json::Bool(*self)
+    }
+}
+
+impl ToJson for str {
+    fn to_json(&self) -> Json {
+        Json::String(self.into())
+    }
+}
+
+impl ToJson for String {
+    fn to_json(&self) -> Json {
+        Json::String(self.clone())
+    }
+}
+
+impl<T: ToJson> ToJson for [T] {
+    fn to_json(&self) -> Json {
+        Json::Array(self.iter().map(ToJson::to_json).collect())
+    }
+}
+
+impl<T: ToJson, const N: usize> ToJson for [T; N] {
+    fn to_json(&self) -> Json {
+        self[..].to_json()
+    }
+}
+
+impl<T: ToJson> ToJson for Option<T> {
+    fn to_json(&self) -> Json {
+        self.as_ref().map_or(Json::Null, ToJson::to_json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_strings() {
+        let json = "quote \" backslash \\ \n\r\t \u{1} \u{1f} é".to_json();
+        assert_eq!(json.to_string(), r#""quote \" backslash \\ \n\r\t \u0001 \u001f é""#);
+    }
+
+    #[test]
+    fn empty_containers() {
+        assert_eq!(Json::Array(vec![]).to_string(), "[]");
+        assert_eq!(Json::Object(vec![]).to_string(), "{}");
+        let json = Json::object([("a", Json::Array(vec![])), ("b", Json::Object(vec![]))]);
+        assert_eq!(json.to_string(), "{\n  \"a\": [],\n  \"b\": {}\n}");
+    }
+
+    #[test]
+    fn nested_indentation() {
+        let json = Json::object([("items", [1u32, 2].to_json()), ("name", "x".to_json())]);
+        assert_eq!(json.to_string(), "{\n  \"items\": [\n    1,\n    2\n  ],\n  \"name\": \"x\"\n}");
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(u64::MAX.to_json().to_string(), "18446744073709551615");
+        assert_eq!(1.0f32.to_json().to_string(), "1.0");
+        assert_eq!(0.25f32.to_json().to_string(), "0.25");
+        assert_eq!(f32::NAN.to_json(), Json::Null);
+        assert_eq!(f32::INFINITY.to_json(), Json::Null);
+        assert_eq!(f32::NEG_INFINITY.to_json(), Json::Null);
+        assert_eq!(None::<u32>.to_json(), Json::Null);
+    }
+
+    #[test]
+    fn flags() {
+        assert_eq!(Json::flags([]), Json::Array(vec![]));
+        assert_eq!(Json::flags([("GRAPHICS", false), ("COMPUTE", false)]), Json::Array(vec![]));
+        assert_eq!(
+            Json::flags([("GRAPHICS", true), ("COMPUTE", false), ("TRANSFER", true)]),
+            Json::Array(vec!["GRAPHICS".to_json(), "TRANSFER".to_json()]),
+        );
+    }
+}
@@ -8,20 +8,76 @@
 use std::collections::HashSet;
 use std::ffi::CStr;
 use std::os::raw::c_void;
+use std::path::PathBuf;
+use std::time::Instant;
 
 use anyhow::{anyhow, Result};
 use log::*;
-use thiserror::Error;
 
 use winit::dpi::LogicalSize;
 use winit::event::{Event, WindowEvent};
 use winit::event_loop::{ControlFlow, EventLoop};
-use winit::window::{Window, WindowBuilder};
+use winit::window::{CursorGrabMode, Window, WindowBuilder};
 use vulkanalia::loader::{LibloadingLoader, LIBRARY};
 use vulkanalia::window as vk_window;
 use vulkanalia::prelude::v1_0::*;
 
-use vulkanalia::vk::{ExtDebugUtilsExtension, KhrSurfaceExtension};
+use vulkanalia::vk::{ExtDebugUtilsExtension, KhrSurfaceExtension, KhrSwapchainExtension};
+
+mod args;
+mod camera;
+mod capture;
+mod commands;
+mod depth;
+mod device;
+mod diagnostics;
+mod features;
+mod golden;
+mod hot_reload;
+mod input;
+mod json;
+mod memory;
+mod model;
+mod msaa;
+mod offscreen;
+mod pipeline;
+mod pipeline_cache;
+mod reflect;
+mod render_pass;
+mod scene;
+mod shader;
+mod shader_compiler;
+mod swapchain;
+mod sync;
+mod texture;
+mod upload;
+
+use args::Args;
+use camera::Camera;
+use capture::{capture_image, destroy_pending_capture, finish_capture, request_capture, CapturedFrame, PendingCapture};
+use commands::{allocate_command_buffers, CLEAR_COLOR, create_command_pool, record_command_buffer};
+use depth::{create_depth_objects, destroy_depth_objects, get_depth_format};
+use msaa::{choose_msaa_samples, choose_sample_shading, create_color_objects, destroy_color_objects};
+use device::{create_logical_device, pick_physical_device};
+use hot_reload::{reload_changed_shaders, ShaderWatcher};
+use input::{update_camera, update_cursor_grab, Bindings, ControlSettings, InputEvent, InputState};
+use memory::{Allocation, Allocator};
+use model::Model;
+use offscreen::{create_offscreen_target, destroy_offscreen_target};
+use pipeline::{add_pipeline, create_pipelines, destroy_pipelines, triangle_pipeline_desc, PipelineId, Pipelines};
+use pipeline_cache::{create_pipeline_cache, destroy_pipeline_cache, save_pipeline_cache};
+use render_pass::{create_framebuffers, create_render_pass};
+use scene::{destroy_scene, load_scene, update_uniform_buffer};
+use swapchain::{
+    create_ownership_transfer, create_swapchain, create_swapchain_image_views, destroy_ownership_transfer,
+    destroy_swapchain, needs_ownership_transfer, VsyncPolicy,
+};
+use sync::{
+    create_frame_sync_objects, create_image_sync_objects, destroy_frame_sync_objects, destroy_image_sync_objects,
+    ImageOwnership,
+};
+use texture::Texture;
+use upload::{create_uploader, destroy_uploader, Uploader, DEFAULT_STAGING_BUFFERS, DEFAULT_STAGING_SIZE};
 
 const VALIDATION_ENABLED: bool = cfg!(debug_assertions);
 const VALIDATION_LAYER: vk::ExtensionName = vk::ExtensionName::from_bytes(b"VK_LAYER_KHRONOS_validation");
@@ -34,34 +90,280 @@ struct App {
     instance: Instance,
     data: AppData,
     device: Device,
+    /// Set when the window was resized so the swapchain gets recreated
+    resized: bool,
+    /// Index of the frame in flight being rendered
+    frame: usize,
+    vsync: VsyncPolicy,
+    /// Watches the shader sources when hot-reloading is enabled
+    shader_watcher: Option<ShaderWatcher>,
+    // Input
+    input: InputState,
+    bindings: Bindings,
+    controls: ControlSettings,
+    last_update: Instant,
 }
 
 impl App {
-    /// Creates our Vulkan App
-    unsafe fn create (window: &Window) -> Result<Self> {
-        let mut data = AppData::default();
+    /// Creates our Vulkan App, rendering offscreen when there is no window
+    unsafe fn create (window: Option<&Window>, args: &Args) -> Result<Self> {
+        let mut data = AppData { clear_color: CLEAR_COLOR, draw_triangle: true, ..Default::default() };
         let loader = LibloadingLoader::new (LIBRARY)?;
         let entry = Entry::new (loader).map_err(|b| anyhow!("{}", b))?;
-        // Only for X11
         let instance = create_instance(window, &entry, &mut data)?;
-        data.surface = vk_window::create_surface(&instance, window)?;
-        pick_physical_device(&instance, &mut data)?;
+        if let Some(window) = window {
+            data.surface = vk_window::create_surface(&instance, window)?;
+        }
+        pick_physical_device(&instance, &mut data, args.device.as_ref())?;
         let device = create_logical_device(&instance, &mut data)?;
-        Ok(Self {entry, instance, data, device})
+        data.allocator = Allocator::new(&instance, data.physical_device);
+        data.depth_format = get_depth_format(&instance, &data)?;
+        choose_msaa_samples(&mut data, args.msaa);
+        choose_sample_shading(&mut data, args.sample_shading);
+        create_command_pool(&instance, &device, &mut data)?;
+        create_pipeline_cache(&device, &mut data, args.pipeline_cache.as_deref())?;
+        create_uploader(&device, &mut data, DEFAULT_STAGING_BUFFERS, DEFAULT_STAGING_SIZE)?;
+        if let Some(window) = window {
+            create_swapchain_resources(window, &instance, &device, &mut data, args.vsync)?;
+            create_frame_sync_objects(&device, &mut data, args.frames_in_flight)?;
+        } else {
+            create_offscreen_target(&instance, &device, &mut data, args.size)?;
+            create_color_objects(&device, &mut data)?;
+            create_depth_objects(&device, &mut data)?;
+            create_render_pass(&device, &mut data, vk::ImageLayout::TRANSFER_SRC_OPTIMAL)?;
+            create_framebuffers(&device, &mut data)?;
+            data.offscreen_command_buffer = allocate_command_buffers(&device, &data, 1)?[0];
+            data.offscreen_fence = device.create_fence(&vk::FenceCreateInfo::default(), None)?;
+        }
+        data.triangle_pipeline = add_pipeline(&device, &mut data, triangle_pipeline_desc()?)?;
+        load_scene(&instance, &device, &mut data, args)?;
+        let shader_watcher = args.hot_reload.then(|| ShaderWatcher::new(&args.shader_dir));
+        Ok(Self {
+            entry,
+            instance,
+            data,
+            device,
+            resized: false,
+            frame: 0,
+            vsync: args.vsync,
+            shader_watcher,
+            input: InputState::new(),
+            bindings: args.bindings.clone(),
+            controls: ControlSettings::default(),
+            last_update: Instant::now(),
+        })
+    }
+
+    /// Applies the input received since the last frame to the camera and cursor
+    fn update (&mut self, window: &Window) {
+        let now = Instant::now();
+        let dt = now.duration_since(self.last_update).as_secs_f32();
+        self.last_update = now;
+
+        update_cursor_grab(&mut self.input, &self.bindings);
+        if let Some(grabbed) = self.input.take_grab_change() {
+            let mode = if grabbed { CursorGrabMode::Confined } else { CursorGrabMode::None };
+            let result = window
+                .set_cursor_grab(mode)
+                .or_else(|_| window.set_cursor_grab(if grabbed { CursorGrabMode::Locked } else { mode }));
+            match result {
+                Ok(()) => window.set_cursor_visible(!grabbed),
+                Err(e) => {
+                    warn!("Failed to change the cursor grab: {}.", e);
+                    self.input.cursor_grabbed = false;
+                }
+            }
+        }
+        update_camera(&mut self.data.camera, &self.input, &self.bindings, &self.controls, dt);
+        self.input.end_frame();
     }
 
     /// Render a frame for out vulkan app
     unsafe fn render (&mut self, window: &Window) -> Result<()> {
+        if let Some(watcher) = &mut self.shader_watcher {
+            reload_changed_shaders(&self.device, &mut self.data, watcher)?;
+        }
+
+        let frame = self.frame;
+        let in_flight_fence = self.data.in_flight_fences[frame];
+        self.device.wait_for_fences(&[in_flight_fence], true, u64::MAX)?;
+
+        let result = self
+            .device
+            .acquire_next_image_khr(self.data.swapchain, u64::MAX, self.data.image_available_semaphores[frame], vk::Fence::null());
+        // A suboptimal image can still be presented, recreate after presenting it
+        let image_index = match result {
+            Ok((image_index, vk::SuccessCode::SUBOPTIMAL_KHR)) => {
+                self.resized = true;
+                image_index as usize
+            }
+            Ok((image_index, _)) => image_index as usize,
+            Err(vk::ErrorCode::OUT_OF_DATE_KHR) => return self.recreate_swapchain(window),
+            Err(e) => return Err(anyhow!(e)),
+        };
+
+        // Another frame may still be rendering to this image
+        if let Some(owner) = self.data.images_in_flight.acquire(image_index, frame) {
+            self.device.wait_for_fences(&[self.data.in_flight_fences[owner]], true, u64::MAX)?;
+        }
+
+        self.device.reset_fences(&[in_flight_fence])?;
+        let command_buffer = self.data.frame_command_buffers[frame];
+        if self.data.model.is_some() {
+            update_uniform_buffer(&self.data, frame)?;
+        }
+        record_command_buffer(&self.device, &self.data, command_buffer, image_index, frame)?;
+        if let Some(capture) = &mut self.data.pending_capture {
+            capture.fence.get_or_insert(in_flight_fence);
+        }
+
+        let wait_semaphores = &[self.data.image_available_semaphores[frame]];
+        let wait_stages = &[vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
+        let command_buffers = &[command_buffer];
+        let signal_semaphores = &[self.data.render_finished_semaphores[image_index]];
+        let submit_info = vk::SubmitInfo::builder()
+            .wait_semaphores(wait_semaphores)
+            .wait_dst_stage_mask(wait_stages)
+            .command_buffers(command_buffers)
+            .signal_semaphores(signal_semaphores);
+        self.device.queue_submit(self.data.graphics_queue, &[submit_info], in_flight_fence)?;
+
+        // Hand the image over to the present queue family if it differs
+        let present_wait_semaphores = if needs_ownership_transfer(&self.data) {
+            let present_ready_semaphores = &[self.data.present_ready_semaphores[image_index]];
+            let wait_stages = &[vk::PipelineStageFlags::ALL_COMMANDS];
+            let command_buffers = &[self.data.present_command_buffers[image_index]];
+            let submit_info = vk::SubmitInfo::builder()
+                .wait_semaphores(signal_semaphores)
+                .wait_dst_stage_mask(wait_stages)
+                .command_buffers(command_buffers)
+                .signal_semaphores(present_ready_semaphores);
+            self.device.queue_submit(self.data.present_queue, &[submit_info], vk::Fence::null())?;
+            *present_ready_semaphores
+        } else {
+            *signal_semaphores
+        };
+
+        let swapchains = &[self.data.swapchain];
+        let image_indices = &[image_index as u32];
+        let present_info = vk::PresentInfoKHR::builder()
+            .wait_semaphores(&present_wait_semaphores)
+            .swapchains(swapchains)
+            .image_indices(image_indices);
+        let result = self.device.queue_present_khr(self.data.present_queue, &present_info);
+        self.data.image_index = image_index;
+
+        match result {
+            Ok(vk::SuccessCode::SUBOPTIMAL_KHR) | Err(vk::ErrorCode::OUT_OF_DATE_KHR) => self.resized = true,
+            Err(e) => return Err(anyhow!(e)),
+            Ok(_) => {}
+        }
+        if self.resized {
+            self.resized = false;
+            self.recreate_swapchain(window)?;
+        }
+
+        self.frame = (self.frame + 1) % self.data.in_flight_fences.len();
         Ok(())
     }
 
+    /// Recreates the swapchain and everything depending on its size or format
+    unsafe fn recreate_swapchain (&mut self, window: &Window) -> Result<()> {
+        // A zero sized swapchain is invalid, try again once the window is restored
+        let size = window.inner_size();
+        if size.width == 0 || size.height == 0 {
+            self.resized = true;
+            return Ok(());
+        }
+
+        self.device.device_wait_idle()?;
+        self.destroy_swapchain();
+        create_swapchain_resources(window, &self.instance, &self.device, &mut self.data, self.vsync)
+    }
+
+    /// Destroys the swapchain and everything depending on its size or format
+    unsafe fn destroy_swapchain (&mut self) {
+        destroy_ownership_transfer(&self.device, &mut self.data);
+        destroy_image_sync_objects(&self.device, &mut self.data);
+        destroy_pipelines(&self.device, &mut self.data);
+        self.data.framebuffers.drain(..).for_each(|f| self.device.destroy_framebuffer(f, None));
+        self.device.destroy_render_pass(self.data.render_pass, None);
+        destroy_depth_objects(&self.device, &mut self.data);
+        destroy_color_objects(&self.device, &mut self.data);
+        destroy_swapchain(&self.device, &mut self.data);
+    }
+
+    /// Renders a frame into the offscreen image and waits for it to finish
+    unsafe fn render_offscreen (&mut self) -> Result<()> {
+        let command_buffer = self.data.offscreen_command_buffer;
+        if self.data.model.is_some() {
+            update_uniform_buffer(&self.data, 0)?;
+        }
+        record_command_buffer(&self.device, &self.data, command_buffer, 0, 0)?;
+
+        let command_buffers = &[command_buffer];
+        let submit_info = vk::SubmitInfo::builder().command_buffers(command_buffers);
+        self.device.queue_submit(self.data.graphics_queue, &[submit_info], self.data.offscreen_fence)?;
+        self.device.wait_for_fences(&[self.data.offscreen_fence], true, u64::MAX)?;
+        self.device.reset_fences(&[self.data.offscreen_fence])?;
+        self.data.image_index = 0;
+        Ok(())
+    }
+
+    /// Makes the next windowed frame copy its swapchain image for `capture`
+    unsafe fn request_capture (&mut self) -> Result<()> {
+        request_capture(&self.device, &mut self.data)
+    }
+
+    /// Whether a requested capture has been recorded into a frame
+    fn capture_recorded (&self) -> bool {
+        self.data.pending_capture.is_some_and(|c| c.fence.is_some())
+    }
+
+    /// Reads back the most recently rendered image. Windowed frames must have
+    /// been captured while rendering, since presented images are not ours.
+    unsafe fn capture (&mut self) -> Result<CapturedFrame> {
+        if !self.data.surface.is_null() {
+            return finish_capture(&self.device, &mut self.data);
+        }
+        let image = *self.data.target_images
+            .get(self.data.image_index)
+            .ok_or_else(|| anyhow!("No rendered image to capture."))?;
+        let layout = self.data.render_pass_final_layout;
+        capture_image(&self.device, &mut self.data, image, layout)
+    }
+
     /// Destroyes out Vulkan app
     unsafe fn destroy (&mut self) {
+        self.device.device_wait_idle().unwrap();
+        destroy_pending_capture(&self.device, &mut self.data);
+        self.device.destroy_fence(self.data.offscreen_fence, None);
+        destroy_frame_sync_objects(&self.device, &mut self.data);
+        if self.data.surface.is_null() {
+            destroy_pipelines(&self.device, &mut self.data);
+            self.data.framebuffers.drain(..).for_each(|f| self.device.destroy_framebuffer(f, None));
+            self.device.destroy_render_pass(self.data.render_pass, None);
+            destroy_depth_objects(&self.device, &mut self.data);
+            destroy_color_objects(&self.device, &mut self.data);
+            destroy_offscreen_target(&self.device, &mut self.data);
+        } else {
+            self.destroy_swapchain();
+        }
+        if let Err(e) = save_pipeline_cache(&self.device, &self.data) {
+            warn!("{:#}", e);
+        }
+        destroy_pipeline_cache(&self.device, &mut self.data);
+        destroy_scene(&self.device, &mut self.data);
+        destroy_uploader(&self.device, &mut self.data);
+        self.device.destroy_command_pool(self.data.command_pool, None);
+        self.data.allocator.destroy(&self.device);
+        self.device.destroy_device(None);
+        if !self.data.surface.is_null() {
+            self.instance.destroy_surface_khr(self.data.surface, None);
+        }
         if VALIDATION_ENABLED {
             self.instance.destroy_debug_utils_messenger_ext(self.data.messenger, None);
         }
-        self.instance.destroy_surface_khr(self.data.surface, None);
-        self.device.destroy_device(None);
         self.instance.destroy_instance(None);
     }
 }
@@ -70,106 +372,111 @@ impl App {
 struct AppData{
     messenger: vk::DebugUtilsMessengerEXT,
     physical_device: vk::PhysicalDevice,
+    physical_device_properties: vk::PhysicalDeviceProperties,
+    /// Features enabled on the logical device
+    enabled_features: vk::PhysicalDeviceFeatures,
+    graphics_queue_family: u32,
     graphics_queue: vk::Queue,
     surface: vk::SurfaceKHR,
-    //present_queue: vk::Queue
+    present_queue_family: u32,
+    present_queue: vk::Queue,
+    /// Async compute queue, the graphics queue if there is no dedicated family
+    compute_queue_family: u32,
+    compute_queue: vk::Queue,
+    /// Transfer queue, the graphics queue if there is no dedicated family
+    transfer_queue_family: u32,
+    transfer_queue: vk::Queue,
+    swapchain: vk::SwapchainKHR,
+    allocator: Allocator,
+    uploader: Uploader,
+    // Render target (swapchain or offscreen image)
+    target_format: vk::Format,
+    target_extent: vk::Extent2D,
+    target_usage: vk::ImageUsageFlags,
+    target_images: Vec<vk::Image>,
+    target_image_views: Vec<vk::ImageView>,
+    /// Index of the most recently rendered target image
+    image_index: usize,
+    // Depth attachment, recreated with the render target
+    depth_format: vk::Format,
+    depth_image: vk::Image,
+    depth_image_allocation: Allocation,
+    depth_image_view: vk::ImageView,
+    // Multisampling; the color attachment is resolved into the render target
+    // and only exists with more than one sample
+    msaa_samples: vk::SampleCountFlags,
+    /// Minimum fraction of samples shaded individually, if sample shading is on
+    min_sample_shading: Option<f32>,
+    color_image: vk::Image,
+    color_image_allocation: Allocation,
+    color_image_view: vk::ImageView,
+    // Offscreen rendering
+    offscreen_image_allocation: Allocation,
+    offscreen_command_buffer: vk::CommandBuffer,
+    offscreen_fence: vk::Fence,
+    // Windowed rendering, per frame in flight
+    frame_command_buffers: Vec<vk::CommandBuffer>,
+    image_available_semaphores: Vec<vk::Semaphore>,
+    in_flight_fences: Vec<vk::Fence>,
+    // Windowed rendering, per swapchain image
+    render_finished_semaphores: Vec<vk::Semaphore>,
+    images_in_flight: ImageOwnership,
+    // Swapchain image ownership transfer to a separate present queue family
+    present_command_pool: vk::CommandPool,
+    present_command_buffers: Vec<vk::CommandBuffer>,
+    present_ready_semaphores: Vec<vk::Semaphore>,
+    // Windowed frame capture, recorded into the command buffer of a frame
+    pending_capture: Option<PendingCapture>,
+    // Render pass
+    render_pass: vk::RenderPass,
+    render_pass_final_layout: vk::ImageLayout,
+    framebuffers: Vec<vk::Framebuffer>,
+    // Pipelines
+    pipeline_cache: vk::PipelineCache,
+    /// Where `pipeline_cache` is saved on exit, if anywhere
+    pipeline_cache_path: Option<PathBuf>,
+    pipelines: Pipelines,
+    triangle_pipeline: PipelineId,
+    draw_triangle: bool,
+    // Scene
+    model: Option<Model>,
+    texture: Option<Texture>,
+    sampler: vk::Sampler,
+    camera: Camera,
+    /// Camera uniforms and the descriptor sets binding them, per frame in flight
+    uniform_buffers: Vec<vk::Buffer>,
+    uniform_allocations: Vec<Allocation>,
+    descriptor_set_layouts: Vec<vk::DescriptorSetLayout>,
+    descriptor_pool: vk::DescriptorPool,
+    descriptor_sets: Vec<vk::DescriptorSet>,
+    mesh_pipeline: PipelineId,
+    // Commands
+    command_pool: vk::CommandPool,
+    clear_color: [f32; 4],
 }
 
-#[derive(Debug, Error)]
-#[error("Missing {0}.")]
-pub struct SuitabilityError (pub &'static str);
-
-unsafe fn pick_physical_device (instance: &Instance, data: &mut AppData) -> Result<()>{
-    for physical_device in instance.enumerate_physical_devices()? {
-        let proparties = instance.get_physical_device_properties(physical_device);
-        if let Err(error) = check_physical_device(instance, data, physical_device) {
-            warn!("Skipping physical device (`{}`): {}", proparties.device_name, error);
-        } else {
-            info!("Selected Device is `{}`", proparties.device_name);
-            data.physical_device = physical_device;
-            return Ok(())
-        }
-    }
-    Err (anyhow!("Failed to find suitable grpahics device"))
-}
-
-unsafe fn check_physical_device (
+/// Creates the swapchain and everything depending on its size or format
+unsafe fn create_swapchain_resources (
+    window: &Window,
     instance: &Instance,
-    data: &AppData,
-    physical_device: vk::PhysicalDevice
+    device: &Device,
+    data: &mut AppData,
+    vsync: VsyncPolicy,
 ) -> Result<()> {
-    let proparties = instance.get_physical_device_properties(physical_device);
-    if String::from_utf8_lossy (proparties.device_name.as_bytes()).contains("NVIDIA") {
-        QueueFamilyIndices::get(instance, data, physical_device)?;
-        Ok(())
-    } else {
-        Err (anyhow!("!Nvidia"))
-    }
+    create_swapchain(window, instance, device, data, vsync)?;
+    create_swapchain_image_views(device, data)?;
+    create_color_objects(device, data)?;
+    create_depth_objects(device, data)?;
+    create_render_pass(device, data, vk::ImageLayout::PRESENT_SRC_KHR)?;
+    create_framebuffers(device, data)?;
+    create_pipelines(device, data)?;
+    create_image_sync_objects(device, data)?;
+    create_ownership_transfer(device, data)?;
+    Ok(())
 }
 
-unsafe fn create_logical_device (instance: &Instance, data: &mut AppData) -> Result<Device> {
-    let indices = QueueFamilyIndices::get (instance, data, data.physical_device)?;
-    let queue_priorities = &[1.0];
-
-    let queue_info = vk::DeviceQueueCreateInfo::builder()
-        .queue_family_index (indices.graphics)
-        .queue_priorities (queue_priorities);
-
-    let leayers = if VALIDATION_ENABLED {
-        vec![VALIDATION_LAYER.as_ptr()]
-    } else {
-        vec![]
-    };
-
-    let features = vk::PhysicalDeviceFeatures::builder();
-    let queue_info = &[queue_info];
-    let info = vk::DeviceCreateInfo::builder()
-        .queue_create_infos (queue_info)
-        .enabled_layer_names (&leayers)
-        .enabled_features (&features);
-    let device = instance.create_device(data.physical_device, &info, None)?;
-    data.graphics_queue = device.get_device_queue(indices.graphics, 0);
-    Ok(device)
-}
-
-#[derive(Copy, Clone, Debug)]
-struct QueueFamilyIndices {
-    graphics: u32,
-    present: u32,
-}
-
-impl QueueFamilyIndices {
-    unsafe fn get (
-        instance: &Instance,
-        data: &AppData,
-        physical_device: vk::PhysicalDevice
-    ) -> Result<Self> {
-        let proparties = instance.get_physical_device_queue_family_properties(physical_device);
-        let graphics = proparties.iter()
-                                 .position (|p| p.queue_flags.contains(vk::QueueFlags::GRAPHICS))
-                                 .map(|i| i as u32);
-
-        // Look for Presentation support
-        let mut present = None;
-        for (index, properties) in proparties.iter().enumerate() {
-            if instance.get_physical_device_surface_support_khr(physical_device, index as u32, data.surface)? {
-                present = Some (index as u32);
-                break;
-            }
-        }
-
-        // Look for Graphics support
-        if let (Some(graphics), Some(present)) = (graphics, present) {
-            Ok (Self { graphics, present })
-        }
-        else {
-            Err(anyhow!(SuitabilityError("Missing required queue families")))
-        }
-
-    }
-}
-
-unsafe fn create_instance (window: &Window, entry: &Entry, data: &mut AppData) -> Result<Instance>{
+/// Creates the instance, with the surface extensions `window` needs if one is given
+unsafe fn create_instance (window: Option<&Window>, entry: &Entry, data: &mut AppData) -> Result<Instance>{
     let applicatoin_info = vk::ApplicationInfo::builder()
     .application_name (b"Vulkan Application in Rust\0")
     .application_version (vk::make_version(1, 0, 0))
@@ -177,7 +484,8 @@ unsafe fn create_instance (window: &Window, entry: &Entry, data: &mut AppData) -
     .engine_version (vk::make_version(1, 0, 0))
     .api_version (vk::make_version(1, 0, 0));
 
-    let mut extensions = vk_window::get_required_instance_extensions(window)
+    let mut extensions = window
+        .map_or(&[][..], |w| vk_window::get_required_instance_extensions(w))
         .iter ()
         .map (|e| e.as_ptr())
         .collect::<Vec<_>>();
@@ -252,33 +560,109 @@ extern "system" fn debug_callback (
 
 
 
+/// Creates only an instance and prints every physical device it exposes
+unsafe fn list_devices (args: &Args) -> Result<()> {
+    let mut data = AppData::default();
+    let loader = LibloadingLoader::new (LIBRARY)?;
+    let entry = Entry::new (loader).map_err(|b| anyhow!("{}", b))?;
+    let instance = create_instance(None, &entry, &mut data)?;
+    let result = diagnostics::list_devices(&instance, &data, args.format);
+    if VALIDATION_ENABLED {
+        instance.destroy_debug_utils_messenger_ext(data.messenger, None);
+    }
+    instance.destroy_instance(None);
+    result
+}
+
 fn main() -> Result<()> {
     pretty_env_logger::init();
+    let args = Args::from_env()?;
+    if args.list_devices {
+        return unsafe { list_devices(&args) };
+    }
+    if let Some(options) = args.golden_options() {
+        return golden::run(&args, &options);
+    }
+    if args.headless {
+        let mut app = unsafe { App::create (None, &args)? };
+        let result = (0..args.frame_limit().unwrap_or(1))
+            .try_for_each(|_| unsafe { app.render_offscreen() })
+            .and_then(|_| match &args.screenshot {
+                Some(path) => unsafe { app.capture() }?.save_png(path),
+                None => Ok(()),
+            });
+        unsafe { app.destroy(); }
+        return result;
+    }
 
     // Window
 
     let event_loop = EventLoop::new ();
     let window = WindowBuilder::new ()
         .with_title("Hey Vulkan")
-        .with_inner_size(LogicalSize::new(1024, 768))
+        .with_inner_size(LogicalSize::new(args.size.width, args.size.height))
         .build(&event_loop)?;
 
     // App
 
-    let mut app = unsafe { App::create (&window)? };
+    let mut app = unsafe { App::create (Some(&window), &args)? };
     let mut destroying = false;
+    let mut minimized = false;
+    let mut frames = 0;
     event_loop.run (move |event, _, control_flow| {
         *control_flow = ControlFlow::Poll;
         match event {
             // Render a frame if our Vulkan app is not being destrpyed
-            Event::MainEventsCleared if !destroying => unsafe {
-                app.render (&window)
-            }.unwrap(),
+            Event::MainEventsCleared if !destroying && !minimized => {
+                app.update(&window);
+                let last = args.frame_limit() == Some(frames + 1);
+                let capture = last && args.screenshot.is_some();
+                if capture && !app.capture_recorded() {
+                    unsafe { app.request_capture() }.unwrap();
+                }
+                unsafe { app.render (&window) }.unwrap();
+                // Retry if the swapchain was out of date and nothing was rendered
+                if capture && !app.capture_recorded() {
+                    return;
+                }
+                frames += 1;
+                if last {
+                    if let Some(path) = &args.screenshot {
+                        unsafe { app.capture() }.and_then(|f| f.save_png(path)).unwrap();
+                    }
+                    destroying = true;
+                    *control_flow = ControlFlow::Exit;
+                    unsafe { app.destroy(); }
+                }
+            }
 
+            // Pause rendering while the window has no area
+            Event::WindowEvent { event: WindowEvent::Resized(size), .. } => {
+                if size.width == 0 || size.height == 0 {
+                    minimized = true;
+                } else {
+                    minimized = false;
+                    app.resized = true;
+                }
+            }
             Event::WindowEvent { event: WindowEvent::CloseRequested, .. } => {
-                destroying = true;
                 *control_flow = ControlFlow::Exit;
-                unsafe { app.destroy(); }
+                if !destroying {
+                    destroying = true;
+                    unsafe { app.destroy(); }
+                }
+            }
+
+            // Collect input for the next update
+            Event::WindowEvent { event, .. } => {
+                if let Some(event) = InputEvent::from_window_event(&event) {
+                    app.input.handle(event);
+                }
+            }
+            Event::DeviceEvent { event, .. } => {
+                if let Some(event) = InputEvent::from_device_event(&event) {
+                    app.input.handle(event);
+                }
             }
             _ => {}
         }
@@ -0,0 +1,618 @@
+use std::collections::BTreeMap;
+use std::ptr::NonNull;
+
+use anyhow::{anyhow, Result};
+use log::*;
+
+use vulkanalia::prelude::v1_0::*;
+
+use crate::AppData;
+
+/// Size of the blocks allocations are pooled in, unless the heap is small.
+pub const DEFAULT_BLOCK_SIZE: vk::DeviceSize = 64 * 1024 * 1024;
+
+/// Rounds `offset` up to a multiple of `alignment` (a power of two, or 0/1).
+pub fn align_up(offset: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::DeviceSize {
+    if alignment <= 1 {
+        offset
+    } else {
+        offset.div_ceil(alignment) * alignment
+    }
+}
+
+/// Whether a resource is linear (buffers, linearly tiled images) or
+/// optimally tiled. Neighbors of different kinds must not share a
+/// `bufferImageGranularity` page.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ResourceKind {
+    #[default]
+    Linear,
+    Optimal,
+}
+
+/// The free list of one memory block: hands out aligned ranges of a fixed
+/// size and merges them back when they are freed. Pure bookkeeping, no
+/// Vulkan calls.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BlockAllocator {
+    size: vk::DeviceSize,
+    granularity: vk::DeviceSize,
+    /// Free ranges, start to end, never adjacent to each other.
+    free: BTreeMap<vk::DeviceSize, vk::DeviceSize>,
+    /// Allocations, offset to size and kind.
+    used: BTreeMap<vk::DeviceSize, (vk::DeviceSize, ResourceKind)>,
+}
+
+impl BlockAllocator {
+    pub fn new(size: vk::DeviceSize, granularity: vk::DeviceSize) -> Self {
+        Self { size, granularity: granularity.max(1), free: BTreeMap::from([(0, size)]), used: BTreeMap::new() }
+    }
+
+    /// Finds room for `size` bytes aligned to `alignment`, taking the
+    /// smallest free range that fits. Returns the offset, or `None` if the
+    /// block is too full or fragmented.
+    pub fn allocate(
+        &mut self,
+        size: vk::DeviceSize,
+        alignment: vk::DeviceSize,
+        kind: ResourceKind,
+    ) -> Option<vk::DeviceSize> {
+        let (start, end, offset) = self
+            .free
+            .iter()
+            .filter_map(|(start, end)| Some((*start, *end, self.fit(*start, *end, size, alignment, kind)?)))
+            .min_by_key(|(start, end, _)| end - start)?;
+
+        self.free.remove(&start);
+        if offset > start {
+            self.free.insert(start, offset);
+        }
+        if offset + size < end {
+            self.free.insert(offset + size, end);
+        }
+        self.used.insert(offset, (size, kind));
+        Some(offset)
+    }
+
+    /// Where an allocation would go in the free range `start..end`.
+    fn fit(
+        &self,
+        start: vk::DeviceSize,
+        end: vk::DeviceSize,
+        size: vk::DeviceSize,
+        alignment: vk::DeviceSize,
+        kind: ResourceKind,
+    ) -> Option<vk::DeviceSize> {
+        let mut offset = align_up(start, alignment);
+        if let Some((previous, (previous_size, previous_kind))) = self.used.range(..start).next_back() {
+            if *previous_kind != kind && self.same_page(previous + previous_size - 1, offset) {
+                offset = align_up(offset, self.granularity);
+            }
+        }
+        if offset.checked_add(size)? > end {
+            return None;
+        }
+        if let Some((next, (_, next_kind))) = self.used.range(end..).next() {
+            if *next_kind != kind && self.same_page(offset + size.max(1) - 1, *next) {
+                return None;
+            }
+        }
+        Some(offset)
+    }
+
+    fn same_page(&self, a: vk::DeviceSize, b: vk::DeviceSize) -> bool {
+        a / self.granularity == b / self.granularity
+    }
+
+    /// Releases the allocation at `offset`, returning its size.
+    pub fn free(&mut self, offset: vk::DeviceSize) -> Option<vk::DeviceSize> {
+        let (size, _) = self.used.remove(&offset)?;
+        let mut start = offset;
+        let mut end = offset + size;
+        if let Some((previous, previous_end)) = self.free.range(..start).next_back().map(|(s, e)| (*s, *e)) {
+            if previous_end == start {
+                self.free.remove(&previous);
+                start = previous;
+            }
+        }
+        if let Some(next_end) = self.free.remove(&end) {
+            end = next_end;
+        }
+        self.free.insert(start, end);
+        Some(size)
+    }
+
+    pub fn size(&self) -> vk::DeviceSize {
+        self.size
+    }
+
+    /// Bytes handed out, excluding alignment padding.
+    pub fn used(&self) -> vk::DeviceSize {
+        self.used.values().map(|(size, _)| size).sum()
+    }
+
+    pub fn allocation_count(&self) -> usize {
+        self.used.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.used.is_empty()
+    }
+
+    pub fn largest_free(&self) -> vk::DeviceSize {
+        self.free.iter().map(|(start, end)| end - start).max().unwrap_or(0)
+    }
+}
+
+/// Picks a memory type allowed by `type_bits` that has all of `required`,
+/// preferring one that also has all of `preferred`.
+pub fn find_memory_type(
+    memory: &vk::PhysicalDeviceMemoryProperties,
+    type_bits: u32,
+    required: vk::MemoryPropertyFlags,
+    preferred: vk::MemoryPropertyFlags,
+) -> Option<u32> {
+    let candidates = (0..memory.memory_type_count)
+        .filter(|i| type_bits & (1 << i) != 0)
+        .filter(|i| memory.memory_types[*i as usize].property_flags.contains(required))
+        .collect::<Vec<_>>();
+    candidates
+        .iter()
+        .find(|i| memory.memory_types[**i as usize].property_flags.contains(required | preferred))
+        .or_else(|| candidates.first())
+        .copied()
+}
+
+/// What memory a resource needs.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct AllocationRequest {
+    pub required: vk::MemoryPropertyFlags,
+    /// Flags used to choose between memory types that all have `required`.
+    pub preferred: vk::MemoryPropertyFlags,
+    pub kind: ResourceKind,
+    /// Gives the resource its own `vk::DeviceMemory` even if it is small.
+    pub dedicated: bool,
+}
+
+impl AllocationRequest {
+    /// Device local memory for GPU-only resources.
+    pub fn device_local(kind: ResourceKind) -> Self {
+        Self { required: vk::MemoryPropertyFlags::DEVICE_LOCAL, kind, ..Default::default() }
+    }
+
+    /// Host visible, coherent memory the CPU writes to or reads from.
+    pub fn host_visible() -> Self {
+        Self {
+            required: vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            ..Default::default()
+        }
+    }
+}
+
+/// A range of device memory handed out by the [`Allocator`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Allocation {
+    pub memory: vk::DeviceMemory,
+    pub offset: vk::DeviceSize,
+    pub size: vk::DeviceSize,
+    pub memory_type: u32,
+    /// Host pointer to `offset` if the memory is host visible; blocks stay
+    /// mapped for their whole lifetime.
+    pub mapped: Option<NonNull<u8>>,
+    dedicated: bool,
+}
+
+#[derive(Clone, Debug)]
+struct MemoryBlock {
+    memory: vk::DeviceMemory,
+    mapped: Option<NonNull<u8>>,
+    allocator: BlockAllocator,
+}
+
+/// Usage of one memory type, or of all of them.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    pub blocks: usize,
+    pub block_bytes: vk::DeviceSize,
+    pub allocations: usize,
+    pub used_bytes: vk::DeviceSize,
+    pub dedicated_allocations: usize,
+    pub dedicated_bytes: vk::DeviceSize,
+}
+
+impl MemoryStats {
+    /// Bytes of device memory allocated from Vulkan.
+    pub fn allocated_bytes(&self) -> vk::DeviceSize {
+        self.block_bytes + self.dedicated_bytes
+    }
+
+    fn add(&mut self, other: &Self) {
+        self.blocks += other.blocks;
+        self.block_bytes += other.block_bytes;
+        self.allocations += other.allocations;
+        self.used_bytes += other.used_bytes;
+        self.dedicated_allocations += other.dedicated_allocations;
+        self.dedicated_bytes += other.dedicated_bytes;
+    }
+}
+
+/// Sub-allocates buffers and images from large blocks of device memory, one
+/// pool of blocks per memory type. Resources larger than half a block get a
+/// dedicated allocation.
+#[derive(Clone, Debug, Default)]
+pub struct Allocator {
+    memory: vk::PhysicalDeviceMemoryProperties,
+    granularity: vk::DeviceSize,
+    blocks: Vec<Vec<MemoryBlock>>,
+    dedicated: Vec<(usize, vk::DeviceSize)>,
+}
+
+impl Allocator {
+    pub unsafe fn new(instance: &Instance, physical_device: vk::PhysicalDevice) -> Self {
+        let memory = instance.get_physical_device_memory_properties(physical_device);
+        let properties = instance.get_physical_device_properties(physical_device);
+        let count = memory.memory_type_count as usize;
+        Self {
+            memory,
+            granularity: properties.limits.buffer_image_granularity,
+            blocks: vec![vec![]; count],
+            dedicated: vec![(0, 0); count],
+        }
+    }
+
+    /// The block size of a memory type: [`DEFAULT_BLOCK_SIZE`], or an eighth
+    /// of the heap for heaps smaller than 1 GiB, but never more than the heap.
+    fn block_size(&self, memory_type: u32) -> vk::DeviceSize {
+        let heap = self.memory.memory_types[memory_type as usize].heap_index;
+        let heap_size = self.memory.memory_heaps[heap as usize].size;
+        if heap_size < 1024 * 1024 * 1024 {
+            align_up(heap_size / 8, 1024 * 1024).min(DEFAULT_BLOCK_SIZE).min(heap_size)
+        } else {
+            DEFAULT_BLOCK_SIZE
+        }
+    }
+
+    /// Whether a resource gets its own `vk::DeviceMemory` instead of a range
+    /// of a block: on request, or when it is larger than half a block.
+    fn is_dedicated(&self, memory_type: u32, size: vk::DeviceSize, request: AllocationRequest) -> bool {
+        request.dedicated || size > self.block_size(memory_type) / 2
+    }
+
+    pub unsafe fn allocate(
+        &mut self,
+        device: &Device,
+        requirements: vk::MemoryRequirements,
+        request: AllocationRequest,
+    ) -> Result<Allocation> {
+        let memory_type = find_memory_type(&self.memory, requirements.memory_type_bits, request.required, request.preferred)
+            .ok_or_else(|| anyhow!("Failed to find suitable memory type ({:?}).", request.required))?;
+        let block_size = self.block_size(memory_type);
+
+        if self.is_dedicated(memory_type, requirements.size, request) {
+            let (memory, mapped) = self.allocate_memory(device, memory_type, requirements.size)?;
+            let (count, bytes) = &mut self.dedicated[memory_type as usize];
+            *count += 1;
+            *bytes += requirements.size;
+            return Ok(Allocation {
+                memory,
+                offset: 0,
+                size: requirements.size,
+                memory_type,
+                mapped,
+                dedicated: true,
+            });
+        }
+
+        let blocks = &mut self.blocks[memory_type as usize];
+        let found = blocks.iter_mut().find_map(|b| {
+            let offset = b.allocator.allocate(requirements.size, requirements.alignment, request.kind)?;
+            Some((b.memory, b.mapped, offset))
+        });
+        let (memory, mapped, offset) = match found {
+            Some(found) => found,
+            None => {
+                let (memory, mapped) = self.allocate_memory(device, memory_type, block_size)?;
+                let mut allocator = BlockAllocator::new(block_size, self.granularity);
+                let offset = allocator
+                    .allocate(requirements.size, requirements.alignment, request.kind)
+                    .ok_or_else(|| anyhow!("Allocation of {} bytes does not fit a new block.", requirements.size))?;
+                self.blocks[memory_type as usize].push(MemoryBlock { memory, mapped, allocator });
+                (memory, mapped, offset)
+            }
+        };
+
+        Ok(Allocation {
+            memory,
+            offset,
+            size: requirements.size,
+            memory_type,
+            mapped: mapped.map(|p| NonNull::new_unchecked(p.as_ptr().add(offset as usize))),
+            dedicated: false,
+        })
+    }
+
+    /// Allocates device memory, mapping it if it is host visible.
+    unsafe fn allocate_memory(
+        &self,
+        device: &Device,
+        memory_type: u32,
+        size: vk::DeviceSize,
+    ) -> Result<(vk::DeviceMemory, Option<NonNull<u8>>)> {
+        let info = vk::MemoryAllocateInfo::builder().allocation_size(size).memory_type_index(memory_type);
+        let memory = device.allocate_memory(&info, None)?;
+        let flags = self.memory.memory_types[memory_type as usize].property_flags;
+        if !flags.contains(vk::MemoryPropertyFlags::HOST_VISIBLE) {
+            return Ok((memory, None));
+        }
+        match device.map_memory(memory, 0, vk::WHOLE_SIZE as u64, vk::MemoryMapFlags::empty()) {
+            Ok(pointer) => Ok((memory, NonNull::new(pointer.cast()))),
+            Err(e) => {
+                device.free_memory(memory, None);
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Returns `allocation` to its pool. Empty blocks are released, except the
+    /// last one of each memory type.
+    pub unsafe fn free(&mut self, device: &Device, allocation: &Allocation) {
+        if allocation.memory.is_null() {
+            return;
+        }
+
+        let memory_type = allocation.memory_type as usize;
+        if allocation.dedicated {
+            device.free_memory(allocation.memory, None);
+            let (count, bytes) = &mut self.dedicated[memory_type];
+            match count.checked_sub(1) {
+                Some(remaining) => {
+                    *count = remaining;
+                    *bytes = bytes.saturating_sub(allocation.size);
+                }
+                None => warn!("Freeing more dedicated allocations of memory type {} than were made.", memory_type),
+            }
+            return;
+        }
+
+        let blocks = &mut self.blocks[memory_type];
+        let Some(index) = blocks.iter().position(|b| b.memory == allocation.memory) else {
+            warn!("Freeing an allocation of unknown memory {:?}.", allocation.memory);
+            return;
+        };
+        blocks[index].allocator.free(allocation.offset);
+        if blocks[index].allocator.is_empty() && blocks.len() > 1 {
+            device.free_memory(blocks.remove(index).memory, None);
+        }
+    }
+
+    /// Usage of every memory type, indexed by memory type.
+    pub fn stats_by_type(&self) -> Vec<MemoryStats> {
+        self.blocks
+            .iter()
+            .zip(&self.dedicated)
+            .map(|(blocks, (dedicated_allocations, dedicated_bytes))| MemoryStats {
+                blocks: blocks.len(),
+                block_bytes: blocks.iter().map(|b| b.allocator.size()).sum(),
+                allocations: blocks.iter().map(|b| b.allocator.allocation_count()).sum::<usize>() + dedicated_allocations,
+                used_bytes: blocks.iter().map(|b| b.allocator.used()).sum::<vk::DeviceSize>() + dedicated_bytes,
+                dedicated_allocations: *dedicated_allocations,
+                dedicated_bytes: *dedicated_bytes,
+            })
+            .collect()
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        let mut total = MemoryStats::default();
+        self.stats_by_type().iter().for_each(|s| total.add(s));
+        total
+    }
+
+    /// Releases every block. Allocations still alive at this point leaked.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        let stats = self.stats();
+        if stats.allocations > 0 {
+            warn!("Destroying the allocator with {} live allocation(s) ({} bytes).", stats.allocations, stats.used_bytes);
+        }
+        for blocks in &mut self.blocks {
+            blocks.drain(..).for_each(|b| device.free_memory(b.memory, None));
+        }
+    }
+}
+
+/// Creates a buffer backed by memory from `data.allocator`.
+pub unsafe fn create_buffer(
+    device: &Device,
+    data: &mut AppData,
+    size: vk::DeviceSize,
+    usage: vk::BufferUsageFlags,
+    request: AllocationRequest,
+) -> Result<(vk::Buffer, Allocation)> {
+    let info = vk::BufferCreateInfo::builder()
+        .size(size)
+        .usage(usage)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE);
+    let buffer = device.create_buffer(&info, None)?;
+
+    let requirements = device.get_buffer_memory_requirements(buffer);
+    let request = AllocationRequest { kind: ResourceKind::Linear, ..request };
+    let allocation = match data.allocator.allocate(device, requirements, request) {
+        Ok(allocation) => allocation,
+        Err(e) => {
+            device.destroy_buffer(buffer, None);
+            return Err(e);
+        }
+    };
+    if let Err(e) = device.bind_buffer_memory(buffer, allocation.memory, allocation.offset) {
+        destroy_buffer(device, data, buffer, &allocation);
+        return Err(e.into());
+    }
+
+    Ok((buffer, allocation))
+}
+
+pub unsafe fn destroy_buffer(device: &Device, data: &mut AppData, buffer: vk::Buffer, allocation: &Allocation) {
+    device.destroy_buffer(buffer, None);
+    data.allocator.free(device, allocation);
+}
+
+/// Allocates memory for `image` from `data.allocator` and binds it.
+pub unsafe fn allocate_image_memory(
+    device: &Device,
+    data: &mut AppData,
+    image: vk::Image,
+    tiling: vk::ImageTiling,
+    request: AllocationRequest,
+) -> Result<Allocation> {
+    let requirements = device.get_image_memory_requirements(image);
+    let kind = if tiling == vk::ImageTiling::OPTIMAL { ResourceKind::Optimal } else { ResourceKind::Linear };
+    let allocation = data.allocator.allocate(device, requirements, AllocationRequest { kind, ..request })?;
+    if let Err(e) = device.bind_image_memory(image, allocation.memory, allocation.offset) {
+        data.allocator.free(device, &allocation);
+        return Err(e.into());
+    }
+    Ok(allocation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: vk::DeviceSize = 1024 * 1024;
+
+    #[test]
+    fn align_up_rounds_to_the_alignment() {
+        assert_eq!(align_up(0, 256), 0);
+        assert_eq!(align_up(1, 256), 256);
+        assert_eq!(align_up(256, 256), 256);
+        assert_eq!(align_up(257, 256), 512);
+        assert_eq!(align_up(13, 0), 13);
+        assert_eq!(align_up(13, 1), 13);
+    }
+
+    #[test]
+    fn allocations_are_aligned() {
+        let mut block = BlockAllocator::new(1024, 1);
+        assert_eq!(block.allocate(10, 1, ResourceKind::Linear), Some(0));
+        assert_eq!(block.allocate(10, 64, ResourceKind::Linear), Some(64));
+        // The smallest free range that fits is the gap left by the padding.
+        assert_eq!(block.allocate(10, 16, ResourceKind::Linear), Some(16));
+        assert_eq!(block.used(), 30);
+        assert_eq!(block.allocation_count(), 3);
+    }
+
+    #[test]
+    fn different_kinds_do_not_share_a_page() {
+        let mut block = BlockAllocator::new(2048, 256);
+        assert_eq!(block.allocate(100, 4, ResourceKind::Linear), Some(0));
+        assert_eq!(block.allocate(100, 4, ResourceKind::Optimal), Some(256));
+        assert_eq!(block.allocate(16, 4, ResourceKind::Optimal), Some(356));
+        // Linear resources may fill the rest of the first page, but not the
+        // page the optimal ones start on.
+        assert_eq!(block.allocate(16, 4, ResourceKind::Linear), Some(100));
+        assert_eq!(block.allocate(150, 4, ResourceKind::Linear), Some(512));
+    }
+
+    #[test]
+    fn linear_before_optimal_ends_a_page_early() {
+        let mut block = BlockAllocator::new(2048, 512);
+        assert_eq!(block.allocate(600, 4, ResourceKind::Optimal), Some(0));
+        assert_eq!(block.allocate(100, 4, ResourceKind::Optimal), Some(600));
+        assert_eq!(block.free(0), Some(600));
+        assert_eq!(block.allocate(100, 4, ResourceKind::Linear), Some(0));
+        // Ending at 549 would share the page of the optimal resource at 600.
+        assert_eq!(block.allocate(450, 4, ResourceKind::Linear), Some(1024));
+    }
+
+    #[test]
+    fn same_kinds_share_pages() {
+        let mut block = BlockAllocator::new(1024, 256);
+        assert_eq!(block.allocate(100, 4, ResourceKind::Optimal), Some(0));
+        assert_eq!(block.allocate(100, 4, ResourceKind::Optimal), Some(100));
+    }
+
+    #[test]
+    fn free_ranges_are_merged() {
+        let mut block = BlockAllocator::new(1024, 1);
+        let offsets = (0..3).map(|_| block.allocate(256, 1, ResourceKind::Linear).unwrap()).collect::<Vec<_>>();
+        assert_eq!(offsets, [0, 256, 512]);
+        assert_eq!(block.largest_free(), 256);
+
+        assert_eq!(block.free(256), Some(256));
+        assert_eq!(block.largest_free(), 256);
+        assert_eq!(block.free(0), Some(256));
+        assert_eq!(block.largest_free(), 512);
+        assert_eq!(block.free(512), Some(256));
+        assert_eq!(block.largest_free(), 1024);
+        assert!(block.is_empty());
+        assert_eq!(block, BlockAllocator::new(1024, 1));
+    }
+
+    #[test]
+    fn unknown_offsets_are_not_freed() {
+        let mut block = BlockAllocator::new(1024, 1);
+        assert_eq!(block.allocate(256, 1, ResourceKind::Linear), Some(0));
+        assert_eq!(block.free(128), None);
+        assert_eq!(block.free(0), Some(256));
+        assert_eq!(block.free(0), None);
+    }
+
+    #[test]
+    fn full_block() {
+        let mut block = BlockAllocator::new(1024, 1);
+        assert_eq!(block.allocate(1025, 1, ResourceKind::Linear), None);
+        assert_eq!(block.allocate(1024, 1, ResourceKind::Linear), Some(0));
+        assert_eq!(block.allocate(1, 1, ResourceKind::Linear), None);
+        assert_eq!(block.largest_free(), 0);
+        assert_eq!(block.free(0), Some(1024));
+        assert_eq!(block.allocate(1024, 1, ResourceKind::Linear), Some(0));
+    }
+
+    #[test]
+    fn padding_can_exhaust_a_block() {
+        let mut block = BlockAllocator::new(1024, 1);
+        assert_eq!(block.allocate(1, 1, ResourceKind::Linear), Some(0));
+        assert_eq!(block.allocate(768, 256, ResourceKind::Linear), Some(256));
+        assert_eq!(block.allocate(1, 256, ResourceKind::Linear), None);
+        assert_eq!(block.allocate(1, 1, ResourceKind::Linear), Some(1));
+    }
+
+    fn allocator(heap_sizes: &[vk::DeviceSize]) -> Allocator {
+        let mut memory = vk::PhysicalDeviceMemoryProperties {
+            memory_type_count: heap_sizes.len() as u32,
+            memory_heap_count: heap_sizes.len() as u32,
+            ..Default::default()
+        };
+        for (i, size) in heap_sizes.iter().enumerate() {
+            let property_flags = vk::MemoryPropertyFlags::DEVICE_LOCAL;
+            memory.memory_types[i] = vk::MemoryType { property_flags, heap_index: i as u32 };
+            memory.memory_heaps[i] = vk::MemoryHeap { size: *size, flags: vk::MemoryHeapFlags::DEVICE_LOCAL };
+        }
+        Allocator {
+            memory,
+            granularity: 1,
+            blocks: vec![vec![]; heap_sizes.len()],
+            dedicated: vec![(0, 0); heap_sizes.len()],
+        }
+    }
+
+    #[test]
+    fn small_heaps_get_smaller_blocks() {
+        let allocator = allocator(&[4096 * MIB, 256 * MIB, 4 * MIB, 1000]);
+        assert_eq!(allocator.block_size(0), DEFAULT_BLOCK_SIZE);
+        assert_eq!(allocator.block_size(1), 32 * MIB);
+        assert_eq!(allocator.block_size(2), MIB);
+        // Blocks never exceed their heap
+        assert_eq!(allocator.block_size(3), 1000);
+    }
+
+    #[test]
+    fn dedicated_threshold() {
+        let allocator = allocator(&[4096 * MIB, 256 * MIB]);
+        let request = AllocationRequest::device_local(ResourceKind::Optimal);
+        assert!(!allocator.is_dedicated(0, 32 * MIB, request));
+        assert!(allocator.is_dedicated(0, 32 * MIB + 1, request));
+        assert!(!allocator.is_dedicated(1, 16 * MIB, request));
+        assert!(allocator.is_dedicated(1, 16 * MIB + 1, request));
+        assert!(allocator.is_dedicated(0, 1, AllocationRequest { dedicated: true, ..request }));
+    }
+}
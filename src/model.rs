@@ -0,0 +1,472 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader};
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use log::*;
+use nalgebra_glm as glm;
+
+use vulkanalia::prelude::v1_0::*;
+
+use crate::memory::{destroy_buffer, Allocation};
+use crate::upload::{create_device_local_buffer, wait, UploadTicket};
+use crate::AppData;
+
+/// The interleaved vertex format of loaded models.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct Vertex {
+    pub position: glm::Vec3,
+    pub normal: glm::Vec3,
+    pub uv: glm::Vec2,
+    pub color: glm::Vec3,
+}
+
+impl Vertex {
+    pub fn binding_description() -> vk::VertexInputBindingDescription {
+        vk::VertexInputBindingDescription::builder()
+            .binding(0)
+            .stride(size_of::<Vertex>() as u32)
+            .input_rate(vk::VertexInputRate::VERTEX)
+            .build()
+    }
+
+    pub fn attribute_descriptions() -> [vk::VertexInputAttributeDescription; 4] {
+        let attribute = |location, format, offset| {
+            vk::VertexInputAttributeDescription::builder()
+                .binding(0)
+                .location(location)
+                .format(format)
+                .offset(offset as u32)
+                .build()
+        };
+        let vec3 = size_of::<glm::Vec3>();
+        [
+            attribute(0, vk::Format::R32G32B32_SFLOAT, 0),
+            attribute(1, vk::Format::R32G32B32_SFLOAT, vec3),
+            attribute(2, vk::Format::R32G32_SFLOAT, 2 * vec3),
+            attribute(3, vk::Format::R32G32B32_SFLOAT, 2 * vec3 + size_of::<glm::Vec2>()),
+        ]
+    }
+
+    /// The bit patterns of every component, so identical vertices compare
+    /// and hash equal.
+    fn bits(&self) -> [u32; 11] {
+        let mut bits = [0; 11];
+        let components = self.position.iter().chain(&self.normal).chain(&self.uv).chain(&self.color);
+        bits.iter_mut().zip(components).for_each(|(b, c)| *b = c.to_bits());
+        bits
+    }
+}
+
+impl PartialEq for Vertex {
+    fn eq(&self, other: &Self) -> bool {
+        self.bits() == other.bits()
+    }
+}
+
+impl Eq for Vertex {}
+
+impl Hash for Vertex {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.bits().hash(state);
+    }
+}
+
+/// How normals are generated for meshes that have none.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum NormalMode {
+    /// Every triangle uses its face normal.
+    Flat,
+    /// Each position averages the normals of the faces around it, weighted
+    /// by their area.
+    #[default]
+    Smooth,
+}
+
+impl std::str::FromStr for NormalMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "flat" => Ok(Self::Flat),
+            "smooth" => Ok(Self::Smooth),
+            _ => Err(anyhow!("Unknown normal mode `{}` (expected `flat` or `smooth`).", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Material {
+    pub name: String,
+    pub diffuse: glm::Vec3,
+    /// Relative to the `.mtl` file, as written in it.
+    pub diffuse_texture: Option<PathBuf>,
+}
+
+/// A mesh with deduplicated vertices.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MeshData {
+    pub name: String,
+    pub vertices: Vec<Vertex>,
+    pub indices: Vec<u32>,
+    /// Index into [`ModelData::materials`].
+    pub material: Option<usize>,
+    /// Whether the normals were generated rather than read from the file.
+    pub generated_normals: bool,
+}
+
+/// A model as loaded from an `.obj` file, before it is uploaded.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ModelData {
+    pub meshes: Vec<MeshData>,
+    pub materials: Vec<Material>,
+}
+
+impl ModelData {
+    /// The center and radius of a sphere around every vertex, if there are any.
+    pub fn bounding_sphere(&self) -> Option<(glm::Vec3, f32)> {
+        let mut positions = self.meshes.iter().flat_map(|m| &m.vertices).map(|v| v.position);
+        let first = positions.next()?;
+        let (min, max) = positions.fold((first, first), |(min, max), p| (glm::min2(&min, &p), glm::max2(&max, &p)));
+        let center = (min + max) / 2.0;
+        Some((center, glm::distance(&center, &max)))
+    }
+
+    /// The name of every mesh with the name of its material, if any.
+    pub fn material_assignments(&self) -> Vec<(&str, Option<&str>)> {
+        self.meshes
+            .iter()
+            .map(|m| (m.name.as_str(), m.material.and_then(|i| self.materials.get(i)).map(|m| m.name.as_str())))
+            .collect()
+    }
+}
+
+fn load_options() -> tobj::LoadOptions {
+    tobj::LoadOptions { triangulate: true, single_index: false, ..Default::default() }
+}
+
+/// Loads an `.obj` file and the `.mtl` files it references.
+pub fn load_obj(path: &Path, normals: NormalMode) -> Result<ModelData> {
+    let file = File::open(path).with_context(|| format!("Failed to open `{}`.", path.display()))?;
+    let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+    load_obj_buf(&mut BufReader::new(file), |p| tobj::load_mtl(dir.join(p)), normals)
+        .with_context(|| format!("Failed to load `{}`.", path.display()))
+}
+
+/// Loads an `.obj` from `reader`, resolving `mtllib` statements with `load_mtl`.
+pub fn load_obj_buf<R, F>(reader: &mut R, load_mtl: F, normals: NormalMode) -> Result<ModelData>
+where
+    R: BufRead,
+    F: Fn(&Path) -> tobj::MTLLoadResult,
+{
+    let (models, materials) = tobj::load_obj_buf(reader, &load_options(), load_mtl)?;
+    let materials = match materials {
+        Ok(materials) => materials,
+        Err(e) => {
+            warn!("Failed to load materials: {}.", e);
+            vec![]
+        }
+    };
+    let materials = materials
+        .into_iter()
+        .map(|m| Material {
+            name: m.name,
+            diffuse: glm::make_vec3(&m.diffuse),
+            diffuse_texture: Some(m.diffuse_texture).filter(|t| !t.is_empty()).map(PathBuf::from),
+        })
+        .collect::<Vec<_>>();
+
+    let meshes = models
+        .iter()
+        .map(|m| convert_mesh(&m.name, &m.mesh, &materials, normals))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(ModelData { meshes, materials })
+}
+
+fn convert_mesh(name: &str, mesh: &tobj::Mesh, materials: &[Material], normals: NormalMode) -> Result<MeshData> {
+    let vec3 = |values: &[f32], i: u32| glm::vec3(values[3 * i as usize], values[3 * i as usize + 1], values[3 * i as usize + 2]);
+    let position_count = (mesh.positions.len() / 3) as u32;
+    if let Some(i) = mesh.indices.iter().find(|i| **i >= position_count) {
+        return Err(anyhow!("Mesh `{}` references missing position {}.", name, i));
+    }
+
+    let has_normals = !mesh.normals.is_empty() && mesh.normal_indices.len() == mesh.indices.len();
+    let has_uvs = !mesh.texcoords.is_empty() && mesh.texcoord_indices.len() == mesh.indices.len();
+    let positions = (0..position_count).map(|i| vec3(&mesh.positions, i)).collect::<Vec<_>>();
+    let generated = if has_normals { vec![] } else { generate_normals(&positions, &mesh.indices, normals) };
+
+    let material = mesh.material_id.filter(|i| *i < materials.len());
+    let fallback_color = material.map_or(glm::vec3(1.0, 1.0, 1.0), |i| materials[i].diffuse);
+
+    let mut unique = HashMap::new();
+    let mut vertices = vec![];
+    let mut indices = Vec::with_capacity(mesh.indices.len());
+    for (corner, position) in mesh.indices.iter().copied().enumerate() {
+        let normal = if has_normals {
+            vec3(&mesh.normals, mesh.normal_indices[corner])
+        } else {
+            generated[corner]
+        };
+        let uv = if has_uvs {
+            let i = mesh.texcoord_indices[corner] as usize;
+            // OBJ puts v = 0 at the bottom, Vulkan at the top.
+            glm::vec2(mesh.texcoords[2 * i], 1.0 - mesh.texcoords[2 * i + 1])
+        } else {
+            glm::vec2(0.0, 0.0)
+        };
+        let color = if mesh.vertex_color.is_empty() {
+            fallback_color
+        } else {
+            vec3(&mesh.vertex_color, position)
+        };
+
+        let vertex = Vertex { position: positions[position as usize], normal, uv, color };
+        let index = *unique.entry(vertex).or_insert_with(|| {
+            vertices.push(vertex);
+            vertices.len() as u32 - 1
+        });
+        indices.push(index);
+    }
+
+    Ok(MeshData { name: name.into(), vertices, indices, material, generated_normals: !has_normals })
+}
+
+/// Normals for every corner of the triangles in `indices`.
+pub fn generate_normals(positions: &[glm::Vec3], indices: &[u32], mode: NormalMode) -> Vec<glm::Vec3> {
+    // Unnormalized, so its length is twice the triangle's area.
+    let face_normals = indices
+        .chunks_exact(3)
+        .map(|t| {
+            let (a, b, c) = (positions[t[0] as usize], positions[t[1] as usize], positions[t[2] as usize]);
+            (b - a).cross(&(c - a))
+        })
+        .collect::<Vec<_>>();
+
+    let normalize = |n: glm::Vec3| {
+        if n.norm() > f32::EPSILON {
+            n.normalize()
+        } else {
+            glm::vec3(0.0, 0.0, 1.0)
+        }
+    };
+
+    match mode {
+        NormalMode::Flat => face_normals.iter().flat_map(|n| [normalize(*n); 3]).collect(),
+        NormalMode::Smooth => {
+            let mut sums = vec![glm::Vec3::zeros(); positions.len()];
+            for (t, n) in indices.chunks_exact(3).zip(&face_normals) {
+                t.iter().for_each(|i| sums[*i as usize] += n);
+            }
+            indices.iter().map(|i| normalize(sums[*i as usize])).collect()
+        }
+    }
+}
+
+/// A part of a [`Model`] drawn with one material.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MeshRange {
+    pub name: String,
+    pub first_index: u32,
+    pub index_count: u32,
+    pub vertex_offset: i32,
+    pub material: Option<usize>,
+}
+
+/// A model whose meshes share one vertex and one index buffer.
+#[derive(Clone, Debug, Default)]
+pub struct Model {
+    pub vertex_buffer: vk::Buffer,
+    pub vertex_allocation: Allocation,
+    pub index_buffer: vk::Buffer,
+    pub index_allocation: Allocation,
+    pub meshes: Vec<MeshRange>,
+    pub materials: Vec<Material>,
+    /// Completes once both buffers hold the model.
+    pub ticket: UploadTicket,
+}
+
+/// Uploads the meshes of `model` into shared device local buffers.
+pub unsafe fn create_model(device: &Device, data: &mut AppData, model: &ModelData) -> Result<Model> {
+    let mut vertices = vec![];
+    let mut indices = vec![];
+    let mut meshes = vec![];
+    for mesh in &model.meshes {
+        meshes.push(MeshRange {
+            name: mesh.name.clone(),
+            first_index: indices.len() as u32,
+            index_count: mesh.indices.len() as u32,
+            vertex_offset: vertices.len() as i32,
+            material: mesh.material,
+        });
+        vertices.extend_from_slice(&mesh.vertices);
+        indices.extend_from_slice(&mesh.indices);
+    }
+    if indices.is_empty() {
+        return Err(anyhow!("Model has no triangles."));
+    }
+
+    let vertex_bytes = std::slice::from_raw_parts(vertices.as_ptr().cast::<u8>(), vertices.len() * size_of::<Vertex>());
+    let (vertex_buffer, vertex_allocation, vertex_ticket) =
+        create_device_local_buffer(device, data, vertex_bytes, vk::BufferUsageFlags::VERTEX_BUFFER)?;
+    let index_bytes = std::slice::from_raw_parts(indices.as_ptr().cast::<u8>(), indices.len() * size_of::<u32>());
+    let (index_buffer, index_allocation, ticket) =
+        match create_device_local_buffer(device, data, index_bytes, vk::BufferUsageFlags::INDEX_BUFFER) {
+            Ok(index) => index,
+            Err(e) => {
+                // The vertex upload may still be writing to the buffer.
+                let _ = wait(device, data, vertex_ticket);
+                destroy_buffer(device, data, vertex_buffer, &vertex_allocation);
+                return Err(e);
+            }
+        };
+
+    Ok(Model {
+        vertex_buffer,
+        vertex_allocation,
+        index_buffer,
+        index_allocation,
+        meshes,
+        materials: model.materials.clone(),
+        ticket,
+    })
+}
+
+pub unsafe fn destroy_model(device: &Device, data: &mut AppData, model: &Model) {
+    destroy_buffer(device, data, model.vertex_buffer, &model.vertex_allocation);
+    destroy_buffer(device, data, model.index_buffer, &model.index_allocation);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shapes(normals: NormalMode) -> ModelData {
+        load_obj(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/models/shapes.obj"), normals).unwrap()
+    }
+
+    fn vertex(position: glm::Vec3) -> Vertex {
+        Vertex { position, normal: glm::vec3(0.0, 0.0, 1.0), uv: glm::vec2(0.0, 0.0), color: glm::vec3(1.0, 1.0, 1.0) }
+    }
+
+    fn assert_close(a: glm::Vec3, b: glm::Vec3) {
+        assert!(glm::distance(&a, &b) < 1e-6, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn vertices_compare_by_bits() {
+        let a = vertex(glm::vec3(0.0, 1.0, 2.0));
+        assert_eq!(a, vertex(glm::vec3(0.0, 1.0, 2.0)));
+        assert_ne!(a, vertex(glm::vec3(-0.0, 1.0, 2.0)));
+        // Unlike `f32`, equality stays reflexive, as `Eq` and `Hash` require.
+        let nan = vertex(glm::vec3(f32::NAN, 1.0, 2.0));
+        assert_eq!(nan, nan);
+    }
+
+    #[test]
+    fn quad_shares_its_diagonal() {
+        let model = shapes(NormalMode::Smooth);
+        let quad = &model.meshes[1];
+        assert_eq!(quad.name, "quad");
+        assert_eq!(quad.indices.len(), 6);
+        assert_eq!(quad.vertices.len(), 4);
+        assert!(quad.indices.iter().all(|i| (*i as usize) < quad.vertices.len()));
+        assert!(!quad.generated_normals);
+    }
+
+    #[test]
+    fn quad_attributes() {
+        let model = shapes(NormalMode::Smooth);
+        let quad = &model.meshes[1];
+        let first = quad.vertices[quad.indices[0] as usize];
+        assert_eq!(first.position, glm::vec3(0.0, 0.0, 0.0));
+        assert_eq!(first.normal, glm::vec3(0.0, 0.0, 1.0));
+        // Flipped so that v = 0 is the top of the texture.
+        assert_eq!(first.uv, glm::vec2(0.0, 1.0));
+        // Without vertex colors, the diffuse color of the material is used.
+        assert_eq!(first.color, glm::vec3(1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn materials_are_assigned() {
+        let model = shapes(NormalMode::Smooth);
+        assert_eq!(model.materials.len(), 1);
+        assert_eq!(model.materials[0].diffuse_texture, None);
+        assert_eq!(model.material_assignments(), [("wedge", None), ("quad", Some("red"))]);
+    }
+
+    #[test]
+    fn smooth_normals_merge_shared_positions() {
+        let model = shapes(NormalMode::Smooth);
+        let wedge = &model.meshes[0];
+        assert!(wedge.generated_normals);
+        assert_eq!(wedge.indices.len(), 6);
+        assert_eq!(wedge.vertices.len(), 4);
+
+        let normal = |position: glm::Vec3| wedge.vertices.iter().find(|v| v.position == position).unwrap().normal;
+        let shared = glm::vec3(0.0, 1.0, 1.0).normalize();
+        assert_close(normal(glm::vec3(0.0, 0.0, 0.0)), shared);
+        assert_close(normal(glm::vec3(1.0, 0.0, 0.0)), shared);
+        assert_close(normal(glm::vec3(0.0, 1.0, 0.0)), glm::vec3(0.0, 0.0, 1.0));
+        assert_close(normal(glm::vec3(0.0, 0.0, 1.0)), glm::vec3(0.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn flat_normals_split_shared_positions() {
+        let model = shapes(NormalMode::Flat);
+        let wedge = &model.meshes[0];
+        assert_eq!(wedge.indices.len(), 6);
+        assert_eq!(wedge.vertices.len(), 6);
+        let normals = wedge.indices.iter().map(|i| wedge.vertices[*i as usize].normal).collect::<Vec<_>>();
+        assert!(normals[..3].iter().all(|n| *n == glm::vec3(0.0, 0.0, 1.0)));
+        assert!(normals[3..].iter().all(|n| *n == glm::vec3(0.0, 1.0, 0.0)));
+    }
+
+    #[test]
+    fn smooth_normals_are_area_weighted() {
+        let positions = [
+            glm::vec3(0.0, 0.0, 0.0),
+            glm::vec3(0.0, 1.0, 0.0),
+            glm::vec3(3.0, 0.0, 0.0),
+            glm::vec3(0.0, 0.0, 1.0),
+        ];
+        // A triangle three times larger facing -z, and one facing +x.
+        let normals = generate_normals(&positions, &[0, 1, 2, 0, 1, 3], NormalMode::Smooth);
+        assert_close(normals[0], glm::vec3(1.0, 0.0, -3.0).normalize());
+        assert_close(normals[2], glm::vec3(0.0, 0.0, -1.0));
+        assert_close(normals[5], glm::vec3(1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn degenerate_triangles_face_forward() {
+        let positions = [glm::vec3(0.0, 0.0, 0.0), glm::vec3(1.0, 1.0, 1.0), glm::vec3(2.0, 2.0, 2.0)];
+        for mode in [NormalMode::Flat, NormalMode::Smooth] {
+            assert_eq!(generate_normals(&positions, &[0, 1, 2], mode), [glm::vec3(0.0, 0.0, 1.0); 3]);
+        }
+    }
+
+    #[test]
+    fn missing_positions_are_rejected() {
+        let obj = "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\nf 1 2 4\n";
+        let load_mtl = |_: &Path| Err(tobj::LoadError::OpenFileFailed);
+        assert!(load_obj_buf(&mut obj.as_bytes(), load_mtl, NormalMode::Smooth).is_err());
+    }
+
+    #[test]
+    fn bounding_sphere() {
+        assert_eq!(ModelData::default().bounding_sphere(), None);
+        let (center, radius) = shapes(NormalMode::Smooth).bounding_sphere().unwrap();
+        assert_close(center, glm::vec3(0.5, 0.5, 0.5));
+        assert!((radius - 0.75f32.sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn parse_normal_mode() {
+        assert_eq!("flat".parse::<NormalMode>().unwrap(), NormalMode::Flat);
+        assert_eq!("smooth".parse::<NormalMode>().unwrap(), NormalMode::Smooth);
+        assert!("sharp".parse::<NormalMode>().is_err());
+    }
+}
@@ -0,0 +1,176 @@
+use anyhow::{anyhow, Result};
+use log::*;
+
+use vulkanalia::prelude::v1_0::*;
+
+use crate::memory::{allocate_image_memory, AllocationRequest, ResourceKind};
+use crate::AppData;
+
+/// Sample counts from most to fewest.
+const SAMPLE_COUNTS: &[vk::SampleCountFlags] = &[
+    vk::SampleCountFlags::_64,
+    vk::SampleCountFlags::_32,
+    vk::SampleCountFlags::_16,
+    vk::SampleCountFlags::_8,
+    vk::SampleCountFlags::_4,
+    vk::SampleCountFlags::_2,
+];
+
+/// Parses a sample count of `--msaa`, a power of two from 1 to 64.
+pub fn parse_sample_count(s: &str) -> Result<u32> {
+    s.parse::<u32>()
+        .ok()
+        .filter(|n| n.is_power_of_two() && *n <= 64)
+        .ok_or_else(|| anyhow!("Invalid sample count `{}` (expected 1, 2, 4, 8, 16, 32 or 64).", s))
+}
+
+/// The largest of `supported` not above `requested`, or a single sample.
+pub fn clamp_sample_count(requested: u32, supported: vk::SampleCountFlags) -> vk::SampleCountFlags {
+    SAMPLE_COUNTS
+        .iter()
+        .copied()
+        .find(|c| c.bits() <= requested && supported.contains(*c))
+        .unwrap_or(vk::SampleCountFlags::_1)
+}
+
+/// Chooses `data.msaa_samples` for `requested` samples from the counts both
+/// color and depth attachments of the selected device support.
+pub fn choose_msaa_samples(data: &mut AppData, requested: u32) {
+    let limits = &data.physical_device_properties.limits;
+    let supported = limits.framebuffer_color_sample_counts & limits.framebuffer_depth_sample_counts;
+    data.msaa_samples = clamp_sample_count(requested, supported);
+    if data.msaa_samples.bits() < requested {
+        warn!("{} samples requested, using {} (supported: {:?}).", requested, data.msaa_samples.bits(), supported);
+    }
+}
+
+/// Enables sample shading with `requested` as the minimum fraction of samples
+/// shaded individually, if the device feature was enabled.
+pub fn choose_sample_shading(data: &mut AppData, requested: Option<f32>) {
+    data.min_sample_shading = requested.filter(|_| {
+        let enabled = data.enabled_features.sample_rate_shading == vk::TRUE;
+        if !enabled {
+            warn!("Sample shading requested but not supported by the device.");
+        }
+        enabled
+    });
+}
+
+/// Memory for attachments that only live during a render pass, lazily
+/// allocated where the device supports it.
+pub fn transient_attachment_request() -> AllocationRequest {
+    AllocationRequest {
+        preferred: vk::MemoryPropertyFlags::LAZILY_ALLOCATED,
+        ..AllocationRequest::device_local(ResourceKind::Optimal)
+    }
+}
+
+/// Creates the multisampled color attachment that is resolved into the render
+/// target, if `data.msaa_samples` is more than one.
+pub unsafe fn create_color_objects(device: &Device, data: &mut AppData) -> Result<()> {
+    if data.msaa_samples == vk::SampleCountFlags::_1 {
+        return Ok(());
+    }
+
+    let info = vk::ImageCreateInfo::builder()
+        .image_type(vk::ImageType::_2D)
+        .extent(vk::Extent3D { width: data.target_extent.width, height: data.target_extent.height, depth: 1 })
+        .mip_levels(1)
+        .array_layers(1)
+        .format(data.target_format)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .samples(data.msaa_samples);
+    data.color_image = device.create_image(&info, None)?;
+    let request = transient_attachment_request();
+    data.color_image_allocation = allocate_image_memory(device, data, data.color_image, vk::ImageTiling::OPTIMAL, request)?;
+
+    let subresource_range = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(1)
+        .base_array_layer(0)
+        .layer_count(1);
+    let info = vk::ImageViewCreateInfo::builder()
+        .image(data.color_image)
+        .view_type(vk::ImageViewType::_2D)
+        .format(data.target_format)
+        .subresource_range(subresource_range);
+    data.color_image_view = device.create_image_view(&info, None)?;
+    Ok(())
+}
+
+pub unsafe fn destroy_color_objects(device: &Device, data: &mut AppData) {
+    if data.color_image.is_null() {
+        return;
+    }
+    device.destroy_image_view(data.color_image_view, None);
+    device.destroy_image(data.color_image, None);
+    data.allocator.free(device, &data.color_image_allocation);
+    data.color_image_view = vk::ImageView::null();
+    data.color_image = vk::Image::null();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sample counts 1 to 8, as most desktop devices support.
+    fn up_to_8() -> vk::SampleCountFlags {
+        vk::SampleCountFlags::_1 | vk::SampleCountFlags::_2 | vk::SampleCountFlags::_4 | vk::SampleCountFlags::_8
+    }
+
+    #[test]
+    fn parse_powers_of_two() {
+        for n in [1, 2, 4, 8, 16, 32, 64] {
+            assert_eq!(parse_sample_count(&n.to_string()).unwrap(), n);
+        }
+    }
+
+    #[test]
+    fn parse_invalid_counts() {
+        for s in ["0", "3", "6", "128", "-4", "four", ""] {
+            assert!(parse_sample_count(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn supported_counts_are_kept() {
+        assert_eq!(clamp_sample_count(4, up_to_8()), vk::SampleCountFlags::_4);
+        assert_eq!(clamp_sample_count(8, up_to_8()), vk::SampleCountFlags::_8);
+        assert_eq!(clamp_sample_count(1, up_to_8()), vk::SampleCountFlags::_1);
+    }
+
+    #[test]
+    fn unsupported_counts_are_lowered() {
+        assert_eq!(clamp_sample_count(64, up_to_8()), vk::SampleCountFlags::_8);
+        let gaps = vk::SampleCountFlags::_1 | vk::SampleCountFlags::_4;
+        assert_eq!(clamp_sample_count(2, gaps), vk::SampleCountFlags::_1);
+        assert_eq!(clamp_sample_count(16, gaps), vk::SampleCountFlags::_4);
+        assert_eq!(clamp_sample_count(8, vk::SampleCountFlags::empty()), vk::SampleCountFlags::_1);
+    }
+
+    #[test]
+    fn samples_are_supported_by_color_and_depth() {
+        let mut data = AppData::default();
+        let limits = &mut data.physical_device_properties.limits;
+        limits.framebuffer_color_sample_counts = up_to_8();
+        limits.framebuffer_depth_sample_counts = vk::SampleCountFlags::_1 | vk::SampleCountFlags::_4;
+        choose_msaa_samples(&mut data, 8);
+        assert_eq!(data.msaa_samples, vk::SampleCountFlags::_4);
+    }
+
+    #[test]
+    fn sample_shading_needs_the_feature() {
+        let mut data = AppData::default();
+        choose_sample_shading(&mut data, Some(0.5));
+        assert_eq!(data.min_sample_shading, None);
+        data.enabled_features.sample_rate_shading = vk::TRUE;
+        choose_sample_shading(&mut data, Some(0.5));
+        assert_eq!(data.min_sample_shading, Some(0.5));
+        choose_sample_shading(&mut data, None);
+        assert_eq!(data.min_sample_shading, None);
+    }
+}
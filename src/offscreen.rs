@@ -0,0 +1,85 @@
+use anyhow::Result;
+
+use vulkanalia::prelude::v1_0::*;
+
+use crate::memory::{allocate_image_memory, AllocationRequest, ResourceKind};
+use crate::AppData;
+
+/// Format of the offscreen color image.
+pub const OFFSCREEN_FORMAT: vk::Format = vk::Format::R8G8B8A8_SRGB;
+
+/// How the offscreen color image is created: an sRGB color attachment that
+/// frames can be copied out of.
+pub fn offscreen_image_info(extent: vk::Extent2D) -> vk::ImageCreateInfo {
+    vk::ImageCreateInfo::builder()
+        .image_type(vk::ImageType::_2D)
+        .extent(vk::Extent3D { width: extent.width, height: extent.height, depth: 1 })
+        .mip_levels(1)
+        .array_layers(1)
+        .format(OFFSCREEN_FORMAT)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .samples(vk::SampleCountFlags::_1)
+        .build()
+}
+
+/// Creates the color image frames are rendered into when there is no window,
+/// and makes it the only render target.
+pub unsafe fn create_offscreen_target(
+    instance: &Instance,
+    device: &Device,
+    data: &mut AppData,
+    extent: vk::Extent2D,
+) -> Result<()> {
+    let info = offscreen_image_info(extent);
+    let image = device.create_image(&info, None)?;
+    let usage = info.usage;
+
+    let request = AllocationRequest::device_local(ResourceKind::Optimal);
+    let allocation = allocate_image_memory(device, data, image, vk::ImageTiling::OPTIMAL, request)?;
+
+    let subresource_range = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(1)
+        .base_array_layer(0)
+        .layer_count(1);
+    let info = vk::ImageViewCreateInfo::builder()
+        .image(image)
+        .view_type(vk::ImageViewType::_2D)
+        .format(OFFSCREEN_FORMAT)
+        .subresource_range(subresource_range);
+    let view = device.create_image_view(&info, None)?;
+
+    data.offscreen_image_allocation = allocation;
+    data.target_format = OFFSCREEN_FORMAT;
+    data.target_extent = extent;
+    data.target_usage = usage;
+    data.target_images = vec![image];
+    data.target_image_views = vec![view];
+    Ok(())
+}
+
+pub unsafe fn destroy_offscreen_target(device: &Device, data: &mut AppData) {
+    data.target_image_views.drain(..).for_each(|v| device.destroy_image_view(v, None));
+    data.target_images.drain(..).for_each(|i| device.destroy_image(i, None));
+    data.allocator.free(device, &data.offscreen_image_allocation);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offscreen_image_is_a_copyable_srgb_attachment() {
+        let info = offscreen_image_info(vk::Extent2D { width: 640, height: 480 });
+        assert_eq!(info.format, vk::Format::R8G8B8A8_SRGB);
+        assert_eq!(info.usage, vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC);
+        assert_eq!(info.extent, vk::Extent3D { width: 640, height: 480, depth: 1 });
+        assert_eq!((info.mip_levels, info.array_layers), (1, 1));
+        assert_eq!(info.samples, vk::SampleCountFlags::_1);
+        assert_eq!(info.tiling, vk::ImageTiling::OPTIMAL);
+    }
+}
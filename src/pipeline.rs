@@ -0,0 +1,508 @@
+use std::mem;
+
+use anyhow::{anyhow, Context, Result};
+use log::*;
+
+use vulkanalia::prelude::v1_0::*;
+
+use crate::model::Vertex;
+use crate::reflect::{check_vertex_input, packed_vertex_attributes, reflect, PipelineLayoutReflection};
+use crate::shader::{create_shader_module, Spirv};
+use crate::AppData;
+
+/// How the color output is combined with the render target.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum BlendMode {
+    /// Overwrites the target.
+    #[default]
+    Opaque,
+    /// Standard `src * a + dst * (1 - a)` blending.
+    Alpha,
+    /// Adds the output to the target.
+    Additive,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RasterizationState {
+    pub polygon_mode: vk::PolygonMode,
+    pub cull_mode: vk::CullModeFlags,
+    pub front_face: vk::FrontFace,
+    pub line_width: f32,
+}
+
+impl RasterizationState {
+    /// Checks that the line width is 1.0 unless `wideLines` is enabled.
+    pub fn check_line_width(&self, features: &vk::PhysicalDeviceFeatures) -> Result<()> {
+        if self.line_width == 1.0 || features.wide_lines == vk::TRUE {
+            Ok(())
+        } else {
+            Err(anyhow!("Line width {} requires the wideLines feature.", self.line_width))
+        }
+    }
+}
+
+impl Default for RasterizationState {
+    fn default() -> Self {
+        Self {
+            polygon_mode: vk::PolygonMode::FILL,
+            cull_mode: vk::CullModeFlags::BACK,
+            front_face: vk::FrontFace::COUNTER_CLOCKWISE,
+            line_width: 1.0,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DepthState {
+    pub test: bool,
+    pub write: bool,
+    pub compare_op: vk::CompareOp,
+}
+
+impl Default for DepthState {
+    fn default() -> Self {
+        Self { test: false, write: false, compare_op: vk::CompareOp::LESS }
+    }
+}
+
+/// A declarative description of a graphics pipeline. Viewport and scissor are
+/// dynamic state so pipelines survive swapchain recreation.
+///
+/// Left empty, the vertex input, descriptor set layouts and push constant
+/// ranges are generated from the shaders' SPIR-V (see [`crate::reflect`]).
+#[derive(Clone, Debug)]
+pub struct GraphicsPipelineDesc {
+    pub vertex_shader: Spirv,
+    pub fragment_shader: Spirv,
+    pub vertex_bindings: Vec<vk::VertexInputBindingDescription>,
+    pub vertex_attributes: Vec<vk::VertexInputAttributeDescription>,
+    pub topology: vk::PrimitiveTopology,
+    pub rasterization: RasterizationState,
+    pub blend: BlendMode,
+    pub depth: DepthState,
+    pub descriptor_set_layouts: Vec<vk::DescriptorSetLayout>,
+    pub push_constant_ranges: Vec<vk::PushConstantRange>,
+}
+
+impl GraphicsPipelineDesc {
+    /// A description drawing triangle lists with the default fixed function state.
+    pub fn new(vertex_shader: Spirv, fragment_shader: Spirv) -> Self {
+        Self {
+            vertex_shader,
+            fragment_shader,
+            vertex_bindings: vec![],
+            vertex_attributes: vec![],
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            rasterization: RasterizationState::default(),
+            blend: BlendMode::default(),
+            depth: DepthState::default(),
+            descriptor_set_layouts: vec![],
+            push_constant_ranges: vec![],
+        }
+    }
+}
+
+impl GraphicsPipelineDesc {
+    /// A copy of this description using `spirv` in place of the shader with
+    /// the same name, or `None` if it uses no such shader.
+    pub fn with_shader(&self, spirv: &Spirv) -> Option<Self> {
+        let mut desc = self.clone();
+        let mut replaced = false;
+        for shader in [&mut desc.vertex_shader, &mut desc.fragment_shader] {
+            if shader.name == spirv.name {
+                *shader = spirv.clone();
+                replaced = true;
+            }
+        }
+        replaced.then_some(desc)
+    }
+}
+
+/// The vertex-less triangle of the tutorial, drawn with `cmd_draw(3, 1, 0, 0)`.
+pub fn triangle_pipeline_desc() -> Result<GraphicsPipelineDesc> {
+    let mut desc = GraphicsPipelineDesc::new(Spirv::embedded("triangle.vert")?, Spirv::embedded("triangle.frag")?);
+    desc.rasterization.front_face = vk::FrontFace::CLOCKWISE;
+    Ok(desc)
+}
+
+/// Draws the loaded model with the vertex format of [`crate::model::Vertex`].
+/// `set_layouts` must be those the shaders declare, see
+/// [`create_descriptor_set_layouts`].
+pub fn mesh_pipeline_desc(set_layouts: Vec<vk::DescriptorSetLayout>) -> Result<GraphicsPipelineDesc> {
+    let mut desc = GraphicsPipelineDesc::new(Spirv::embedded("mesh.vert")?, Spirv::embedded("mesh.frag")?);
+    desc.vertex_bindings = vec![Vertex::binding_description()];
+    desc.vertex_attributes = Vertex::attribute_descriptions().to_vec();
+    desc.descriptor_set_layouts = set_layouts;
+    desc.depth = DepthState { test: true, write: true, compare_op: vk::CompareOp::LESS };
+    Ok(desc)
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Pipeline {
+    pub pipeline: vk::Pipeline,
+    pub layout: vk::PipelineLayout,
+    /// The layouts of the descriptor sets the pipeline expects, by set index.
+    pub set_layouts: Vec<vk::DescriptorSetLayout>,
+    /// Whether `set_layouts` were generated for this pipeline and are
+    /// destroyed with it.
+    owns_set_layouts: bool,
+}
+
+impl Pipeline {
+    pub unsafe fn destroy(&self, device: &Device) {
+        device.destroy_pipeline(self.pipeline, None);
+        device.destroy_pipeline_layout(self.layout, None);
+        if self.owns_set_layouts {
+            self.set_layouts.iter().for_each(|l| device.destroy_descriptor_set_layout(*l, None));
+        }
+    }
+}
+
+/// Identifies a pipeline registered in [`Pipelines`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PipelineId(usize);
+
+/// The app's graphics pipelines together with the descriptions they were
+/// created from, so they can be rebuilt when the render pass is recreated or
+/// one of their shaders changes.
+#[derive(Clone, Debug, Default)]
+pub struct Pipelines {
+    entries: Vec<(GraphicsPipelineDesc, Pipeline)>,
+}
+
+impl Pipelines {
+    pub fn get(&self, id: PipelineId) -> &Pipeline {
+        &self.entries[id.0].1
+    }
+}
+
+/// Creates a pipeline from `desc` and registers it in `data.pipelines`.
+pub unsafe fn add_pipeline(device: &Device, data: &mut AppData, desc: GraphicsPipelineDesc) -> Result<PipelineId> {
+    let pipeline = create_graphics_pipeline(device, data, &desc)?;
+    data.pipelines.entries.push((desc, pipeline));
+    Ok(PipelineId(data.pipelines.entries.len() - 1))
+}
+
+/// Recreates every registered pipeline, e.g. after the render pass was recreated.
+pub unsafe fn create_pipelines(device: &Device, data: &mut AppData) -> Result<()> {
+    for i in 0..data.pipelines.entries.len() {
+        let pipeline = create_graphics_pipeline(device, data, &data.pipelines.entries[i].0)?;
+        data.pipelines.entries[i].1 = pipeline;
+    }
+    Ok(())
+}
+
+/// Destroys every registered pipeline, keeping their descriptions.
+pub unsafe fn destroy_pipelines(device: &Device, data: &mut AppData) {
+    for (_, pipeline) in &mut data.pipelines.entries {
+        pipeline.destroy(device);
+        *pipeline = Pipeline::default();
+    }
+}
+
+/// Rebuilds the pipelines using the shader named `spirv.name` with its new
+/// code, returning how many were rebuilt. A pipeline that fails to build is
+/// kept as it was and the error is logged.
+pub unsafe fn reload_shader(device: &Device, data: &mut AppData, spirv: &Spirv) -> Result<usize> {
+    let mut retired = vec![];
+    for i in 0..data.pipelines.entries.len() {
+        let Some(desc) = data.pipelines.entries[i].0.with_shader(spirv) else {
+            continue;
+        };
+        match create_graphics_pipeline(device, data, &desc) {
+            Ok(pipeline) => retired.push(mem::replace(&mut data.pipelines.entries[i], (desc, pipeline)).1),
+            Err(e) => error!("{:#}", e),
+        }
+    }
+
+    if !retired.is_empty() {
+        device.device_wait_idle()?;
+        retired.iter().for_each(|p| p.destroy(device));
+    }
+    Ok(retired.len())
+}
+
+/// Creates a graphics pipeline for subpass 0 of `data.render_pass`.
+pub unsafe fn create_graphics_pipeline(
+    device: &Device,
+    data: &AppData,
+    desc: &GraphicsPipelineDesc,
+) -> Result<Pipeline> {
+    desc.rasterization.check_line_width(&data.enabled_features)?;
+    let (desc, reflection) = &reflect_pipeline_desc(desc)?;
+    let vert_shader_module = create_shader_module(device, &desc.vertex_shader)?;
+    let frag_shader_module = match create_shader_module(device, &desc.fragment_shader) {
+        Ok(module) => module,
+        Err(e) => {
+            device.destroy_shader_module(vert_shader_module, None);
+            return Err(e.into());
+        }
+    };
+
+    let result = create_pipeline(device, data, desc, reflection, vert_shader_module, frag_shader_module);
+
+    device.destroy_shader_module(vert_shader_module, None);
+    device.destroy_shader_module(frag_shader_module, None);
+
+    result.with_context(|| {
+        format!(
+            "Failed to create graphics pipeline for `{}` and `{}`.",
+            desc.vertex_shader.name, desc.fragment_shader.name,
+        )
+    })
+}
+
+/// Fills in what `desc` leaves empty from the shaders' reflection and checks
+/// the vertex input against the vertex shader.
+fn reflect_pipeline_desc(desc: &GraphicsPipelineDesc) -> Result<(GraphicsPipelineDesc, PipelineLayoutReflection)> {
+    let vertex = reflect(&desc.vertex_shader)?;
+    let fragment = reflect(&desc.fragment_shader)?;
+    let mut desc = desc.clone();
+
+    if desc.vertex_bindings.is_empty() && desc.vertex_attributes.is_empty() && !vertex.inputs.is_empty() {
+        let (attributes, stride) = packed_vertex_attributes(&vertex.inputs, 0);
+        desc.vertex_bindings = vec![vk::VertexInputBindingDescription::builder()
+            .binding(0)
+            .stride(stride)
+            .input_rate(vk::VertexInputRate::VERTEX)
+            .build()];
+        desc.vertex_attributes = attributes;
+    }
+
+    let (errors, warnings) = check_vertex_input(&vertex.inputs, &desc.vertex_attributes)
+        .into_iter()
+        .partition::<Vec<_>, _>(|m| m.is_error());
+    warnings.iter().for_each(|m| warn!("`{}`: {}.", desc.vertex_shader.name, m));
+    if !errors.is_empty() {
+        let errors = errors.iter().map(|m| m.to_string()).collect::<Vec<_>>();
+        return Err(anyhow!(
+            "Vertex input does not match `{}`: {}.",
+            desc.vertex_shader.name,
+            errors.join("; "),
+        ));
+    }
+
+    let reflection = PipelineLayoutReflection::merge(&[&vertex, &fragment])?;
+    if desc.push_constant_ranges.is_empty() {
+        desc.push_constant_ranges = reflection.push_constant_ranges.clone();
+    }
+    Ok((desc, reflection))
+}
+
+/// Creates the descriptor set layouts the shaders declare, unless `desc`
+/// supplies its own. Returns them and whether they were created here.
+unsafe fn create_set_layouts(
+    device: &Device,
+    desc: &GraphicsPipelineDesc,
+    reflection: &PipelineLayoutReflection,
+) -> Result<(Vec<vk::DescriptorSetLayout>, bool)> {
+    if !desc.descriptor_set_layouts.is_empty() {
+        return Ok((desc.descriptor_set_layouts.clone(), false));
+    }
+    Ok((create_reflected_set_layouts(device, reflection)?, true))
+}
+
+/// Creates the descriptor set layouts the shaders of `desc` declare, for
+/// descriptor sets that outlive rebuilds of the pipeline. Passing them as
+/// `desc.descriptor_set_layouts` keeps the pipeline from creating its own.
+pub unsafe fn create_descriptor_set_layouts(
+    device: &Device,
+    desc: &GraphicsPipelineDesc,
+) -> Result<(Vec<vk::DescriptorSetLayout>, PipelineLayoutReflection)> {
+    let (_, reflection) = reflect_pipeline_desc(desc)?;
+    let set_layouts = create_reflected_set_layouts(device, &reflection)?;
+    Ok((set_layouts, reflection))
+}
+
+unsafe fn create_reflected_set_layouts(
+    device: &Device,
+    reflection: &PipelineLayoutReflection,
+) -> Result<Vec<vk::DescriptorSetLayout>> {
+    let mut set_layouts = vec![];
+    for bindings in reflection.set_layout_bindings() {
+        let info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings);
+        match device.create_descriptor_set_layout(&info, None) {
+            Ok(set_layout) => set_layouts.push(set_layout),
+            Err(e) => {
+                set_layouts.iter().for_each(|l| device.destroy_descriptor_set_layout(*l, None));
+                return Err(e.into());
+            }
+        }
+    }
+    Ok(set_layouts)
+}
+
+unsafe fn create_pipeline(
+    device: &Device,
+    data: &AppData,
+    desc: &GraphicsPipelineDesc,
+    reflection: &PipelineLayoutReflection,
+    vert_shader_module: vk::ShaderModule,
+    frag_shader_module: vk::ShaderModule,
+) -> Result<Pipeline> {
+    let vert_stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::VERTEX)
+        .module(vert_shader_module)
+        .name(b"main\0");
+    let frag_stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::FRAGMENT)
+        .module(frag_shader_module)
+        .name(b"main\0");
+
+    let vertex_input_state = vk::PipelineVertexInputStateCreateInfo::builder()
+        .vertex_binding_descriptions(&desc.vertex_bindings)
+        .vertex_attribute_descriptions(&desc.vertex_attributes);
+
+    let input_assembly_state = vk::PipelineInputAssemblyStateCreateInfo::builder()
+        .topology(desc.topology)
+        .primitive_restart_enable(false);
+
+    let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+        .viewport_count(1)
+        .scissor_count(1);
+
+    let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
+        .depth_clamp_enable(false)
+        .rasterizer_discard_enable(false)
+        .polygon_mode(desc.rasterization.polygon_mode)
+        .line_width(desc.rasterization.line_width)
+        .cull_mode(desc.rasterization.cull_mode)
+        .front_face(desc.rasterization.front_face)
+        .depth_bias_enable(false);
+
+    let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
+        .sample_shading_enable(data.min_sample_shading.is_some())
+        .min_sample_shading(data.min_sample_shading.unwrap_or(0.0))
+        .rasterization_samples(data.msaa_samples);
+
+    let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::builder()
+        .depth_test_enable(desc.depth.test)
+        .depth_write_enable(desc.depth.write)
+        .depth_compare_op(desc.depth.compare_op)
+        .depth_bounds_test_enable(false)
+        .stencil_test_enable(false);
+
+    let attachment = color_blend_attachment(desc.blend);
+    let attachments = &[attachment];
+    let color_blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
+        .logic_op_enable(false)
+        .logic_op(vk::LogicOp::COPY)
+        .attachments(attachments)
+        .blend_constants([0.0, 0.0, 0.0, 0.0]);
+
+    let dynamic_states = &[vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+    let dynamic_state = vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(dynamic_states);
+
+    let (set_layouts, owns_set_layouts) = create_set_layouts(device, desc, reflection)?;
+    let destroy_set_layouts = || {
+        if owns_set_layouts {
+            set_layouts.iter().for_each(|l| device.destroy_descriptor_set_layout(*l, None));
+        }
+    };
+
+    let layout_info = vk::PipelineLayoutCreateInfo::builder()
+        .set_layouts(&set_layouts)
+        .push_constant_ranges(&desc.push_constant_ranges);
+    let layout = match device.create_pipeline_layout(&layout_info, None) {
+        Ok(layout) => layout,
+        Err(e) => {
+            destroy_set_layouts();
+            return Err(e.into());
+        }
+    };
+
+    let stages = &[vert_stage, frag_stage];
+    let info = vk::GraphicsPipelineCreateInfo::builder()
+        .stages(stages)
+        .vertex_input_state(&vertex_input_state)
+        .input_assembly_state(&input_assembly_state)
+        .viewport_state(&viewport_state)
+        .rasterization_state(&rasterization_state)
+        .multisample_state(&multisample_state)
+        .depth_stencil_state(&depth_stencil_state)
+        .color_blend_state(&color_blend_state)
+        .dynamic_state(&dynamic_state)
+        .layout(layout)
+        .render_pass(data.render_pass)
+        .subpass(0);
+
+    match device.create_graphics_pipelines(data.pipeline_cache, &[info], None) {
+        Ok((pipeline, _)) => Ok(Pipeline { pipeline, layout, set_layouts, owns_set_layouts }),
+        Err(e) => {
+            device.destroy_pipeline_layout(layout, None);
+            destroy_set_layouts();
+            Err(e.into())
+        }
+    }
+}
+
+fn color_blend_attachment(blend: BlendMode) -> vk::PipelineColorBlendAttachmentState {
+    let builder = vk::PipelineColorBlendAttachmentState::builder()
+        .color_write_mask(vk::ColorComponentFlags::all())
+        .color_blend_op(vk::BlendOp::ADD)
+        .src_alpha_blend_factor(vk::BlendFactor::ONE)
+        .dst_alpha_blend_factor(vk::BlendFactor::ZERO)
+        .alpha_blend_op(vk::BlendOp::ADD);
+    match blend {
+        BlendMode::Opaque => builder
+            .blend_enable(false)
+            .src_color_blend_factor(vk::BlendFactor::ONE)
+            .dst_color_blend_factor(vk::BlendFactor::ZERO),
+        BlendMode::Alpha => builder
+            .blend_enable(true)
+            .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
+            .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA),
+        BlendMode::Additive => builder
+            .blend_enable(true)
+            .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
+            .dst_color_blend_factor(vk::BlendFactor::ONE),
+    }
+    .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn with_shader_replaces_matching_shaders() {
+        let desc = triangle_pipeline_desc().unwrap();
+        let mut spirv = Spirv::embedded("triangle.frag").unwrap();
+        spirv.words.push(0);
+        let replaced = desc.with_shader(&spirv).unwrap();
+        assert_eq!(replaced.fragment_shader, spirv);
+        assert_eq!(replaced.vertex_shader, desc.vertex_shader);
+        assert_eq!(replaced.rasterization, desc.rasterization);
+    }
+
+    #[test]
+    fn with_shader_ignores_other_shaders() {
+        let desc = triangle_pipeline_desc().unwrap();
+        let other = Spirv { name: "other.frag".into(), words: desc.fragment_shader.words.clone() };
+        assert!(desc.with_shader(&other).is_none());
+    }
+
+    #[test]
+    fn wide_lines_need_the_feature() {
+        let features = vk::PhysicalDeviceFeatures::default();
+        let mut rasterization = RasterizationState::default();
+        assert!(rasterization.check_line_width(&features).is_ok());
+        rasterization.line_width = 2.0;
+        assert!(rasterization.check_line_width(&features).is_err());
+        let features = vk::PhysicalDeviceFeatures { wide_lines: vk::TRUE, ..features };
+        assert!(rasterization.check_line_width(&features).is_ok());
+    }
+
+    #[test]
+    fn blend_modes() {
+        let opaque = color_blend_attachment(BlendMode::Opaque);
+        assert_eq!(opaque.blend_enable, 0);
+        assert_eq!(opaque.color_write_mask, vk::ColorComponentFlags::all());
+        let alpha = color_blend_attachment(BlendMode::Alpha);
+        assert_eq!(alpha.blend_enable, 1);
+        assert_eq!(alpha.dst_color_blend_factor, vk::BlendFactor::ONE_MINUS_SRC_ALPHA);
+        let additive = color_blend_attachment(BlendMode::Additive);
+        assert_eq!(additive.blend_enable, 1);
+        assert_eq!(additive.dst_color_blend_factor, vk::BlendFactor::ONE);
+    }
+}
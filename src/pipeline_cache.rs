@@ -0,0 +1,203 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::*;
+use thiserror::Error;
+
+use vulkanalia::prelude::v1_0::*;
+
+use crate::AppData;
+
+/// Size of the `VK_PIPELINE_CACHE_HEADER_VERSION_ONE` header.
+const HEADER_SIZE: usize = 32;
+
+/// Why a saved pipeline cache cannot be used with the current device.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum CacheHeaderError {
+    #[error("the file is too short to hold a header")]
+    Truncated,
+    #[error("unsupported header (size {size}, version {version})")]
+    Version { size: u32, version: u32 },
+    #[error("it was written for device {vendor:04x}:{device:04x}")]
+    Device { vendor: u32, device: u32 },
+    #[error("it was written by a different driver version")]
+    Uuid,
+}
+
+/// The default directory for saved pipeline caches:
+/// `$XDG_CACHE_HOME/vulkan-works`, else `~/.cache/vulkan-works`.
+pub fn default_cache_dir() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CACHE_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| Path::new(&h).join(".cache")))?;
+    Some(base.join("vulkan-works"))
+}
+
+/// The file the cache of the device with `properties` is saved to, keyed by
+/// vendor ID, device ID and pipeline cache UUID.
+pub fn cache_path(dir: &Path, properties: &vk::PhysicalDeviceProperties) -> PathBuf {
+    let uuid = properties.pipeline_cache_uuid.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    dir.join(format!("pipeline-cache-{:04x}-{:04x}-{}.bin", properties.vendor_id, properties.device_id, uuid))
+}
+
+/// Checks that saved cache data starts with a header matching the device.
+pub fn validate_header(bytes: &[u8], properties: &vk::PhysicalDeviceProperties) -> Result<(), CacheHeaderError> {
+    if bytes.len() < HEADER_SIZE {
+        return Err(CacheHeaderError::Truncated);
+    }
+
+    let word = |i: usize| u32::from_le_bytes([bytes[i * 4], bytes[i * 4 + 1], bytes[i * 4 + 2], bytes[i * 4 + 3]]);
+    let (size, version) = (word(0), word(1));
+    if size as usize != HEADER_SIZE || version != vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32 {
+        return Err(CacheHeaderError::Version { size, version });
+    }
+    let (vendor, device) = (word(2), word(3));
+    if (vendor, device) != (properties.vendor_id, properties.device_id) {
+        return Err(CacheHeaderError::Device { vendor, device });
+    }
+    if bytes[16..32] != properties.pipeline_cache_uuid[..] {
+        return Err(CacheHeaderError::Uuid);
+    }
+    Ok(())
+}
+
+/// Creates `data.pipeline_cache`, seeded from the file saved for this device
+/// in `dir` if there is one and it matches. A missing, unreadable or stale
+/// file is ignored and the cache starts out empty.
+pub unsafe fn create_pipeline_cache(device: &Device, data: &mut AppData, dir: Option<&Path>) -> Result<()> {
+    let path = dir.map(|d| cache_path(d, &data.physical_device_properties));
+    let initial_data = match &path {
+        Some(path) => match fs::read(path) {
+            Ok(bytes) => match validate_header(&bytes, &data.physical_device_properties) {
+                Ok(()) => {
+                    info!("Loaded pipeline cache `{}` ({} bytes).", path.display(), bytes.len());
+                    bytes
+                }
+                Err(e) => {
+                    warn!("Ignoring pipeline cache `{}`: {}.", path.display(), e);
+                    vec![]
+                }
+            },
+            Err(e) => {
+                debug!("No pipeline cache loaded from `{}`: {}.", path.display(), e);
+                vec![]
+            }
+        },
+        None => vec![],
+    };
+
+    let info = vk::PipelineCacheCreateInfo::builder().initial_data(&initial_data);
+    data.pipeline_cache = match device.create_pipeline_cache(&info, None) {
+        Ok(cache) => cache,
+        // The driver may still reject data with a matching header.
+        Err(e) if !initial_data.is_empty() => {
+            warn!("Driver rejected pipeline cache data ({}), starting with an empty cache.", e);
+            device.create_pipeline_cache(&vk::PipelineCacheCreateInfo::builder(), None)?
+        }
+        Err(e) => return Err(e.into()),
+    };
+    data.pipeline_cache_path = path;
+    Ok(())
+}
+
+/// Writes `data.pipeline_cache` to its file, through a temporary file so an
+/// interrupted write never leaves a truncated cache behind.
+pub unsafe fn save_pipeline_cache(device: &Device, data: &AppData) -> Result<()> {
+    let Some(path) = &data.pipeline_cache_path else {
+        return Ok(());
+    };
+
+    let bytes = device.get_pipeline_cache_data(data.pipeline_cache)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create `{}`.", dir.display()))?;
+    }
+    let temp = path.with_extension("tmp");
+    if let Err(e) = fs::write(&temp, &bytes).and_then(|_| fs::rename(&temp, path)) {
+        let _ = fs::remove_file(&temp);
+        return Err(e).with_context(|| format!("Failed to write pipeline cache `{}`.", path.display()));
+    }
+    info!("Saved pipeline cache `{}` ({} bytes).", path.display(), bytes.len());
+    Ok(())
+}
+
+pub unsafe fn destroy_pipeline_cache(device: &Device, data: &mut AppData) {
+    device.destroy_pipeline_cache(data.pipeline_cache, None);
+    data.pipeline_cache = vk::PipelineCache::null();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn properties() -> vk::PhysicalDeviceProperties {
+        let pipeline_cache_uuid = vk::ByteArray(std::array::from_fn(|i| i as u8));
+        vk::PhysicalDeviceProperties { vendor_id: 0x10de, device_id: 0x1b80, pipeline_cache_uuid, ..Default::default() }
+    }
+
+    /// A header as the driver of `properties` writes it, followed by some data.
+    fn cache(properties: &vk::PhysicalDeviceProperties) -> Vec<u8> {
+        let version = vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32;
+        let words = [HEADER_SIZE as u32, version, properties.vendor_id, properties.device_id];
+        let mut bytes = words.iter().flat_map(|w| w.to_le_bytes()).collect::<Vec<_>>();
+        bytes.extend_from_slice(&properties.pipeline_cache_uuid.0);
+        bytes.extend_from_slice(&[0xab; 100]);
+        bytes
+    }
+
+    #[test]
+    fn matching_header() {
+        let properties = properties();
+        assert_eq!(validate_header(&cache(&properties), &properties), Ok(()));
+        assert_eq!(validate_header(&cache(&properties)[..HEADER_SIZE], &properties), Ok(()));
+    }
+
+    #[test]
+    fn truncated() {
+        let properties = properties();
+        assert_eq!(validate_header(&[], &properties), Err(CacheHeaderError::Truncated));
+        let bytes = cache(&properties);
+        assert_eq!(validate_header(&bytes[..HEADER_SIZE - 1], &properties), Err(CacheHeaderError::Truncated));
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let properties = properties();
+        let mut bytes = cache(&properties);
+        bytes[4..8].copy_from_slice(&2u32.to_le_bytes());
+        assert_eq!(validate_header(&bytes, &properties), Err(CacheHeaderError::Version { size: 32, version: 2 }));
+        let mut bytes = cache(&properties);
+        bytes[0..4].copy_from_slice(&48u32.to_le_bytes());
+        assert_eq!(validate_header(&bytes, &properties), Err(CacheHeaderError::Version { size: 48, version: 1 }));
+    }
+
+    #[test]
+    fn other_device() {
+        let properties = properties();
+        let other = vk::PhysicalDeviceProperties { device_id: 0x1b81, ..properties };
+        assert_eq!(
+            validate_header(&cache(&other), &properties),
+            Err(CacheHeaderError::Device { vendor: 0x10de, device: 0x1b81 }),
+        );
+    }
+
+    #[test]
+    fn other_driver() {
+        let properties = properties();
+        let mut other = properties;
+        other.pipeline_cache_uuid.0[15] ^= 1;
+        assert_eq!(validate_header(&cache(&other), &properties), Err(CacheHeaderError::Uuid));
+    }
+
+    #[test]
+    fn paths_are_keyed_by_device_and_uuid() {
+        let properties = properties();
+        let path = cache_path(Path::new("/cache"), &properties);
+        assert_eq!(path, Path::new("/cache/pipeline-cache-10de-1b80-000102030405060708090a0b0c0d0e0f.bin"));
+        let mut other = properties;
+        other.pipeline_cache_uuid.0[0] = 0xff;
+        assert_ne!(cache_path(Path::new("/cache"), &other), path);
+    }
+}
@@ -0,0 +1,850 @@
+//! Minimal SPIR-V reflection: descriptor bindings, push constants and the
+//! vertex shader's input locations, read straight from the module's words.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use thiserror::Error;
+
+use vulkanalia::prelude::v1_0::*;
+
+use crate::shader::{Spirv, SPIRV_MAGIC};
+
+// Opcodes
+const OP_NAME: u32 = 5;
+const OP_ENTRY_POINT: u32 = 15;
+const OP_TYPE_BOOL: u32 = 20;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+const OP_TYPE_IMAGE: u32 = 25;
+const OP_TYPE_SAMPLER: u32 = 26;
+const OP_TYPE_SAMPLED_IMAGE: u32 = 27;
+const OP_TYPE_ARRAY: u32 = 28;
+const OP_TYPE_RUNTIME_ARRAY: u32 = 29;
+const OP_TYPE_STRUCT: u32 = 30;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_CONSTANT: u32 = 43;
+const OP_VARIABLE: u32 = 59;
+const OP_DECORATE: u32 = 71;
+const OP_MEMBER_DECORATE: u32 = 72;
+
+// Decorations
+const DECORATION_BLOCK: u32 = 2;
+const DECORATION_BUFFER_BLOCK: u32 = 3;
+const DECORATION_ARRAY_STRIDE: u32 = 6;
+const DECORATION_MATRIX_STRIDE: u32 = 7;
+const DECORATION_BUILT_IN: u32 = 11;
+const DECORATION_LOCATION: u32 = 30;
+const DECORATION_BINDING: u32 = 33;
+const DECORATION_DESCRIPTOR_SET: u32 = 34;
+const DECORATION_OFFSET: u32 = 35;
+
+// Storage classes
+const STORAGE_UNIFORM_CONSTANT: u32 = 0;
+const STORAGE_INPUT: u32 = 1;
+const STORAGE_UNIFORM: u32 = 2;
+const STORAGE_PUSH_CONSTANT: u32 = 9;
+const STORAGE_STORAGE_BUFFER: u32 = 12;
+
+// Image dimensions
+const DIM_BUFFER: u32 = 5;
+const DIM_SUBPASS_DATA: u32 = 6;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ReflectError {
+    #[error("Shader `{0}` is not a valid SPIR-V module: {1}.")]
+    Malformed(String, &'static str),
+    #[error("Shader `{0}` has no entry point.")]
+    NoEntryPoint(String),
+    #[error("Shader `{0}` uses unsupported execution model {1}.")]
+    ExecutionModel(String, u32),
+    #[error("Shader `{0}`: {1} `{2}` has a type reflection does not support.")]
+    UnsupportedType(String, &'static str, String),
+    #[error("Binding {set}.{binding} is declared as {a:?} in one stage and {b:?} in another.")]
+    BindingConflict { set: u32, binding: u32, a: vk::DescriptorType, b: vk::DescriptorType },
+}
+
+/// A resource binding declared by a shader.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DescriptorBinding {
+    pub set: u32,
+    pub binding: u32,
+    pub descriptor_type: vk::DescriptorType,
+    /// Number of descriptors, more than one for arrays of resources.
+    pub count: u32,
+    pub stages: vk::ShaderStageFlags,
+    pub name: String,
+}
+
+/// A vertex shader input occupying one location.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VertexInput {
+    pub location: u32,
+    pub format: vk::Format,
+    pub name: String,
+}
+
+/// What a single shader module declares.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShaderReflection {
+    pub stage: vk::ShaderStageFlags,
+    pub bindings: Vec<DescriptorBinding>,
+    /// The push constant block, if any, as a range covering its members.
+    pub push_constants: Option<vk::PushConstantRange>,
+    /// Inputs of a vertex shader, sorted by location. Built-ins are skipped.
+    pub inputs: Vec<VertexInput>,
+}
+
+#[derive(Clone, Debug)]
+enum Type {
+    Bool,
+    Int { width: u32, signed: bool },
+    Float { width: u32 },
+    Vector { component: u32, count: u32 },
+    Matrix { column: u32, count: u32 },
+    Image { dim: u32, sampled: u32 },
+    Sampler,
+    SampledImage,
+    Array { element: u32, length: u32 },
+    RuntimeArray,
+    Struct { members: Vec<u32> },
+    Pointer { pointee: u32 },
+}
+
+#[derive(Clone, Debug, Default)]
+struct Decorations {
+    block: bool,
+    buffer_block: bool,
+    built_in: bool,
+    location: Option<u32>,
+    binding: Option<u32>,
+    set: Option<u32>,
+    array_stride: Option<u32>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct MemberDecorations {
+    offset: u32,
+    matrix_stride: Option<u32>,
+}
+
+/// The parts of a module reflection needs, indexed by result id.
+#[derive(Debug, Default)]
+struct Module {
+    execution_model: Option<u32>,
+    names: HashMap<u32, String>,
+    types: HashMap<u32, Type>,
+    constants: HashMap<u32, u32>,
+    decorations: HashMap<u32, Decorations>,
+    members: HashMap<(u32, u32), MemberDecorations>,
+    /// `(id, pointer type, storage class)` of every global variable.
+    variables: Vec<(u32, u32, u32)>,
+}
+
+impl Module {
+    fn parse(name: &str, words: &[u32]) -> Result<Self, ReflectError> {
+        let malformed = |reason| ReflectError::Malformed(name.into(), reason);
+        if words.len() < 5 || words[0] != SPIRV_MAGIC {
+            return Err(malformed("missing header"));
+        }
+
+        let mut module = Self::default();
+        let mut offset = 5;
+        while offset < words.len() {
+            let count = (words[offset] >> 16) as usize;
+            let opcode = words[offset] & 0xffff;
+            if count == 0 || offset + count > words.len() {
+                return Err(malformed("truncated instruction"));
+            }
+            let operands = &words[offset + 1..offset + count];
+            let operand = |i: usize| operands.get(i).copied().ok_or_else(|| malformed("missing operand"));
+            match opcode {
+                OP_NAME => {
+                    module.names.insert(operand(0)?, string(&operands[1..]));
+                }
+                OP_ENTRY_POINT if module.execution_model.is_none() => {
+                    module.execution_model = Some(operand(0)?);
+                }
+                OP_TYPE_BOOL => {
+                    module.types.insert(operand(0)?, Type::Bool);
+                }
+                OP_TYPE_INT => {
+                    let ty = Type::Int { width: operand(1)?, signed: operand(2)? != 0 };
+                    module.types.insert(operand(0)?, ty);
+                }
+                OP_TYPE_FLOAT => {
+                    module.types.insert(operand(0)?, Type::Float { width: operand(1)? });
+                }
+                OP_TYPE_VECTOR => {
+                    let ty = Type::Vector { component: operand(1)?, count: operand(2)? };
+                    module.types.insert(operand(0)?, ty);
+                }
+                OP_TYPE_MATRIX => {
+                    let ty = Type::Matrix { column: operand(1)?, count: operand(2)? };
+                    module.types.insert(operand(0)?, ty);
+                }
+                OP_TYPE_IMAGE => {
+                    let ty = Type::Image { dim: operand(2)?, sampled: operand(6)? };
+                    module.types.insert(operand(0)?, ty);
+                }
+                OP_TYPE_SAMPLER => {
+                    module.types.insert(operand(0)?, Type::Sampler);
+                }
+                OP_TYPE_SAMPLED_IMAGE => {
+                    module.types.insert(operand(0)?, Type::SampledImage);
+                }
+                OP_TYPE_ARRAY => {
+                    // The length is resolved later since it refers to a constant.
+                    let ty = Type::Array { element: operand(1)?, length: operand(2)? };
+                    module.types.insert(operand(0)?, ty);
+                }
+                OP_TYPE_RUNTIME_ARRAY => {
+                    module.types.insert(operand(0)?, Type::RuntimeArray);
+                }
+                OP_TYPE_STRUCT => {
+                    module.types.insert(operand(0)?, Type::Struct { members: operands[1..].to_vec() });
+                }
+                OP_TYPE_POINTER => {
+                    module.types.insert(operand(0)?, Type::Pointer { pointee: operand(2)? });
+                }
+                OP_CONSTANT => {
+                    module.constants.insert(operand(1)?, operand(2)?);
+                }
+                OP_VARIABLE => {
+                    module.variables.push((operand(1)?, operand(0)?, operand(2)?));
+                }
+                OP_DECORATE => {
+                    let decorations = module.decorations.entry(operand(0)?).or_default();
+                    match operand(1)? {
+                        DECORATION_BLOCK => decorations.block = true,
+                        DECORATION_BUFFER_BLOCK => decorations.buffer_block = true,
+                        DECORATION_BUILT_IN => decorations.built_in = true,
+                        DECORATION_LOCATION => decorations.location = Some(operand(2)?),
+                        DECORATION_BINDING => decorations.binding = Some(operand(2)?),
+                        DECORATION_DESCRIPTOR_SET => decorations.set = Some(operand(2)?),
+                        DECORATION_ARRAY_STRIDE => decorations.array_stride = Some(operand(2)?),
+                        _ => {}
+                    }
+                }
+                OP_MEMBER_DECORATE => {
+                    let member = module.members.entry((operand(0)?, operand(1)?)).or_default();
+                    match operand(2)? {
+                        DECORATION_OFFSET => member.offset = operand(3)?,
+                        DECORATION_MATRIX_STRIDE => member.matrix_stride = Some(operand(3)?),
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+            offset += count;
+        }
+        Ok(module)
+    }
+
+    fn name(&self, id: u32) -> String {
+        self.names.get(&id).cloned().unwrap_or_else(|| format!("%{}", id))
+    }
+
+    fn decorations(&self, id: u32) -> Decorations {
+        self.decorations.get(&id).cloned().unwrap_or_default()
+    }
+
+    fn pointee(&self, pointer: u32) -> Option<u32> {
+        match self.types.get(&pointer) {
+            Some(Type::Pointer { pointee }) => Some(*pointee),
+            _ => None,
+        }
+    }
+
+    fn array_length(&self, length: u32) -> Option<u32> {
+        self.constants.get(&length).copied()
+    }
+
+    /// Size in bytes of a type laid out in a buffer block.
+    fn size(&self, ty: u32, matrix_stride: Option<u32>) -> Option<u32> {
+        Some(match self.types.get(&ty)? {
+            Type::Bool => 4,
+            Type::Int { width, .. } | Type::Float { width } => width / 8,
+            Type::Vector { component, count } => self.size(*component, None)? * count,
+            Type::Matrix { column, count } => {
+                matrix_stride.map_or_else(|| self.size(*column, None), Some)? * count
+            }
+            Type::Array { element, length } => {
+                let stride = self.decorations(ty).array_stride.map_or_else(|| self.size(*element, matrix_stride), Some)?;
+                stride * self.array_length(*length)?
+            }
+            Type::Struct { members } => members
+                .iter()
+                .enumerate()
+                .map(|(i, m)| {
+                    let member = self.members.get(&(ty, i as u32)).cloned().unwrap_or_default();
+                    Some(member.offset + self.size(*m, member.matrix_stride)?)
+                })
+                .try_fold(0, |size, end| Some(size.max(end?)))?,
+            _ => return None,
+        })
+    }
+
+    /// The vertex attribute format of a scalar or vector type.
+    fn format(&self, ty: u32) -> Option<vk::Format> {
+        let (component, count) = match self.types.get(&ty)? {
+            Type::Vector { component, count } => (*component, *count),
+            _ => (ty, 1),
+        };
+        let formats = match self.types.get(&component)? {
+            Type::Float { width: 32 } => [
+                vk::Format::R32_SFLOAT,
+                vk::Format::R32G32_SFLOAT,
+                vk::Format::R32G32B32_SFLOAT,
+                vk::Format::R32G32B32A32_SFLOAT,
+            ],
+            Type::Float { width: 64 } => [
+                vk::Format::R64_SFLOAT,
+                vk::Format::R64G64_SFLOAT,
+                vk::Format::R64G64B64_SFLOAT,
+                vk::Format::R64G64B64A64_SFLOAT,
+            ],
+            Type::Int { width: 32, signed: true } => [
+                vk::Format::R32_SINT,
+                vk::Format::R32G32_SINT,
+                vk::Format::R32G32B32_SINT,
+                vk::Format::R32G32B32A32_SINT,
+            ],
+            Type::Int { width: 32, signed: false } => [
+                vk::Format::R32_UINT,
+                vk::Format::R32G32_UINT,
+                vk::Format::R32G32B32_UINT,
+                vk::Format::R32G32B32A32_UINT,
+            ],
+            _ => return None,
+        };
+        formats.get(count.checked_sub(1)? as usize).copied()
+    }
+
+    /// The descriptor type and count of a resource variable of type `ty`.
+    fn descriptor(&self, ty: u32, storage_class: u32) -> Option<(vk::DescriptorType, u32)> {
+        let decorations = self.decorations(ty);
+        let descriptor_type = match (self.types.get(&ty)?, storage_class) {
+            (Type::Array { element, length }, _) => {
+                let (descriptor_type, count) = self.descriptor(*element, storage_class)?;
+                return Some((descriptor_type, count * self.array_length(*length)?));
+            }
+            (Type::Struct { .. }, STORAGE_STORAGE_BUFFER) => vk::DescriptorType::STORAGE_BUFFER,
+            (Type::Struct { .. }, STORAGE_UNIFORM) if decorations.buffer_block => vk::DescriptorType::STORAGE_BUFFER,
+            (Type::Struct { .. }, STORAGE_UNIFORM) if decorations.block => vk::DescriptorType::UNIFORM_BUFFER,
+            (Type::SampledImage, _) => vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            (Type::Sampler, _) => vk::DescriptorType::SAMPLER,
+            (Type::Image { dim: DIM_SUBPASS_DATA, .. }, _) => vk::DescriptorType::INPUT_ATTACHMENT,
+            (Type::Image { dim: DIM_BUFFER, sampled: 2 }, _) => vk::DescriptorType::STORAGE_TEXEL_BUFFER,
+            (Type::Image { dim: DIM_BUFFER, .. }, _) => vk::DescriptorType::UNIFORM_TEXEL_BUFFER,
+            (Type::Image { sampled: 2, .. }, _) => vk::DescriptorType::STORAGE_IMAGE,
+            (Type::Image { .. }, _) => vk::DescriptorType::SAMPLED_IMAGE,
+            _ => return None,
+        };
+        Some((descriptor_type, 1))
+    }
+}
+
+/// Decodes a nul-terminated SPIR-V literal string.
+fn string(words: &[u32]) -> String {
+    let bytes = words.iter().flat_map(|w| w.to_le_bytes()).take_while(|b| *b != 0).collect::<Vec<_>>();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn stage(name: &str, execution_model: u32) -> Result<vk::ShaderStageFlags, ReflectError> {
+    Ok(match execution_model {
+        0 => vk::ShaderStageFlags::VERTEX,
+        1 => vk::ShaderStageFlags::TESSELLATION_CONTROL,
+        2 => vk::ShaderStageFlags::TESSELLATION_EVALUATION,
+        3 => vk::ShaderStageFlags::GEOMETRY,
+        4 => vk::ShaderStageFlags::FRAGMENT,
+        5 => vk::ShaderStageFlags::COMPUTE,
+        _ => return Err(ReflectError::ExecutionModel(name.into(), execution_model)),
+    })
+}
+
+/// Reflects the first entry point of `spirv`.
+pub fn reflect(spirv: &Spirv) -> Result<ShaderReflection, ReflectError> {
+    let name = spirv.name.as_str();
+    let module = Module::parse(name, &spirv.words)?;
+    let execution_model = module.execution_model.ok_or_else(|| ReflectError::NoEntryPoint(name.into()))?;
+    let stage = stage(name, execution_model)?;
+    let unsupported = |what, id| ReflectError::UnsupportedType(name.into(), what, module.name(id));
+
+    let mut reflection = ShaderReflection { stage, bindings: vec![], push_constants: None, inputs: vec![] };
+    for (id, pointer, storage_class) in module.variables.iter().copied() {
+        let ty = module.pointee(pointer).ok_or_else(|| unsupported("variable", id))?;
+        let decorations = module.decorations(id);
+        match storage_class {
+            STORAGE_UNIFORM_CONSTANT | STORAGE_UNIFORM | STORAGE_STORAGE_BUFFER => {
+                let (descriptor_type, count) =
+                    module.descriptor(ty, storage_class).ok_or_else(|| unsupported("resource", id))?;
+                reflection.bindings.push(DescriptorBinding {
+                    set: decorations.set.unwrap_or(0),
+                    binding: decorations.binding.unwrap_or(0),
+                    descriptor_type,
+                    count,
+                    stages: stage,
+                    name: module.name(id),
+                });
+            }
+            STORAGE_PUSH_CONSTANT => {
+                let size = module.size(ty, None).ok_or_else(|| unsupported("push constant block", id))?;
+                let offset = match module.types.get(&ty) {
+                    Some(Type::Struct { members }) => (0..members.len() as u32)
+                        .map(|i| module.members.get(&(ty, i)).map_or(0, |m| m.offset))
+                        .min()
+                        .unwrap_or(0),
+                    _ => 0,
+                };
+                reflection.push_constants = Some(vk::PushConstantRange {
+                    stage_flags: stage,
+                    offset,
+                    size: size - offset,
+                });
+            }
+            STORAGE_INPUT if stage == vk::ShaderStageFlags::VERTEX && !decorations.built_in => {
+                let Some(location) = decorations.location else {
+                    // Members of built-in blocks (e.g. `gl_PerVertex`) carry no location.
+                    continue;
+                };
+                let name = module.name(id);
+                // Matrices occupy one location per column.
+                let (column, columns) = match module.types.get(&ty) {
+                    Some(Type::Matrix { column, count }) => (*column, *count),
+                    _ => (ty, 1),
+                };
+                let format = module.format(column).ok_or_else(|| unsupported("input", id))?;
+                for i in 0..columns {
+                    reflection.inputs.push(VertexInput { location: location + i, format, name: name.clone() });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    reflection.bindings.sort_by_key(|b| (b.set, b.binding));
+    reflection.inputs.sort_by_key(|i| i.location);
+    Ok(reflection)
+}
+
+/// The descriptor bindings and push constants of a whole pipeline.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PipelineLayoutReflection {
+    /// Bindings of every set, sorted by set and binding.
+    pub bindings: Vec<DescriptorBinding>,
+    pub push_constant_ranges: Vec<vk::PushConstantRange>,
+}
+
+impl PipelineLayoutReflection {
+    /// Merges the reflections of the pipeline's stages. A binding or push
+    /// constant range used by several stages is visible to all of them;
+    /// declaring a binding with different descriptor types is an error.
+    pub fn merge(stages: &[&ShaderReflection]) -> Result<Self, ReflectError> {
+        let mut layout = Self::default();
+        for stage in stages {
+            for binding in &stage.bindings {
+                match layout.bindings.iter_mut().find(|b| (b.set, b.binding) == (binding.set, binding.binding)) {
+                    Some(existing) if existing.descriptor_type != binding.descriptor_type => {
+                        return Err(ReflectError::BindingConflict {
+                            set: binding.set,
+                            binding: binding.binding,
+                            a: existing.descriptor_type,
+                            b: binding.descriptor_type,
+                        });
+                    }
+                    Some(existing) => {
+                        existing.stages |= binding.stages;
+                        existing.count = existing.count.max(binding.count);
+                    }
+                    None => layout.bindings.push(binding.clone()),
+                }
+            }
+            if let Some(range) = stage.push_constants {
+                let same = |r: &&mut vk::PushConstantRange| (r.offset, r.size) == (range.offset, range.size);
+                match layout.push_constant_ranges.iter_mut().find(same) {
+                    Some(existing) => existing.stage_flags |= range.stage_flags,
+                    None => layout.push_constant_ranges.push(range),
+                }
+            }
+        }
+        layout.bindings.sort_by_key(|b| (b.set, b.binding));
+        Ok(layout)
+    }
+
+    /// The `vk::DescriptorSetLayoutBinding`s of every set from 0 up to the
+    /// highest one used; sets in between are empty.
+    pub fn set_layout_bindings(&self) -> Vec<Vec<vk::DescriptorSetLayoutBinding>> {
+        let set_count = self.bindings.iter().map(|b| b.set + 1).max().unwrap_or(0);
+        (0..set_count)
+            .map(|set| {
+                self.bindings
+                    .iter()
+                    .filter(|b| b.set == set)
+                    .map(|b| {
+                        vk::DescriptorSetLayoutBinding::builder()
+                            .binding(b.binding)
+                            .descriptor_type(b.descriptor_type)
+                            .descriptor_count(b.count)
+                            .stage_flags(b.stages)
+                            .build()
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// A disagreement between the vertex shader's inputs and the supplied
+/// vertex attribute descriptions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VertexInputMismatch {
+    /// The shader reads a location no attribute provides.
+    Missing { location: u32, name: String, format: vk::Format },
+    /// The attribute's format has a different numeric type than the shader input.
+    Format { location: u32, name: String, expected: vk::Format, actual: vk::Format },
+    /// An attribute is provided for a location the shader does not read.
+    Unused { location: u32 },
+}
+
+impl VertexInputMismatch {
+    /// Whether the mismatch makes the pipeline invalid rather than wasteful.
+    pub fn is_error(&self) -> bool {
+        !matches!(self, Self::Unused { .. })
+    }
+}
+
+impl fmt::Display for VertexInputMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Missing { location, name, format } => {
+                write!(f, "input `{}` at location {} ({:?}) has no vertex attribute", name, location, format)
+            }
+            Self::Format { location, name, expected, actual } => write!(
+                f,
+                "input `{}` at location {} is {:?} but its vertex attribute is {:?}",
+                name, location, expected, actual,
+            ),
+            Self::Unused { location } => write!(f, "vertex attribute at location {} is not used", location),
+        }
+    }
+}
+
+/// Compares the vertex shader inputs with the supplied attributes.
+pub fn check_vertex_input(
+    inputs: &[VertexInput],
+    attributes: &[vk::VertexInputAttributeDescription],
+) -> Vec<VertexInputMismatch> {
+    let mut mismatches = vec![];
+    for input in inputs {
+        match attributes.iter().find(|a| a.location == input.location) {
+            None => mismatches.push(VertexInputMismatch::Missing {
+                location: input.location,
+                name: input.name.clone(),
+                format: input.format,
+            }),
+            Some(attribute) if !format_compatible(input.format, attribute.format) => {
+                mismatches.push(VertexInputMismatch::Format {
+                    location: input.location,
+                    name: input.name.clone(),
+                    expected: input.format,
+                    actual: attribute.format,
+                });
+            }
+            Some(_) => {}
+        }
+    }
+    for attribute in attributes {
+        if !inputs.iter().any(|i| i.location == attribute.location) {
+            mismatches.push(VertexInputMismatch::Unused { location: attribute.location });
+        }
+    }
+    mismatches
+}
+
+/// The numeric type and component count of a vertex attribute format.
+fn format_class(format: vk::Format) -> Option<(char, u32)> {
+    Some(match format {
+        vk::Format::R32_SFLOAT => ('f', 1),
+        vk::Format::R32G32_SFLOAT => ('f', 2),
+        vk::Format::R32G32B32_SFLOAT => ('f', 3),
+        vk::Format::R32G32B32A32_SFLOAT => ('f', 4),
+        vk::Format::R64_SFLOAT => ('d', 1),
+        vk::Format::R64G64_SFLOAT => ('d', 2),
+        vk::Format::R64G64B64_SFLOAT => ('d', 3),
+        vk::Format::R64G64B64A64_SFLOAT => ('d', 4),
+        vk::Format::R32_SINT => ('i', 1),
+        vk::Format::R32G32_SINT => ('i', 2),
+        vk::Format::R32G32B32_SINT => ('i', 3),
+        vk::Format::R32G32B32A32_SINT => ('i', 4),
+        vk::Format::R32_UINT => ('u', 1),
+        vk::Format::R32G32_UINT => ('u', 2),
+        vk::Format::R32G32B32_UINT => ('u', 3),
+        vk::Format::R32G32B32A32_UINT => ('u', 4),
+        vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SNORM | vk::Format::B8G8R8A8_UNORM => ('f', 4),
+        vk::Format::R16G16_SFLOAT | vk::Format::R16G16_UNORM | vk::Format::R16G16_SNORM => ('f', 2),
+        vk::Format::R16G16B16A16_SFLOAT | vk::Format::R16G16B16A16_UNORM | vk::Format::R16G16B16A16_SNORM => ('f', 4),
+        _ => return None,
+    })
+}
+
+/// Whether an attribute of format `actual` can feed a shader input of
+/// format `expected`. Only the numeric type has to agree since the pipeline
+/// fills in missing components and drops extra ones; unknown formats are
+/// assumed compatible.
+fn format_compatible(expected: vk::Format, actual: vk::Format) -> bool {
+    match (format_class(expected), format_class(actual)) {
+        (Some((expected, _)), Some((actual, _))) => expected == actual,
+        _ => true,
+    }
+}
+
+/// Attribute descriptions for a tightly packed, interleaved vertex holding
+/// every shader input in location order, and the resulting stride.
+pub fn packed_vertex_attributes(inputs: &[VertexInput], binding: u32) -> (Vec<vk::VertexInputAttributeDescription>, u32) {
+    let mut offset = 0;
+    let attributes = inputs
+        .iter()
+        .map(|input| {
+            let attribute = vk::VertexInputAttributeDescription::builder()
+                .binding(binding)
+                .location(input.location)
+                .format(input.format)
+                .offset(offset)
+                .build();
+            let (class, components) = format_class(input.format).unwrap_or(('f', 4));
+            offset += components * if class == 'd' { 8 } else { 4 };
+            attribute
+        })
+        .collect();
+    (attributes, offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::mem::size_of;
+    use std::path::Path;
+
+    use super::*;
+    use crate::model::Vertex;
+    use crate::shader_compiler::compile;
+
+    /// A lit vertex shader with a camera uniform, a model push constant and
+    /// position, normal, uv and color inputs.
+    const LIT_VERT: &str = "#version 450
+        layout(set = 0, binding = 0) uniform Camera { mat4 view; mat4 proj; vec4 eye; } camera;
+        layout(push_constant) uniform Push { mat4 model; } push;
+        layout(location = 0) in vec3 inPosition;
+        layout(location = 1) in vec3 inNormal;
+        layout(location = 2) in vec2 inUv;
+        layout(location = 3) in vec3 inColor;
+        layout(location = 0) out vec3 fragColor;
+        void main() {
+            gl_Position = camera.proj * camera.view * push.model * vec4(inPosition, 1.0);
+            fragColor = inColor * (inNormal + vec3(inUv, 1.0));
+        }
+    ";
+
+    /// A textured fragment shader with a separate texture and sampler.
+    const LIT_FRAG: &str = "#version 450
+        layout(set = 0, binding = 1) uniform texture2D diffuseTexture;
+        layout(set = 0, binding = 2) uniform sampler diffuseSampler;
+        layout(location = 0) in vec3 fragColor;
+        layout(location = 0) out vec4 outColor;
+        void main() {
+            outColor = texture(sampler2D(diffuseTexture, diffuseSampler), fragColor.xy);
+        }
+    ";
+
+    /// Vertex attributes matching the inputs of [`LIT_VERT`].
+    fn lit_attributes() -> Vec<vk::VertexInputAttributeDescription> {
+        let formats = [
+            (vk::Format::R32G32B32_SFLOAT, 0),
+            (vk::Format::R32G32B32_SFLOAT, 12),
+            (vk::Format::R32G32_SFLOAT, 24),
+            (vk::Format::R32G32B32_SFLOAT, 32),
+        ];
+        formats
+            .iter()
+            .enumerate()
+            .map(|(location, (format, offset))| vk::VertexInputAttributeDescription {
+                location: location as u32,
+                binding: 0,
+                format: *format,
+                offset: *offset,
+            })
+            .collect()
+    }
+
+    /// Compiles `shaders/<name>` with naga and reflects it.
+    fn reflect_shader(name: &str) -> ShaderReflection {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("shaders").join(name);
+        reflect_words(name, compile(&path).unwrap())
+    }
+
+    /// Compiles a GLSL `source` for the stage of `name`'s extension.
+    fn reflect_source(name: &str, source: &str) -> ShaderReflection {
+        let path = std::env::temp_dir().join(format!("vulkan-works-reflect-{}-{}", std::process::id(), name));
+        fs::write(&path, source).unwrap();
+        let words = compile(&path);
+        fs::remove_file(&path).unwrap();
+        reflect_words(name, words.unwrap())
+    }
+
+    fn reflect_words(name: &str, words: Vec<u32>) -> ShaderReflection {
+        reflect(&Spirv { name: name.into(), words }).unwrap()
+    }
+
+    /// Set, binding, type, count and stages of a binding.
+    type Binding = (u32, u32, vk::DescriptorType, u32, vk::ShaderStageFlags);
+
+    fn binding(binding: u32, descriptor_type: vk::DescriptorType, stages: vk::ShaderStageFlags) -> Binding {
+        (0, binding, descriptor_type, 1, stages)
+    }
+
+    fn bindings(reflection: &[DescriptorBinding]) -> Vec<Binding> {
+        reflection.iter().map(|b| (b.set, b.binding, b.descriptor_type, b.count, b.stages)).collect()
+    }
+
+    #[test]
+    fn lit_vertex_shader() {
+        let vert = reflect_source("lit.vert", LIT_VERT);
+        assert_eq!(vert.stage, vk::ShaderStageFlags::VERTEX);
+        assert_eq!(
+            bindings(&vert.bindings),
+            [binding(0, vk::DescriptorType::UNIFORM_BUFFER, vk::ShaderStageFlags::VERTEX)],
+        );
+        let formats = vert.inputs.iter().map(|i| (i.location, i.format)).collect::<Vec<_>>();
+        assert_eq!(
+            formats,
+            [
+                (0, vk::Format::R32G32B32_SFLOAT),
+                (1, vk::Format::R32G32B32_SFLOAT),
+                (2, vk::Format::R32G32_SFLOAT),
+                (3, vk::Format::R32G32B32_SFLOAT),
+            ],
+        );
+        assert_eq!(
+            vert.push_constants,
+            Some(vk::PushConstantRange { stage_flags: vk::ShaderStageFlags::VERTEX, offset: 0, size: 64 }),
+        );
+    }
+
+    #[test]
+    fn lit_fragment_shader() {
+        let frag = reflect_source("lit.frag", LIT_FRAG);
+        assert_eq!(frag.stage, vk::ShaderStageFlags::FRAGMENT);
+        assert_eq!(
+            bindings(&frag.bindings),
+            [
+                binding(1, vk::DescriptorType::SAMPLED_IMAGE, vk::ShaderStageFlags::FRAGMENT),
+                binding(2, vk::DescriptorType::SAMPLER, vk::ShaderStageFlags::FRAGMENT),
+            ],
+        );
+        assert!(frag.inputs.is_empty());
+        assert_eq!(frag.push_constants, None);
+    }
+
+    #[test]
+    fn triangle_shaders_declare_nothing() {
+        let vert = reflect_shader("triangle.vert");
+        let frag = reflect_shader("triangle.frag");
+        let layout = PipelineLayoutReflection::merge(&[&vert, &frag]).unwrap();
+        assert_eq!(layout, PipelineLayoutReflection::default());
+        assert!(vert.inputs.is_empty());
+    }
+
+    #[test]
+    fn vertex_input_matches_packed_attributes() {
+        let vert = reflect_source("lit.vert", LIT_VERT);
+        assert_eq!(check_vertex_input(&vert.inputs, &lit_attributes()), []);
+        let (attributes, stride) = packed_vertex_attributes(&vert.inputs, 0);
+        assert_eq!(stride, 44);
+        assert_eq!(attributes.iter().map(|a| a.offset).collect::<Vec<_>>(), [0, 12, 24, 32]);
+    }
+
+    #[test]
+    fn mesh_vertex_input_matches_vertex() {
+        let vert = reflect_shader("mesh.vert");
+        assert_eq!(check_vertex_input(&vert.inputs, &Vertex::attribute_descriptions()), []);
+        assert_eq!(packed_vertex_attributes(&vert.inputs, 0).1, size_of::<Vertex>() as u32);
+    }
+
+    #[test]
+    fn vertex_input_mismatches() {
+        let vert = reflect_source("lit.vert", LIT_VERT);
+        let mut attributes = lit_attributes();
+        attributes[2].format = vk::Format::R32G32_SINT;
+        attributes.remove(3);
+        attributes.push(vk::VertexInputAttributeDescription { location: 7, ..attributes[0] });
+        let mismatches = check_vertex_input(&vert.inputs, &attributes);
+        assert!(matches!(mismatches[0], VertexInputMismatch::Format { location: 2, .. }));
+        assert!(matches!(mismatches[1], VertexInputMismatch::Missing { location: 3, .. }));
+        assert_eq!(mismatches[2], VertexInputMismatch::Unused { location: 7 });
+    }
+
+    #[test]
+    fn lit_pipeline_layout() {
+        let (vert, frag) = (reflect_source("lit.vert", LIT_VERT), reflect_source("lit.frag", LIT_FRAG));
+        let layout = PipelineLayoutReflection::merge(&[&vert, &frag]).unwrap();
+        let sets = layout.set_layout_bindings();
+        assert_eq!(sets.len(), 1);
+        assert_eq!(sets[0].iter().map(|b| b.binding).collect::<Vec<_>>(), [0, 1, 2]);
+        assert_eq!(layout.push_constant_ranges.len(), 1);
+    }
+
+    #[test]
+    fn shared_bindings_and_push_constants_are_merged() {
+        let common = "
+            layout(set = 0, binding = 0) uniform Globals { vec4 tint; } globals;
+            layout(set = 1, binding = 3) readonly buffer Data { vec4 values[]; } data;
+            layout(push_constant) uniform Push { mat4 model; vec4 color; } push;
+        ";
+        let vert = reflect_source(
+            "shared.vert",
+            &format!("#version 450\n{}\nvoid main() {{ gl_Position = push.model * globals.tint; }}\n", common),
+        );
+        let frag = reflect_source(
+            "shared.frag",
+            &format!(
+                "#version 450\n{}\nlayout(location = 0) out vec4 color;\n\
+                 void main() {{ color = push.color * globals.tint * data.values[0]; }}\n",
+                common,
+            ),
+        );
+        let layout = PipelineLayoutReflection::merge(&[&vert, &frag]).unwrap();
+        let all = vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT;
+        assert_eq!(
+            layout.push_constant_ranges,
+            [vk::PushConstantRange { stage_flags: all, offset: 0, size: 80 }],
+        );
+        assert_eq!(layout.bindings[0].stages, all);
+        assert_eq!(layout.bindings[1].descriptor_type, vk::DescriptorType::STORAGE_BUFFER);
+        assert_eq!(layout.bindings[1].stages, all);
+        assert_eq!(layout.set_layout_bindings().len(), 2);
+    }
+
+    #[test]
+    fn conflicting_bindings() {
+        let vert = reflect_source("lit.vert", LIT_VERT);
+        let mut frag = reflect_source("lit.frag", LIT_FRAG);
+        frag.bindings[0].binding = 0;
+        assert!(matches!(
+            PipelineLayoutReflection::merge(&[&vert, &frag]),
+            Err(ReflectError::BindingConflict { set: 0, binding: 0, .. }),
+        ));
+    }
+
+    #[test]
+    fn malformed_modules() {
+        let header = vec![crate::shader::SPIRV_MAGIC, 0x10000, 0, 8, 0];
+        // An instruction claiming to be longer than the module
+        let spirv = Spirv { name: "bad".into(), words: [header.clone(), vec![0xffff_0001]].concat() };
+        assert!(matches!(reflect(&spirv), Err(ReflectError::Malformed(..))));
+        let spirv = Spirv { name: "empty".into(), words: header };
+        assert_eq!(reflect(&spirv), Err(ReflectError::NoEntryPoint("empty".into())));
+    }
+}
@@ -0,0 +1,174 @@
+use std::mem::size_of;
+use std::ptr::copy_nonoverlapping as memcpy;
+
+use anyhow::{anyhow, Result};
+use log::*;
+use nalgebra_glm as glm;
+
+use vulkanalia::prelude::v1_0::*;
+
+use crate::args::Args;
+use crate::camera::{Camera, CameraUniform};
+use crate::memory::{create_buffer, destroy_buffer, AllocationRequest};
+use crate::model::{create_model, destroy_model, load_obj};
+use crate::pipeline::{add_pipeline, create_descriptor_set_layouts, mesh_pipeline_desc};
+use crate::reflect::DescriptorBinding;
+use crate::texture::{create_sampler, create_texture, destroy_texture, load_texture, TextureData};
+use crate::upload::wait;
+use crate::AppData;
+
+/// Loads the model and texture named by `args` and creates everything needed
+/// to draw them, framing the model with the camera. Without a model only the
+/// camera is set up.
+pub unsafe fn load_scene(instance: &Instance, device: &Device, data: &mut AppData, args: &Args) -> Result<()> {
+    data.camera = Camera::framing(args.camera, args.projection, &glm::Vec3::zeros(), 1.0);
+    let Some(path) = &args.model else {
+        return Ok(());
+    };
+
+    let model = load_obj(path, args.normals)?;
+    for (mesh, material) in model.material_assignments() {
+        info!("Mesh `{}` uses material `{}`.", mesh, material.unwrap_or("<none>"));
+    }
+    if let Some((center, radius)) = model.bounding_sphere() {
+        data.camera = Camera::framing(args.camera, args.projection, &center, radius);
+    }
+
+    let texture_path = args.texture.clone().or_else(|| {
+        let dir = path.parent().unwrap_or(path);
+        model.materials.iter().find_map(|m| m.diffuse_texture.as_ref()).map(|t| dir.join(t))
+    });
+    let texture = match &texture_path {
+        Some(path) => load_texture(path)?,
+        None => TextureData { width: 1, height: 1, pixels: vec![255; 4] },
+    };
+    let texture = create_texture(instance, device, data, &texture)?;
+    data.texture = Some(texture);
+    data.sampler = create_sampler(device, data, texture.mip_levels, args.anisotropy)?;
+
+    let model = create_model(device, data, &model)?;
+    let ticket = model.ticket.max(texture.ticket);
+    data.model = Some(model);
+    wait(device, data, ticket)?;
+
+    let frames = data.in_flight_fences.len().max(1);
+    create_scene_resources(device, data, frames)?;
+    data.draw_triangle = false;
+    Ok(())
+}
+
+pub unsafe fn destroy_scene(device: &Device, data: &mut AppData) {
+    destroy_scene_resources(device, data);
+    if let Some(model) = data.model.take() {
+        destroy_model(device, data, &model);
+    }
+    if let Some(texture) = data.texture.take() {
+        destroy_texture(device, data, &texture);
+    }
+    device.destroy_sampler(data.sampler, None);
+    data.sampler = vk::Sampler::null();
+}
+
+/// Creates the mesh pipeline together with one camera uniform buffer and one
+/// descriptor set per frame in flight. The set layouts are generated from
+/// the mesh shaders and outlive rebuilds of the pipeline; their bindings are
+/// filled by descriptor type with the camera buffer, `data.texture` and
+/// `data.sampler`.
+unsafe fn create_scene_resources(device: &Device, data: &mut AppData, frames: usize) -> Result<()> {
+    let (set_layouts, reflection) = create_descriptor_set_layouts(device, &mesh_pipeline_desc(vec![])?)?;
+    data.descriptor_set_layouts = set_layouts;
+    data.mesh_pipeline = add_pipeline(device, data, mesh_pipeline_desc(data.descriptor_set_layouts.clone())?)?;
+
+    for _ in 0..frames {
+        let (buffer, allocation) = create_buffer(
+            device,
+            data,
+            size_of::<CameraUniform>() as vk::DeviceSize,
+            vk::BufferUsageFlags::UNIFORM_BUFFER,
+            AllocationRequest::host_visible(),
+        )?;
+        data.uniform_buffers.push(buffer);
+        data.uniform_allocations.push(allocation);
+    }
+
+    // Only set 0 is used; the mesh shaders declare no others.
+    let bindings = reflection.bindings.iter().filter(|b| b.set == 0).collect::<Vec<_>>();
+    let pool_sizes = bindings
+        .iter()
+        .map(|b| {
+            vk::DescriptorPoolSize::builder()
+                .type_(b.descriptor_type)
+                .descriptor_count(b.count * frames as u32)
+                .build()
+        })
+        .collect::<Vec<_>>();
+    let info = vk::DescriptorPoolCreateInfo::builder()
+        .pool_sizes(&pool_sizes)
+        .max_sets(frames as u32);
+    data.descriptor_pool = device.create_descriptor_pool(&info, None)?;
+
+    let set_layouts = vec![data.descriptor_set_layouts[0]; frames];
+    let info = vk::DescriptorSetAllocateInfo::builder()
+        .descriptor_pool(data.descriptor_pool)
+        .set_layouts(&set_layouts);
+    data.descriptor_sets = device.allocate_descriptor_sets(&info)?;
+
+    for frame in 0..frames {
+        write_descriptor_set(device, data, frame, &bindings)?;
+    }
+    Ok(())
+}
+
+unsafe fn write_descriptor_set(device: &Device, data: &AppData, frame: usize, bindings: &[&DescriptorBinding]) -> Result<()> {
+    let texture = data.texture.as_ref().ok_or_else(|| anyhow!("No texture to bind."))?;
+    let buffer_info = [vk::DescriptorBufferInfo::builder()
+        .buffer(data.uniform_buffers[frame])
+        .offset(0)
+        .range(size_of::<CameraUniform>() as vk::DeviceSize)
+        .build()];
+    let image_info = [vk::DescriptorImageInfo::builder()
+        .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+        .image_view(texture.view)
+        .sampler(data.sampler)
+        .build()];
+
+    let mut writes = vec![];
+    for binding in bindings {
+        let write = vk::WriteDescriptorSet::builder()
+            .dst_set(data.descriptor_sets[frame])
+            .dst_binding(binding.binding)
+            .dst_array_element(0)
+            .descriptor_type(binding.descriptor_type);
+        let write = match binding.descriptor_type {
+            vk::DescriptorType::UNIFORM_BUFFER => write.buffer_info(&buffer_info),
+            vk::DescriptorType::SAMPLED_IMAGE
+            | vk::DescriptorType::SAMPLER
+            | vk::DescriptorType::COMBINED_IMAGE_SAMPLER => write.image_info(&image_info),
+            other => return Err(anyhow!("Cannot bind `{}` of type {:?}.", binding.name, other)),
+        };
+        writes.push(write);
+    }
+    device.update_descriptor_sets(&writes, &[] as &[vk::CopyDescriptorSet]);
+    Ok(())
+}
+
+/// Writes the camera of `data` into the uniform buffer of `frame`.
+pub unsafe fn update_uniform_buffer(data: &AppData, frame: usize) -> Result<()> {
+    let uniform = data.camera.uniform(data.target_extent.width, data.target_extent.height);
+    let mapped = data.uniform_allocations[frame]
+        .mapped
+        .ok_or_else(|| anyhow!("Uniform buffer is not mapped."))?;
+    memcpy(&uniform, mapped.as_ptr().cast(), 1);
+    Ok(())
+}
+
+unsafe fn destroy_scene_resources(device: &Device, data: &mut AppData) {
+    device.destroy_descriptor_pool(data.descriptor_pool, None);
+    data.descriptor_sets.clear();
+    data.descriptor_set_layouts.drain(..).for_each(|l| device.destroy_descriptor_set_layout(l, None));
+    let buffers = data.uniform_buffers.drain(..).collect::<Vec<_>>();
+    let allocations = data.uniform_allocations.drain(..).collect::<Vec<_>>();
+    for (buffer, allocation) in buffers.into_iter().zip(&allocations) {
+        destroy_buffer(device, data, buffer, allocation);
+    }
+}
@@ -0,0 +1,135 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use vulkanalia::prelude::v1_0::*;
+
+/// The first word of every SPIR-V module.
+pub const SPIRV_MAGIC: u32 = 0x0723_0203;
+
+/// Number of words in the SPIR-V module header.
+const SPIRV_HEADER_WORDS: usize = 5;
+
+// Generated by `build.rs` from the sources under `shaders/`.
+include!(concat!(env!("OUT_DIR"), "/shaders.rs"));
+
+#[derive(Debug, Error)]
+pub enum ShaderError {
+    #[error("Failed to read shader `{}`: {1}", .0.display())]
+    Io(PathBuf, #[source] std::io::Error),
+    #[error("Shader `{0}` is {1} bytes long, which is not a multiple of 4.")]
+    Misaligned(String, usize),
+    #[error("Shader `{0}` is too short to hold a SPIR-V header.")]
+    Truncated(String),
+    #[error("Shader `{0}` does not start with the SPIR-V magic number (found {1:#010x}).")]
+    BadMagic(String, u32),
+    #[error("No shader `{0}` was compiled into the binary.")]
+    Missing(String),
+    #[error("Failed to create shader module for `{0}`: {1}")]
+    Module(String, vk::ErrorCode),
+}
+
+/// A validated SPIR-V module together with where it came from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Spirv {
+    /// The file path or label used in error messages.
+    pub name: String,
+    /// The module in native endianness.
+    pub words: Vec<u32>,
+}
+
+impl Spirv {
+    /// Reads and validates a SPIR-V file.
+    pub fn load(path: &Path) -> Result<Self, ShaderError> {
+        let bytes = fs::read(path).map_err(|e| ShaderError::Io(path.into(), e))?;
+        Self::from_bytes(&path.display().to_string(), &bytes)
+    }
+
+    /// Looks up a shader compiled from `shaders/<name>` at build time.
+    pub fn embedded(name: &str) -> Result<Self, ShaderError> {
+        let (_, bytes) = EMBEDDED_SHADERS
+            .iter()
+            .find(|(n, _)| *n == name)
+            .ok_or_else(|| ShaderError::Missing(name.into()))?;
+        Self::from_bytes(name, bytes)
+    }
+
+    /// Validates SPIR-V bytes, e.g. from `include_bytes!`. The bytes need
+    /// not be 4-byte aligned; they are copied into words, swapping the byte
+    /// order if the module was written with the opposite endianness.
+    pub fn from_bytes(name: &str, bytes: &[u8]) -> Result<Self, ShaderError> {
+        if bytes.len() % 4 != 0 {
+            return Err(ShaderError::Misaligned(name.into(), bytes.len()));
+        }
+        if bytes.len() < SPIRV_HEADER_WORDS * 4 {
+            return Err(ShaderError::Truncated(name.into()));
+        }
+
+        let mut words = bytes
+            .chunks_exact(4)
+            .map(|w| u32::from_ne_bytes([w[0], w[1], w[2], w[3]]))
+            .collect::<Vec<_>>();
+        if words[0] == SPIRV_MAGIC.swap_bytes() {
+            words.iter_mut().for_each(|w| *w = w.swap_bytes());
+        }
+        if words[0] != SPIRV_MAGIC {
+            return Err(ShaderError::BadMagic(name.into(), words[0]));
+        }
+
+        Ok(Self { name: name.into(), words })
+    }
+}
+
+pub unsafe fn create_shader_module(device: &Device, spirv: &Spirv) -> Result<vk::ShaderModule, ShaderError> {
+    let info = vk::ShaderModuleCreateInfo::builder()
+        .code_size(spirv.words.len() * 4)
+        .code(&spirv.words);
+    device
+        .create_shader_module(&info, None)
+        .map_err(|e| ShaderError::Module(spirv.name.clone(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(magic: u32) -> Vec<u32> {
+        vec![magic, 0x0001_0000, 0, 8, 0]
+    }
+
+    fn bytes(words: &[u32], to_bytes: fn(u32) -> [u8; 4]) -> Vec<u8> {
+        words.iter().flat_map(|w| to_bytes(*w)).collect()
+    }
+
+    #[test]
+    fn native_modules() {
+        let spirv = Spirv::from_bytes("a.spv", &bytes(&header(SPIRV_MAGIC), u32::to_ne_bytes)).unwrap();
+        assert_eq!(spirv.words, header(SPIRV_MAGIC));
+        assert_eq!(spirv.name, "a.spv");
+    }
+
+    #[test]
+    fn swapped_modules() {
+        let swapped = |w: u32| w.swap_bytes().to_ne_bytes();
+        let spirv = Spirv::from_bytes("a.spv", &bytes(&header(SPIRV_MAGIC), swapped)).unwrap();
+        assert_eq!(spirv.words, header(SPIRV_MAGIC));
+    }
+
+    #[test]
+    fn invalid_modules() {
+        let valid = bytes(&header(SPIRV_MAGIC), u32::to_ne_bytes);
+        assert!(matches!(Spirv::from_bytes("a", &valid[..19]), Err(ShaderError::Misaligned(_, 19))));
+        assert!(matches!(Spirv::from_bytes("a", &valid[..16]), Err(ShaderError::Truncated(_))));
+        let bad = bytes(&header(0xdead_beef), u32::to_ne_bytes);
+        assert!(matches!(Spirv::from_bytes("a", &bad), Err(ShaderError::BadMagic(_, 0xdead_beef))));
+    }
+
+    #[test]
+    fn embedded_shaders() {
+        for name in ["triangle.vert", "triangle.frag", "mesh.vert", "mesh.frag"] {
+            assert_eq!(Spirv::embedded(name).unwrap().words[0], SPIRV_MAGIC);
+        }
+        assert!(matches!(Spirv::embedded("missing.frag"), Err(ShaderError::Missing(_))));
+    }
+}
@@ -0,0 +1,139 @@
+//! Compiles shader sources to SPIR-V. Used by `build.rs` (through `#[path]`),
+//! so it only depends on `std` and `naga`.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// GLSL stages compiled in-process with naga, by file extension.
+const GLSL_STAGES: &[(&str, naga::ShaderStage)] = &[
+    ("vert", naga::ShaderStage::Vertex),
+    ("frag", naga::ShaderStage::Fragment),
+    ("comp", naga::ShaderStage::Compute),
+];
+
+/// Stages of HLSL sources (`<name>.<stage>.hlsl`), which are compiled with `glslc`.
+const HLSL_STAGES: &[&str] = &["vert", "frag", "comp", "geom", "tesc", "tese"];
+
+/// Whether `path` looks like a shader source this module can compile.
+pub fn is_shader_source(path: &Path) -> bool {
+    hlsl_stage(path).is_some() || glsl_stage(path).is_some()
+}
+
+/// Compiles the GLSL (`.vert`, `.frag`, `.comp`) or HLSL (`.<stage>.hlsl`)
+/// shader at `path`. Errors hold the compiler's diagnostics.
+pub fn compile(path: &Path) -> Result<Vec<u32>, String> {
+    if let Some(stage) = hlsl_stage(path) {
+        compile_hlsl(path, stage)
+    } else if let Some(stage) = glsl_stage(path) {
+        compile_glsl(path, stage)
+    } else {
+        Err(format!("{}: unknown shader type", path.display()))
+    }
+}
+
+fn glsl_stage(path: &Path) -> Option<naga::ShaderStage> {
+    let extension = path.extension()?.to_str()?;
+    GLSL_STAGES.iter().find(|(e, _)| *e == extension).map(|(_, s)| *s)
+}
+
+fn hlsl_stage(path: &Path) -> Option<&'static str> {
+    if path.extension()? != "hlsl" {
+        return None;
+    }
+    let stage = Path::new(path.file_stem()?).extension()?.to_str()?;
+    HLSL_STAGES.iter().find(|s| **s == stage).copied()
+}
+
+fn compile_glsl(path: &Path, stage: naga::ShaderStage) -> Result<Vec<u32>, String> {
+    let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    let options = naga::front::glsl::Options::from(stage);
+    let module = naga::front::glsl::Frontend::default()
+        .parse(&options, &source)
+        .map_err(|errors| {
+            errors
+                .iter()
+                .map(|e| {
+                    let location = e.meta.location(&source);
+                    format!("{}:{}:{}: {}", path.display(), location.line_number, location.line_position, e.kind)
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        })?;
+
+    let info = naga::valid::Validator::new(naga::valid::ValidationFlags::all(), naga::valid::Capabilities::all())
+        .validate(&module)
+        .map_err(|e| e.emit_to_string_with_path(&source, &path.display().to_string()))?;
+
+    naga::back::spv::write_vec(&module, &info, &naga::back::spv::Options::default(), None)
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn compile_hlsl(path: &Path, stage: &str) -> Result<Vec<u32>, String> {
+    let output = env::temp_dir().join(format!(
+        "vulkan-works-{}-{}.spv",
+        std::process::id(),
+        path.file_name().and_then(|n| n.to_str()).unwrap_or("shader"),
+    ));
+    let result = Command::new(glslc())
+        .arg("-x")
+        .arg("hlsl")
+        .arg(format!("-fshader-stage={}", stage))
+        .arg(path)
+        .arg("-o")
+        .arg(&output)
+        .output();
+
+    let result = match result {
+        Ok(result) if result.status.success() => fs::read(&output)
+            .map_err(|e| format!("{}: {}", output.display(), e))
+            .and_then(|bytes| {
+                if bytes.len() % 4 == 0 {
+                    Ok(bytes.chunks_exact(4).map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]])).collect())
+                } else {
+                    Err(format!("{}: glslc produced a truncated module", path.display()))
+                }
+            }),
+        Ok(result) => Err(String::from_utf8_lossy(&result.stderr).into_owned()),
+        Err(e) => Err(format!(
+            "{}: failed to run `glslc` ({}); HLSL shaders need it on PATH, in $VULKAN_SDK/bin or set via $GLSLC",
+            path.display(),
+            e,
+        )),
+    };
+    let _ = fs::remove_file(&output);
+    result
+}
+
+/// The `glslc` executable: `$GLSLC`, then `$VULKAN_SDK/bin/glslc`, then `PATH`.
+fn glslc() -> PathBuf {
+    if let Some(glslc) = env::var_os("GLSLC") {
+        return glslc.into();
+    }
+    if let Some(sdk) = env::var_os("VULKAN_SDK") {
+        let glslc = Path::new(&sdk).join("bin").join("glslc");
+        if glslc.exists() {
+            return glslc;
+        }
+    }
+    "glslc".into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_glsl_reports_file_and_error() {
+        let dir = env::temp_dir().join(format!("vulkan-works-compile-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("broken.frag");
+        fs::write(&path, "#version 450\nvoid main() { undefined_function(); }\n").unwrap();
+        let error = compile(&path).unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(error.contains(&path.display().to_string()), "{}", error);
+        assert!(error.contains("Unknown function 'undefined_function'"), "{}", error);
+    }
+}
@@ -0,0 +1,356 @@
+use anyhow::{anyhow, Result};
+use log::*;
+
+use winit::window::Window;
+use vulkanalia::prelude::v1_0::*;
+use vulkanalia::vk::{KhrSurfaceExtension, KhrSwapchainExtension};
+
+use crate::AppData;
+
+/// How presentation is synchronized with the display's refresh.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum VsyncPolicy {
+    /// Always `FIFO`: no tearing, frames queue behind vertical blank.
+    On,
+    /// `MAILBOX` when available (no tearing, lowest latency), else `FIFO`.
+    #[default]
+    Mailbox,
+    /// `IMMEDIATE` when available (may tear), else `MAILBOX`, else `FIFO`.
+    Off,
+}
+
+impl std::str::FromStr for VsyncPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "on" => Ok(Self::On),
+            "mailbox" => Ok(Self::Mailbox),
+            "off" => Ok(Self::Off),
+            _ => Err(anyhow!("Unknown vsync policy `{}` (expected `on`, `mailbox` or `off`).", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SwapchainSupport {
+    pub capabilities: vk::SurfaceCapabilitiesKHR,
+    pub formats: Vec<vk::SurfaceFormatKHR>,
+    pub present_modes: Vec<vk::PresentModeKHR>,
+}
+
+impl SwapchainSupport {
+    pub unsafe fn get(
+        instance: &Instance,
+        data: &AppData,
+        physical_device: vk::PhysicalDevice,
+    ) -> Result<Self> {
+        Ok(Self {
+            capabilities: instance.get_physical_device_surface_capabilities_khr(physical_device, data.surface)?,
+            formats: instance.get_physical_device_surface_formats_khr(physical_device, data.surface)?,
+            present_modes: instance.get_physical_device_surface_present_modes_khr(physical_device, data.surface)?,
+        })
+    }
+}
+
+/// Prefers `B8G8R8A8_SRGB` with the sRGB non-linear color space, falling back
+/// to the first format the surface reports.
+pub fn choose_surface_format(formats: &[vk::SurfaceFormatKHR]) -> vk::SurfaceFormatKHR {
+    formats
+        .iter()
+        .cloned()
+        .find(|f| {
+            f.format == vk::Format::B8G8R8A8_SRGB && f.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR
+        })
+        .unwrap_or_else(|| formats[0])
+}
+
+pub fn choose_present_mode(policy: VsyncPolicy, present_modes: &[vk::PresentModeKHR]) -> vk::PresentModeKHR {
+    let preferred: &[vk::PresentModeKHR] = match policy {
+        VsyncPolicy::On => &[],
+        VsyncPolicy::Mailbox => &[vk::PresentModeKHR::MAILBOX],
+        VsyncPolicy::Off => &[vk::PresentModeKHR::IMMEDIATE, vk::PresentModeKHR::MAILBOX],
+    };
+    preferred
+        .iter()
+        .cloned()
+        .find(|m| present_modes.contains(m))
+        .unwrap_or(vk::PresentModeKHR::FIFO)
+}
+
+/// Uses the surface's current extent, or the window size clamped to the
+/// supported range when the surface lets the swapchain decide.
+pub fn choose_extent(capabilities: &vk::SurfaceCapabilitiesKHR, size: (u32, u32)) -> vk::Extent2D {
+    if capabilities.current_extent.width != u32::MAX {
+        capabilities.current_extent
+    } else {
+        let (min, max) = (capabilities.min_image_extent, capabilities.max_image_extent);
+        vk::Extent2D {
+            width: size.0.clamp(min.width, max.width),
+            height: size.1.clamp(min.height, max.height),
+        }
+    }
+}
+
+pub unsafe fn create_swapchain(
+    window: &Window,
+    instance: &Instance,
+    device: &Device,
+    data: &mut AppData,
+    vsync: VsyncPolicy,
+) -> Result<()> {
+    let support = SwapchainSupport::get(instance, data, data.physical_device)?;
+
+    let surface_format = choose_surface_format(&support.formats);
+    let present_mode = choose_present_mode(vsync, &support.present_modes);
+    let size = window.inner_size();
+    let extent = choose_extent(&support.capabilities, (size.width, size.height));
+    info!("Swapchain: {:?} {:?}, {:?}, {}x{}.", surface_format.format, surface_format.color_space, present_mode, extent.width, extent.height);
+
+    let mut image_count = support.capabilities.min_image_count + 1;
+    if support.capabilities.max_image_count != 0 && image_count > support.capabilities.max_image_count {
+        image_count = support.capabilities.max_image_count;
+    }
+
+    // Frame capture copies out of swapchain images when the surface allows it.
+    let mut image_usage = vk::ImageUsageFlags::COLOR_ATTACHMENT;
+    if support.capabilities.supported_usage_flags.contains(vk::ImageUsageFlags::TRANSFER_SRC) {
+        image_usage |= vk::ImageUsageFlags::TRANSFER_SRC;
+    }
+
+    let info = vk::SwapchainCreateInfoKHR::builder()
+        .surface(data.surface)
+        .min_image_count(image_count)
+        .image_format(surface_format.format)
+        .image_color_space(surface_format.color_space)
+        .image_extent(extent)
+        .image_array_layers(1)
+        .image_usage(image_usage)
+        .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .pre_transform(support.capabilities.current_transform)
+        .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
+        .present_mode(present_mode)
+        .clipped(true)
+        .old_swapchain(vk::SwapchainKHR::null());
+
+    data.swapchain = device.create_swapchain_khr(&info, None)?;
+    data.target_images = device.get_swapchain_images_khr(data.swapchain)?;
+    data.target_format = surface_format.format;
+    data.target_extent = extent;
+    data.target_usage = image_usage;
+    Ok(())
+}
+
+pub unsafe fn create_swapchain_image_views(device: &Device, data: &mut AppData) -> Result<()> {
+    data.target_image_views = data
+        .target_images
+        .iter()
+        .map(|i| {
+            let components = vk::ComponentMapping::builder()
+                .r(vk::ComponentSwizzle::IDENTITY)
+                .g(vk::ComponentSwizzle::IDENTITY)
+                .b(vk::ComponentSwizzle::IDENTITY)
+                .a(vk::ComponentSwizzle::IDENTITY);
+            let subresource_range = vk::ImageSubresourceRange::builder()
+                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                .base_mip_level(0)
+                .level_count(1)
+                .base_array_layer(0)
+                .layer_count(1);
+            let info = vk::ImageViewCreateInfo::builder()
+                .image(*i)
+                .view_type(vk::ImageViewType::_2D)
+                .format(data.target_format)
+                .components(components)
+                .subresource_range(subresource_range);
+            device.create_image_view(&info, None)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(())
+}
+
+pub unsafe fn destroy_swapchain(device: &Device, data: &mut AppData) {
+    data.target_image_views.drain(..).for_each(|v| device.destroy_image_view(v, None));
+    data.target_images.clear();
+    device.destroy_swapchain_khr(data.swapchain, None);
+}
+
+/// Whether swapchain images have to move from the graphics to the present
+/// queue family before presentation (they are created `EXCLUSIVE`).
+pub fn needs_ownership_transfer(data: &AppData) -> bool {
+    !data.surface.is_null() && data.graphics_queue_family != data.present_queue_family
+}
+
+fn ownership_barrier(data: &AppData, image: vk::Image, src_access: vk::AccessFlags) -> vk::ImageMemoryBarrier {
+    let subresource_range = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(1)
+        .base_array_layer(0)
+        .layer_count(1);
+    vk::ImageMemoryBarrier::builder()
+        .old_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+        .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+        .src_queue_family_index(data.graphics_queue_family)
+        .dst_queue_family_index(data.present_queue_family)
+        .image(image)
+        .subresource_range(subresource_range)
+        .src_access_mask(src_access)
+        .dst_access_mask(vk::AccessFlags::empty())
+        .build()
+}
+
+/// Records the graphics queue half of the ownership transfer of `image` to
+/// the present queue family, after the render pass finished writing it.
+pub unsafe fn cmd_release_to_present(device: &Device, data: &AppData, command_buffer: vk::CommandBuffer, image: vk::Image) {
+    let barrier = ownership_barrier(data, image, vk::AccessFlags::COLOR_ATTACHMENT_WRITE);
+    device.cmd_pipeline_barrier(
+        command_buffer,
+        vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+        vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+        vk::DependencyFlags::empty(),
+        &[] as &[vk::MemoryBarrier],
+        &[] as &[vk::BufferMemoryBarrier],
+        &[barrier],
+    );
+}
+
+/// Creates the present queue half of the ownership transfer: a command buffer
+/// per swapchain image acquiring it from the graphics queue family, and the
+/// semaphores presentation waits on once it ran. Images are not transferred
+/// back since the render pass discards their previous contents.
+pub unsafe fn create_ownership_transfer(device: &Device, data: &mut AppData) -> Result<()> {
+    if !needs_ownership_transfer(data) {
+        return Ok(());
+    }
+
+    let info = vk::CommandPoolCreateInfo::builder().queue_family_index(data.present_queue_family);
+    data.present_command_pool = device.create_command_pool(&info, None)?;
+
+    let info = vk::CommandBufferAllocateInfo::builder()
+        .command_pool(data.present_command_pool)
+        .level(vk::CommandBufferLevel::PRIMARY)
+        .command_buffer_count(data.target_images.len() as u32);
+    data.present_command_buffers = device.allocate_command_buffers(&info)?;
+
+    let semaphore_info = vk::SemaphoreCreateInfo::builder();
+    for (command_buffer, image) in data.present_command_buffers.iter().zip(&data.target_images) {
+        device.begin_command_buffer(*command_buffer, &vk::CommandBufferBeginInfo::builder())?;
+        let barrier = ownership_barrier(data, *image, vk::AccessFlags::empty());
+        device.cmd_pipeline_barrier(
+            *command_buffer,
+            vk::PipelineStageFlags::TOP_OF_PIPE,
+            vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            vk::DependencyFlags::empty(),
+            &[] as &[vk::MemoryBarrier],
+            &[] as &[vk::BufferMemoryBarrier],
+            &[barrier],
+        );
+        device.end_command_buffer(*command_buffer)?;
+        data.present_ready_semaphores.push(device.create_semaphore(&semaphore_info, None)?);
+    }
+
+    Ok(())
+}
+
+pub unsafe fn destroy_ownership_transfer(device: &Device, data: &mut AppData) {
+    data.present_ready_semaphores.drain(..).for_each(|s| device.destroy_semaphore(s, None));
+    data.present_command_buffers.clear();
+    if !data.present_command_pool.is_null() {
+        device.destroy_command_pool(data.present_command_pool, None);
+        data.present_command_pool = vk::CommandPool::null();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_MODES: &[vk::PresentModeKHR] = &[
+        vk::PresentModeKHR::FIFO,
+        vk::PresentModeKHR::MAILBOX,
+        vk::PresentModeKHR::IMMEDIATE,
+    ];
+
+    fn surface_format(format: vk::Format, color_space: vk::ColorSpaceKHR) -> vk::SurfaceFormatKHR {
+        vk::SurfaceFormatKHR { format, color_space }
+    }
+
+    fn windowed(graphics_queue_family: u32, present_queue_family: u32) -> AppData {
+        AppData {
+            surface: vk::SurfaceKHR::from_raw(1),
+            graphics_queue_family,
+            present_queue_family,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn vsync_policies() {
+        assert_eq!(choose_present_mode(VsyncPolicy::On, ALL_MODES), vk::PresentModeKHR::FIFO);
+        assert_eq!(choose_present_mode(VsyncPolicy::Mailbox, ALL_MODES), vk::PresentModeKHR::MAILBOX);
+        assert_eq!(choose_present_mode(VsyncPolicy::Off, ALL_MODES), vk::PresentModeKHR::IMMEDIATE);
+    }
+
+    #[test]
+    fn vsync_fallbacks() {
+        let fifo_mailbox = &[vk::PresentModeKHR::FIFO, vk::PresentModeKHR::MAILBOX];
+        assert_eq!(choose_present_mode(VsyncPolicy::Off, fifo_mailbox), vk::PresentModeKHR::MAILBOX);
+        let fifo = &[vk::PresentModeKHR::FIFO];
+        assert_eq!(choose_present_mode(VsyncPolicy::Off, fifo), vk::PresentModeKHR::FIFO);
+        assert_eq!(choose_present_mode(VsyncPolicy::Mailbox, fifo), vk::PresentModeKHR::FIFO);
+    }
+
+    #[test]
+    fn vsync_parsing() {
+        assert_eq!("on".parse::<VsyncPolicy>().unwrap(), VsyncPolicy::On);
+        assert_eq!("off".parse::<VsyncPolicy>().unwrap(), VsyncPolicy::Off);
+        assert!("adaptive".parse::<VsyncPolicy>().is_err());
+    }
+
+    #[test]
+    fn surface_formats() {
+        let unorm = surface_format(vk::Format::B8G8R8A8_UNORM, vk::ColorSpaceKHR::SRGB_NONLINEAR);
+        let srgb = surface_format(vk::Format::B8G8R8A8_SRGB, vk::ColorSpaceKHR::SRGB_NONLINEAR);
+        assert_eq!(choose_surface_format(&[unorm, srgb]), srgb);
+        assert_eq!(choose_surface_format(&[unorm]), unorm);
+    }
+
+    #[test]
+    fn extents() {
+        let mut capabilities = vk::SurfaceCapabilitiesKHR {
+            current_extent: vk::Extent2D { width: 800, height: 600 },
+            min_image_extent: vk::Extent2D { width: 16, height: 16 },
+            max_image_extent: vk::Extent2D { width: 4096, height: 2048 },
+            ..Default::default()
+        };
+        assert_eq!(choose_extent(&capabilities, (1024, 768)), vk::Extent2D { width: 800, height: 600 });
+        capabilities.current_extent = vk::Extent2D { width: u32::MAX, height: u32::MAX };
+        assert_eq!(choose_extent(&capabilities, (1024, 768)), vk::Extent2D { width: 1024, height: 768 });
+        assert_eq!(choose_extent(&capabilities, (8, 8192)), vk::Extent2D { width: 16, height: 2048 });
+    }
+
+    #[test]
+    fn ownership_transfer_only_between_different_families() {
+        assert!(needs_ownership_transfer(&windowed(0, 1)));
+        assert!(!needs_ownership_transfer(&windowed(1, 1)));
+        assert!(!needs_ownership_transfer(&AppData { present_queue_family: 1, ..Default::default() }));
+    }
+
+    #[test]
+    fn release_and_acquire_barriers() {
+        let data = windowed(0, 2);
+        let image = vk::Image::from_raw(7);
+        let release = ownership_barrier(&data, image, vk::AccessFlags::COLOR_ATTACHMENT_WRITE);
+        let acquire = ownership_barrier(&data, image, vk::AccessFlags::empty());
+        for barrier in [release, acquire] {
+            assert_eq!((barrier.src_queue_family_index, barrier.dst_queue_family_index), (0, 2));
+            assert_eq!(barrier.old_layout, vk::ImageLayout::PRESENT_SRC_KHR);
+            assert_eq!(barrier.new_layout, vk::ImageLayout::PRESENT_SRC_KHR);
+            assert_eq!(barrier.image, image);
+            assert_eq!(barrier.dst_access_mask, vk::AccessFlags::empty());
+        }
+        assert_eq!(release.src_access_mask, vk::AccessFlags::COLOR_ATTACHMENT_WRITE);
+        assert_eq!(acquire.src_access_mask, vk::AccessFlags::empty());
+    }
+}
@@ -0,0 +1,100 @@
+use anyhow::{anyhow, Result};
+
+use vulkanalia::prelude::v1_0::*;
+
+use crate::commands::allocate_command_buffers;
+use crate::AppData;
+
+/// Default number of frames the CPU may record ahead of the GPU.
+pub const DEFAULT_FRAMES_IN_FLIGHT: usize = 2;
+
+/// Remembers which frame in flight last submitted work rendering to each
+/// swapchain image, so a frame reusing the image can wait for that work.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImageOwnership {
+    owners: Vec<Option<usize>>,
+}
+
+impl ImageOwnership {
+    pub fn new(image_count: usize) -> Self {
+        Self { owners: vec![None; image_count] }
+    }
+
+    /// Hands `image` to `frame`, returning the previous owner if it was a
+    /// different frame whose work must finish first.
+    pub fn acquire(&mut self, image: usize, frame: usize) -> Option<usize> {
+        let previous = self.owners[image].replace(frame);
+        previous.filter(|p| *p != frame)
+    }
+}
+
+/// Creates the per-frame command buffers, `image_available` semaphores and
+/// fences for `frames_in_flight` frames.
+pub unsafe fn create_frame_sync_objects(device: &Device, data: &mut AppData, frames_in_flight: usize) -> Result<()> {
+    if frames_in_flight == 0 {
+        return Err(anyhow!("At least one frame in flight is required."));
+    }
+
+    let semaphore_info = vk::SemaphoreCreateInfo::builder();
+    let fence_info = vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED);
+    data.frame_command_buffers = allocate_command_buffers(device, data, frames_in_flight as u32)?;
+    for _ in 0..frames_in_flight {
+        data.image_available_semaphores.push(device.create_semaphore(&semaphore_info, None)?);
+        data.in_flight_fences.push(device.create_fence(&fence_info, None)?);
+    }
+    Ok(())
+}
+
+pub unsafe fn destroy_frame_sync_objects(device: &Device, data: &mut AppData) {
+    data.in_flight_fences.drain(..).for_each(|f| device.destroy_fence(f, None));
+    data.image_available_semaphores.drain(..).for_each(|s| device.destroy_semaphore(s, None));
+    if !data.frame_command_buffers.is_empty() {
+        device.free_command_buffers(data.command_pool, &data.frame_command_buffers);
+        data.frame_command_buffers.clear();
+    }
+}
+
+/// Creates the per swapchain image `render_finished` semaphores and resets
+/// the image ownership. Presentation holds on to the semaphore until the image
+/// is reacquired, so these follow the images rather than the frames.
+pub unsafe fn create_image_sync_objects(device: &Device, data: &mut AppData) -> Result<()> {
+    let semaphore_info = vk::SemaphoreCreateInfo::builder();
+    data.render_finished_semaphores = data
+        .target_images
+        .iter()
+        .map(|_| device.create_semaphore(&semaphore_info, None))
+        .collect::<Result<Vec<_>, _>>()?;
+    data.images_in_flight = ImageOwnership::new(data.target_images.len());
+    Ok(())
+}
+
+pub unsafe fn destroy_image_sync_objects(device: &Device, data: &mut AppData) {
+    data.render_finished_semaphores.drain(..).for_each(|s| device.destroy_semaphore(s, None));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_use_has_no_owner() {
+        let mut ownership = ImageOwnership::new(3);
+        assert_eq!(ownership.acquire(0, 0), None);
+        assert_eq!(ownership.acquire(1, 1), None);
+    }
+
+    #[test]
+    fn reuse_by_another_frame_waits_for_the_owner() {
+        let mut ownership = ImageOwnership::new(2);
+        ownership.acquire(0, 0);
+        assert_eq!(ownership.acquire(0, 1), Some(0));
+        assert_eq!(ownership.acquire(0, 0), Some(1));
+    }
+
+    #[test]
+    fn reuse_by_the_same_frame_does_not_wait() {
+        let mut ownership = ImageOwnership::new(2);
+        ownership.acquire(1, 1);
+        assert_eq!(ownership.acquire(1, 1), None);
+    }
+}
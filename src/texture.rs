@@ -0,0 +1,463 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use log::*;
+
+use vulkanalia::prelude::v1_0::*;
+
+use crate::commands::{begin_single_time_commands, end_single_time_commands};
+use crate::memory::{allocate_image_memory, Allocation, AllocationRequest, ResourceKind};
+use crate::upload::{upload_image, wait, ImageUpload, UploadTicket};
+use crate::AppData;
+
+/// Format of every texture; decoded PNGs are converted to it.
+pub const TEXTURE_FORMAT: vk::Format = vk::Format::R8G8B8A8_SRGB;
+
+/// Anisotropy requested for texture samplers unless configured otherwise.
+pub const DEFAULT_ANISOTROPY: f32 = 16.0;
+
+/// Tightly packed sRGB RGBA8 texels.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TextureData {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+/// Loads a PNG of any color type and bit depth as sRGB RGBA8.
+pub fn load_texture(path: &Path) -> Result<TextureData> {
+    let file = File::open(path).with_context(|| format!("Failed to open `{}`.", path.display()))?;
+    decode_png(BufReader::new(file)).with_context(|| format!("Failed to read `{}`.", path.display()))
+}
+
+/// Decodes a PNG as sRGB RGBA8. Palettes and low bit depths are expanded,
+/// 16-bit channels truncated, and gray and RGB images given opaque alpha.
+pub fn decode_png<R: Read>(r: R) -> Result<TextureData> {
+    let mut decoder = png::Decoder::new(r);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info()?;
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels)?;
+    pixels.truncate(info.buffer_size());
+    if info.bit_depth != png::BitDepth::Eight {
+        return Err(anyhow!("Unsupported PNG bit depth {:?}.", info.bit_depth));
+    }
+    let pixels = to_rgba8(info.color_type, &pixels)?;
+    Ok(TextureData { width: info.width, height: info.height, pixels })
+}
+
+/// Converts 8-bit texels of an expanded (non-indexed) `color_type` to RGBA8.
+pub fn to_rgba8(color_type: png::ColorType, pixels: &[u8]) -> Result<Vec<u8>> {
+    Ok(match color_type {
+        png::ColorType::Rgba => pixels.to_vec(),
+        png::ColorType::Rgb => pixels.chunks_exact(3).flat_map(|p| [p[0], p[1], p[2], 255]).collect(),
+        png::ColorType::GrayscaleAlpha => pixels.chunks_exact(2).flat_map(|p| [p[0], p[0], p[0], p[1]]).collect(),
+        png::ColorType::Grayscale => pixels.iter().flat_map(|g| [*g, *g, *g, 255]).collect(),
+        png::ColorType::Indexed => return Err(anyhow!("Indexed PNG texels were not expanded.")),
+    })
+}
+
+/// Number of levels in a full mip chain down to 1x1.
+pub fn mip_level_count(width: u32, height: u32) -> u32 {
+    32 - width.max(height).max(1).leading_zeros()
+}
+
+fn srgb_to_linear(c: u8) -> f32 {
+    let c = c as f32 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(c: f32) -> u8 {
+    let c = if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 };
+    (c * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+/// The next smaller mip level of `level`, averaging 2x2 texels in linear
+/// space like a linear blit would. Odd edges reuse their last texel.
+pub fn downsample(level: &TextureData) -> TextureData {
+    let (width, height) = ((level.width / 2).max(1), (level.height / 2).max(1));
+    let texel = |x: u32, y: u32| {
+        let i = 4 * (y.min(level.height - 1) * level.width + x.min(level.width - 1)) as usize;
+        &level.pixels[i..i + 4]
+    };
+
+    let mut pixels = Vec::with_capacity(4 * (width * height) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let texels = [texel(2 * x, 2 * y), texel(2 * x + 1, 2 * y), texel(2 * x, 2 * y + 1), texel(2 * x + 1, 2 * y + 1)];
+            for c in 0..3 {
+                let sum = texels.iter().map(|t| srgb_to_linear(t[c])).sum::<f32>();
+                pixels.push(linear_to_srgb(sum / 4.0));
+            }
+            let alpha = texels.iter().map(|t| t[3] as u32).sum::<u32>();
+            pixels.push(((alpha + 2) / 4) as u8);
+        }
+    }
+    TextureData { width, height, pixels }
+}
+
+/// Every level of the mip chain of `base`, starting with `base` itself.
+pub fn generate_mip_chain(base: &TextureData) -> Vec<TextureData> {
+    let mut levels = vec![base.clone()];
+    for _ in 1..mip_level_count(base.width, base.height) {
+        let next = downsample(levels.last().unwrap());
+        levels.push(next);
+    }
+    levels
+}
+
+/// The anisotropy a sampler is created with: `requested` clamped to the
+/// device limit, or `None` when it is disabled, not above 1, or the
+/// `samplerAnisotropy` feature is not enabled.
+pub fn sampler_anisotropy(requested: f32, feature_enabled: bool, limit: f32) -> Option<f32> {
+    Some(requested.min(limit)).filter(|a| feature_enabled && *a > 1.0)
+}
+
+/// A sampled device local image with its full mip chain.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Texture {
+    pub image: vk::Image,
+    pub view: vk::ImageView,
+    pub allocation: Allocation,
+    pub extent: vk::Extent2D,
+    pub mip_levels: u32,
+    /// Completes once every level is uploaded and readable by shaders.
+    pub ticket: UploadTicket,
+}
+
+/// Creates a texture from `texture` and fills its mip chain, by blitting on
+/// the graphics queue when the format supports linear filtering of blits and
+/// with mips generated on the CPU otherwise.
+pub unsafe fn create_texture(
+    instance: &Instance,
+    device: &Device,
+    data: &mut AppData,
+    texture: &TextureData,
+) -> Result<Texture> {
+    let extent = vk::Extent2D { width: texture.width, height: texture.height };
+    let mip_levels = mip_level_count(texture.width, texture.height);
+    let properties = instance.get_physical_device_format_properties(data.physical_device, TEXTURE_FORMAT);
+    let blit_features = vk::FormatFeatureFlags::BLIT_SRC
+        | vk::FormatFeatureFlags::BLIT_DST
+        | vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR;
+    let blit = properties.optimal_tiling_features.contains(blit_features);
+
+    let mut usage = vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST;
+    if blit {
+        usage |= vk::ImageUsageFlags::TRANSFER_SRC;
+    }
+    let info = vk::ImageCreateInfo::builder()
+        .image_type(vk::ImageType::_2D)
+        .extent(vk::Extent3D { width: extent.width, height: extent.height, depth: 1 })
+        .mip_levels(mip_levels)
+        .array_layers(1)
+        .format(TEXTURE_FORMAT)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .usage(usage)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .samples(vk::SampleCountFlags::_1);
+    let image = device.create_image(&info, None)?;
+    let request = AllocationRequest::device_local(ResourceKind::Optimal);
+    let allocation = match allocate_image_memory(device, data, image, vk::ImageTiling::OPTIMAL, request) {
+        Ok(allocation) => allocation,
+        Err(e) => {
+            device.destroy_image(image, None);
+            return Err(e);
+        }
+    };
+    let mut result = Texture { image, allocation, extent, mip_levels, ..Default::default() };
+
+    if let Err(e) = fill_mip_chain(device, data, texture, &mut result, blit) {
+        destroy_texture(device, data, &result);
+        return Err(e);
+    }
+
+    let subresource_range = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(mip_levels)
+        .base_array_layer(0)
+        .layer_count(1);
+    let info = vk::ImageViewCreateInfo::builder()
+        .image(image)
+        .view_type(vk::ImageViewType::_2D)
+        .format(TEXTURE_FORMAT)
+        .subresource_range(subresource_range);
+    match device.create_image_view(&info, None) {
+        Ok(view) => result.view = view,
+        Err(e) => {
+            destroy_texture(device, data, &result);
+            return Err(e.into());
+        }
+    }
+
+    Ok(result)
+}
+
+unsafe fn fill_mip_chain(
+    device: &Device,
+    data: &mut AppData,
+    texture: &TextureData,
+    result: &mut Texture,
+    blit: bool,
+) -> Result<()> {
+    let upload = ImageUpload {
+        image: result.image,
+        extent: result.extent,
+        mip_levels: result.mip_levels,
+        final_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+    };
+    if !blit {
+        debug!("{:?} does not support linear blits, generating mipmaps on the CPU.", TEXTURE_FORMAT);
+        let levels = generate_mip_chain(texture);
+        let levels = levels.iter().map(|l| l.pixels.as_slice()).collect::<Vec<_>>();
+        result.ticket = upload_image(device, data, &levels, upload)?;
+        return Ok(());
+    }
+
+    let upload = ImageUpload { final_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL, ..upload };
+    result.ticket = upload_image(device, data, &[&texture.pixels], upload)?;
+    wait(device, data, result.ticket)?;
+    generate_mipmaps(device, data, result.image, result.extent, result.mip_levels)
+}
+
+/// Fills levels 1.. of `image` by successively blitting each level into the
+/// next, and leaves every level in `SHADER_READ_ONLY_OPTIMAL`. Every level
+/// must be in `TRANSFER_DST_OPTIMAL` with level 0 holding the texels.
+unsafe fn generate_mipmaps(
+    device: &Device,
+    data: &AppData,
+    image: vk::Image,
+    extent: vk::Extent2D,
+    mip_levels: u32,
+) -> Result<()> {
+    let command_buffer = begin_single_time_commands(device, data)?;
+    let barrier = |level, old_layout, new_layout, src_access, dst_access| {
+        let subresource_range = vk::ImageSubresourceRange::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .base_mip_level(level)
+            .level_count(1)
+            .base_array_layer(0)
+            .layer_count(1);
+        vk::ImageMemoryBarrier::builder()
+            .old_layout(old_layout)
+            .new_layout(new_layout)
+            .src_access_mask(src_access)
+            .dst_access_mask(dst_access)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image)
+            .subresource_range(subresource_range)
+            .build()
+    };
+    let pipeline_barrier = |dst_stage, barrier: vk::ImageMemoryBarrier| {
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            dst_stage,
+            vk::DependencyFlags::empty(),
+            &[] as &[vk::MemoryBarrier],
+            &[] as &[vk::BufferMemoryBarrier],
+            &[barrier],
+        );
+    };
+    let corner = |level: u32| vk::Offset3D {
+        x: (extent.width >> level).max(1) as i32,
+        y: (extent.height >> level).max(1) as i32,
+        z: 1,
+    };
+    let layers = |level| {
+        vk::ImageSubresourceLayers::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .mip_level(level)
+            .base_array_layer(0)
+            .layer_count(1)
+            .build()
+    };
+
+    for level in 1..mip_levels {
+        pipeline_barrier(
+            vk::PipelineStageFlags::TRANSFER,
+            barrier(
+                level - 1,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                vk::AccessFlags::TRANSFER_WRITE,
+                vk::AccessFlags::TRANSFER_READ,
+            ),
+        );
+
+        let blit = vk::ImageBlit::builder()
+            .src_subresource(layers(level - 1))
+            .src_offsets([vk::Offset3D::default(), corner(level - 1)])
+            .dst_subresource(layers(level))
+            .dst_offsets([vk::Offset3D::default(), corner(level)]);
+        device.cmd_blit_image(
+            command_buffer,
+            image,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            image,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[blit],
+            vk::Filter::LINEAR,
+        );
+
+        pipeline_barrier(
+            vk::PipelineStageFlags::FRAGMENT_SHADER,
+            barrier(
+                level - 1,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                vk::AccessFlags::TRANSFER_READ,
+                vk::AccessFlags::SHADER_READ,
+            ),
+        );
+    }
+
+    pipeline_barrier(
+        vk::PipelineStageFlags::FRAGMENT_SHADER,
+        barrier(
+            mip_levels - 1,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            vk::AccessFlags::TRANSFER_WRITE,
+            vk::AccessFlags::SHADER_READ,
+        ),
+    );
+
+    end_single_time_commands(device, data, command_buffer)
+}
+
+pub unsafe fn destroy_texture(device: &Device, data: &mut AppData, texture: &Texture) {
+    device.destroy_image_view(texture.view, None);
+    device.destroy_image(texture.image, None);
+    data.allocator.free(device, &texture.allocation);
+}
+
+/// Creates a trilinear, repeating sampler covering `mip_levels` levels.
+/// `anisotropy` is clamped to the device limit and ignored when the
+/// `samplerAnisotropy` feature is not enabled.
+pub unsafe fn create_sampler(device: &Device, data: &AppData, mip_levels: u32, anisotropy: f32) -> Result<vk::Sampler> {
+    let limit = data.physical_device_properties.limits.max_sampler_anisotropy;
+    let enabled = data.enabled_features.sampler_anisotropy == vk::TRUE;
+    let anisotropy = sampler_anisotropy(anisotropy, enabled, limit);
+    debug!("Creating sampler with anisotropy {:?} (device limit {}).", anisotropy, limit);
+
+    let info = vk::SamplerCreateInfo::builder()
+        .mag_filter(vk::Filter::LINEAR)
+        .min_filter(vk::Filter::LINEAR)
+        .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
+        .address_mode_u(vk::SamplerAddressMode::REPEAT)
+        .address_mode_v(vk::SamplerAddressMode::REPEAT)
+        .address_mode_w(vk::SamplerAddressMode::REPEAT)
+        .anisotropy_enable(anisotropy.is_some())
+        .max_anisotropy(anisotropy.unwrap_or(1.0))
+        .compare_enable(false)
+        .compare_op(vk::CompareOp::ALWAYS)
+        .min_lod(0.0)
+        .max_lod(mip_levels as f32)
+        .mip_lod_bias(0.0)
+        .border_color(vk::BorderColor::INT_OPAQUE_BLACK)
+        .unnormalized_coordinates(false);
+    Ok(device.create_sampler(&info, None)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texture(width: u32, height: u32, texels: &[[u8; 4]]) -> TextureData {
+        TextureData { width, height, pixels: texels.concat() }
+    }
+
+    fn encode_png(width: u32, height: u32, color_type: png::ColorType, pixels: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![];
+        let mut encoder = png::Encoder::new(&mut bytes, width, height);
+        encoder.set_color(color_type);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header().unwrap().write_image_data(pixels).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn mip_levels() {
+        assert_eq!(mip_level_count(1, 1), 1);
+        assert_eq!(mip_level_count(2, 1), 2);
+        assert_eq!(mip_level_count(256, 256), 9);
+        assert_eq!(mip_level_count(257, 16), 9);
+        assert_eq!(mip_level_count(1, 1000), 10);
+        assert_eq!(mip_level_count(0, 0), 1);
+    }
+
+    #[test]
+    fn uniform_texels_are_kept() {
+        let level = texture(2, 2, &[[10, 128, 250, 77]; 4]);
+        assert_eq!(downsample(&level), texture(1, 1, &[[10, 128, 250, 77]]));
+    }
+
+    #[test]
+    fn colors_are_averaged_in_linear_space() {
+        let level = texture(2, 2, &[[0, 0, 0, 0], [255, 255, 255, 255], [0, 0, 0, 0], [255, 255, 255, 255]]);
+        // Half of the light is 188 in sRGB, while alpha is linear.
+        assert_eq!(downsample(&level), texture(1, 1, &[[188, 188, 188, 128]]));
+    }
+
+    #[test]
+    fn odd_sizes_round_down() {
+        // A single column is averaged with itself.
+        let level = texture(1, 2, &[[255, 0, 0, 255], [255, 0, 0, 255]]);
+        assert_eq!(downsample(&level), texture(1, 1, &[[255, 0, 0, 255]]));
+        let level = texture(3, 1, &[[0, 0, 0, 255], [0, 0, 0, 255], [255, 255, 255, 255]]);
+        assert_eq!(downsample(&level), texture(1, 1, &[[0, 0, 0, 255]]));
+    }
+
+    #[test]
+    fn mip_chain_ends_at_one_texel() {
+        let base = texture(5, 3, &[[200, 100, 50, 255]; 15]);
+        let chain = generate_mip_chain(&base);
+        let sizes = chain.iter().map(|l| (l.width, l.height)).collect::<Vec<_>>();
+        assert_eq!(sizes, [(5, 3), (2, 1), (1, 1)]);
+        assert_eq!(chain[0], base);
+        assert!(chain.iter().all(|l| l.pixels.len() == 4 * (l.width * l.height) as usize));
+        assert_eq!(chain[2], texture(1, 1, &[[200, 100, 50, 255]]));
+    }
+
+    #[test]
+    fn single_texel_has_no_smaller_levels() {
+        let base = texture(1, 1, &[[1, 2, 3, 4]]);
+        assert_eq!(generate_mip_chain(&base), [base]);
+    }
+
+    #[test]
+    fn anisotropy() {
+        assert_eq!(sampler_anisotropy(16.0, true, 8.0), Some(8.0));
+        assert_eq!(sampler_anisotropy(4.0, true, 16.0), Some(4.0));
+        assert_eq!(sampler_anisotropy(16.0, false, 16.0), None);
+        assert_eq!(sampler_anisotropy(1.0, true, 16.0), None);
+        assert_eq!(sampler_anisotropy(0.0, true, 16.0), None);
+    }
+
+    #[test]
+    fn texels_are_converted_to_rgba() {
+        assert_eq!(to_rgba8(png::ColorType::Rgb, &[1, 2, 3, 4, 5, 6]).unwrap(), [1, 2, 3, 255, 4, 5, 6, 255]);
+        assert_eq!(to_rgba8(png::ColorType::GrayscaleAlpha, &[7, 8]).unwrap(), [7, 7, 7, 8]);
+        assert_eq!(to_rgba8(png::ColorType::Grayscale, &[9]).unwrap(), [9, 9, 9, 255]);
+        assert_eq!(to_rgba8(png::ColorType::Rgba, &[1, 2, 3, 4]).unwrap(), [1, 2, 3, 4]);
+        assert!(to_rgba8(png::ColorType::Indexed, &[0]).is_err());
+    }
+
+    #[test]
+    fn decoded_pngs_are_rgba() {
+        let png = encode_png(2, 1, png::ColorType::Grayscale, &[0, 200]);
+        let texture = decode_png(png.as_slice()).unwrap();
+        assert_eq!(texture, TextureData { width: 2, height: 1, pixels: vec![0, 0, 0, 255, 200, 200, 200, 255] });
+        assert!(decode_png(&b"not a png"[..]).is_err());
+    }
+}
@@ -0,0 +1,564 @@
+use std::ptr::copy_nonoverlapping as memcpy;
+
+use anyhow::{anyhow, Result};
+
+use vulkanalia::prelude::v1_0::*;
+
+use crate::commands::allocate_command_buffers;
+use crate::memory::{align_up, create_buffer, destroy_buffer, Allocation, AllocationRequest, ResourceKind};
+use crate::AppData;
+
+/// Size of each staging buffer in the ring.
+pub const DEFAULT_STAGING_SIZE: vk::DeviceSize = 16 * 1024 * 1024;
+
+/// Number of staging buffers in the ring.
+pub const DEFAULT_STAGING_BUFFERS: usize = 3;
+
+/// Identifies the batch of uploads a copy was recorded into. Batches complete
+/// in order, so a ticket also stands for every batch before it.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct UploadTicket(u64);
+
+/// One staging buffer of the ring with the commands copying out of it.
+#[derive(Clone, Debug, Default)]
+struct StagingBuffer {
+    buffer: vk::Buffer,
+    allocation: Allocation,
+    /// Copies and releases, submitted to the transfer queue.
+    transfer_commands: vk::CommandBuffer,
+    /// Acquires on the graphics queue; only used when the transfer queue
+    /// belongs to another family.
+    acquire_commands: vk::CommandBuffer,
+    /// Signalled by the transfer submission, waited on by the acquire one.
+    transferred: vk::Semaphore,
+    /// Signalled once the batch is complete and the staging memory is free.
+    fence: vk::Fence,
+    /// The batch last recorded into this buffer.
+    batch: u64,
+    recording: bool,
+    used: vk::DeviceSize,
+    copies: usize,
+    buffer_acquires: Vec<vk::BufferMemoryBarrier>,
+    image_acquires: Vec<vk::ImageMemoryBarrier>,
+}
+
+/// Moves data from the CPU into device local buffers and images through a
+/// ring of persistently mapped staging buffers. Copies are batched per
+/// staging buffer and run on the transfer queue; when that queue belongs to
+/// another family, ownership of the destination is handed to the graphics
+/// family before the batch counts as complete.
+#[derive(Clone, Debug, Default)]
+pub struct Uploader {
+    command_pool: vk::CommandPool,
+    staging: Vec<StagingBuffer>,
+    current: usize,
+    /// The batch being recorded into `staging[current]`, starting at 1.
+    batch: u64,
+    capacity: vk::DeviceSize,
+    alignment: vk::DeviceSize,
+}
+
+/// Splits `height` rows of `row_bytes` each into runs of at most `capacity`
+/// bytes, as `(first row, row count)` pairs.
+pub fn split_rows(height: u32, row_bytes: vk::DeviceSize, capacity: vk::DeviceSize) -> Vec<(u32, u32)> {
+    let rows_per_chunk = (capacity / row_bytes.max(1)).clamp(1, u32::MAX as u64) as u32;
+    (0..height)
+        .step_by(rows_per_chunk as usize)
+        .map(|y| (y, rows_per_chunk.min(height - y)))
+        .collect()
+}
+
+/// A device local image receiving pixel data.
+#[derive(Copy, Clone, Debug)]
+pub struct ImageUpload {
+    pub image: vk::Image,
+    pub extent: vk::Extent2D,
+    /// Every level is transitioned; the data is copied into the first ones.
+    pub mip_levels: u32,
+    /// The layout every level is left in, e.g. `SHADER_READ_ONLY_OPTIMAL`,
+    /// or `TRANSFER_DST_OPTIMAL` to generate mipmaps afterwards.
+    pub final_layout: vk::ImageLayout,
+}
+
+pub unsafe fn create_uploader(device: &Device, data: &mut AppData, buffers: usize, size: vk::DeviceSize) -> Result<()> {
+    let info = vk::CommandPoolCreateInfo::builder()
+        .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+        .queue_family_index(data.transfer_queue_family);
+    data.uploader.command_pool = device.create_command_pool(&info, None)?;
+    data.uploader.capacity = size;
+    data.uploader.alignment = data.physical_device_properties.limits.optimal_buffer_copy_offset_alignment.max(16);
+    data.uploader.batch = 1;
+
+    let separate_family = data.transfer_queue_family != data.graphics_queue_family;
+    let fence_info = vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED);
+    for _ in 0..buffers {
+        let (buffer, allocation) = create_buffer(
+            device,
+            data,
+            size,
+            vk::BufferUsageFlags::TRANSFER_SRC,
+            AllocationRequest::host_visible(),
+        )?;
+        let info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(data.uploader.command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(1);
+        let transfer_commands = device.allocate_command_buffers(&info)?[0];
+        let (acquire_commands, transferred) = if separate_family {
+            let semaphore = device.create_semaphore(&vk::SemaphoreCreateInfo::builder(), None)?;
+            (allocate_command_buffers(device, data, 1)?[0], semaphore)
+        } else {
+            (vk::CommandBuffer::null(), vk::Semaphore::null())
+        };
+        data.uploader.staging.push(StagingBuffer {
+            buffer,
+            allocation,
+            transfer_commands,
+            acquire_commands,
+            transferred,
+            fence: device.create_fence(&fence_info, None)?,
+            ..Default::default()
+        });
+    }
+    Ok(())
+}
+
+pub unsafe fn destroy_uploader(device: &Device, data: &mut AppData) {
+    for staging in std::mem::take(&mut data.uploader.staging) {
+        device.destroy_fence(staging.fence, None);
+        if !staging.transferred.is_null() {
+            device.destroy_semaphore(staging.transferred, None);
+            device.free_command_buffers(data.command_pool, &[staging.acquire_commands]);
+        }
+        destroy_buffer(device, data, staging.buffer, &staging.allocation);
+    }
+    if !data.uploader.command_pool.is_null() {
+        device.destroy_command_pool(data.uploader.command_pool, None);
+        data.uploader.command_pool = vk::CommandPool::null();
+    }
+}
+
+/// Copies `bytes` into `buffer` at `offset`. Large uploads are split over
+/// several batches; the returned ticket is the last one.
+pub unsafe fn upload_buffer(
+    device: &Device,
+    data: &mut AppData,
+    bytes: &[u8],
+    buffer: vk::Buffer,
+    offset: vk::DeviceSize,
+) -> Result<UploadTicket> {
+    let capacity = data.uploader.capacity as usize;
+    for (i, chunk) in bytes.chunks(capacity).enumerate() {
+        let source = stage(device, data, chunk)?;
+        let target = offset + (i * capacity) as vk::DeviceSize;
+        let staging = &mut data.uploader.staging[data.uploader.current];
+        let region = vk::BufferCopy::builder().src_offset(source).dst_offset(target).size(chunk.len() as u64);
+        device.cmd_copy_buffer(staging.transfer_commands, staging.buffer, buffer, &[region]);
+        let barrier = |src_access, dst_access, src_family, dst_family| {
+            vk::BufferMemoryBarrier::builder()
+                .src_access_mask(src_access)
+                .dst_access_mask(dst_access)
+                .src_queue_family_index(src_family)
+                .dst_queue_family_index(dst_family)
+                .buffer(buffer)
+                .offset(target)
+                .size(chunk.len() as u64)
+                .build()
+        };
+        let (src, dst) = (data.transfer_queue_family, data.graphics_queue_family);
+        release(device, data, |release| {
+            let barrier = if src == dst {
+                let ignored = vk::QUEUE_FAMILY_IGNORED;
+                barrier(vk::AccessFlags::TRANSFER_WRITE, vk::AccessFlags::MEMORY_READ, ignored, ignored)
+            } else if release {
+                barrier(vk::AccessFlags::TRANSFER_WRITE, vk::AccessFlags::empty(), src, dst)
+            } else {
+                barrier(vk::AccessFlags::empty(), vk::AccessFlags::MEMORY_READ, src, dst)
+            };
+            (vec![barrier], vec![])
+        });
+    }
+    Ok(UploadTicket(data.uploader.batch))
+}
+
+/// Copies tightly packed texels into the first `levels.len()` mip levels of
+/// `upload.image` and leaves every level in `upload.final_layout`. Levels
+/// too large for one staging buffer are copied in runs of rows over several
+/// batches.
+pub unsafe fn upload_image(
+    device: &Device,
+    data: &mut AppData,
+    levels: &[&[u8]],
+    upload: ImageUpload,
+) -> Result<UploadTicket> {
+    let ImageUpload { image, extent, mip_levels, final_layout } = upload;
+    if levels.is_empty() || levels.len() > mip_levels as usize {
+        return Err(anyhow!("Cannot upload {} of {} mip levels.", levels.len(), mip_levels));
+    }
+
+    let range = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(mip_levels)
+        .base_array_layer(0)
+        .layer_count(1)
+        .build();
+    let barrier = |old_layout, new_layout, src_access, dst_access, src_family, dst_family| {
+        vk::ImageMemoryBarrier::builder()
+            .old_layout(old_layout)
+            .new_layout(new_layout)
+            .src_access_mask(src_access)
+            .dst_access_mask(dst_access)
+            .src_queue_family_index(src_family)
+            .dst_queue_family_index(dst_family)
+            .image(image)
+            .subresource_range(range)
+            .build()
+    };
+
+    let mut transitioned = false;
+    for (level, bytes) in levels.iter().enumerate() {
+        let (width, height) = ((extent.width >> level).max(1), (extent.height >> level).max(1));
+        let row_bytes = bytes.len() as vk::DeviceSize / height as vk::DeviceSize;
+        if row_bytes > data.uploader.capacity {
+            return Err(anyhow!("A row of {} bytes does not fit a staging buffer.", row_bytes));
+        }
+
+        for (y, rows) in split_rows(height, row_bytes, data.uploader.capacity) {
+            let start = (y as vk::DeviceSize * row_bytes) as usize;
+            let end = start + (rows as vk::DeviceSize * row_bytes) as usize;
+            let source = stage(device, data, &bytes[start..end])?;
+            let command_buffer = data.uploader.staging[data.uploader.current].transfer_commands;
+            let staging_buffer = data.uploader.staging[data.uploader.current].buffer;
+
+            if !transitioned {
+                let to_transfer = barrier(
+                    vk::ImageLayout::UNDEFINED,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    vk::AccessFlags::empty(),
+                    vk::AccessFlags::TRANSFER_WRITE,
+                    vk::QUEUE_FAMILY_IGNORED,
+                    vk::QUEUE_FAMILY_IGNORED,
+                );
+                device.cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::TOP_OF_PIPE,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::DependencyFlags::empty(),
+                    &[] as &[vk::MemoryBarrier],
+                    &[] as &[vk::BufferMemoryBarrier],
+                    &[to_transfer],
+                );
+                transitioned = true;
+            }
+
+            let subresource = vk::ImageSubresourceLayers::builder()
+                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                .mip_level(level as u32)
+                .base_array_layer(0)
+                .layer_count(1);
+            let region = vk::BufferImageCopy::builder()
+                .buffer_offset(source)
+                .buffer_row_length(0)
+                .buffer_image_height(0)
+                .image_subresource(subresource)
+                .image_offset(vk::Offset3D { x: 0, y: y as i32, z: 0 })
+                .image_extent(vk::Extent3D { width, height: rows, depth: 1 });
+            device.cmd_copy_buffer_to_image(
+                command_buffer,
+                staging_buffer,
+                image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[region],
+            );
+        }
+    }
+
+    let (src, dst) = (data.transfer_queue_family, data.graphics_queue_family);
+    release(device, data, |release| {
+        let (old, new) = (vk::ImageLayout::TRANSFER_DST_OPTIMAL, final_layout);
+        let barrier = if src == dst {
+            let ignored = vk::QUEUE_FAMILY_IGNORED;
+            barrier(old, new, vk::AccessFlags::TRANSFER_WRITE, vk::AccessFlags::MEMORY_READ, ignored, ignored)
+        } else if release {
+            barrier(old, new, vk::AccessFlags::TRANSFER_WRITE, vk::AccessFlags::empty(), src, dst)
+        } else {
+            barrier(old, new, vk::AccessFlags::empty(), vk::AccessFlags::MEMORY_READ, src, dst)
+        };
+        (vec![], vec![barrier])
+    });
+    Ok(UploadTicket(data.uploader.batch))
+}
+
+/// Records the barrier making a copy visible to the graphics queue. With a
+/// separate transfer family, `barriers(true)` gives the release recorded now
+/// and `barriers(false)` the matching acquire recorded when flushing.
+unsafe fn release<F>(device: &Device, data: &mut AppData, barriers: F)
+where
+    F: Fn(bool) -> (Vec<vk::BufferMemoryBarrier>, Vec<vk::ImageMemoryBarrier>),
+{
+    let separate_family = data.transfer_queue_family != data.graphics_queue_family;
+    let (buffer_barriers, image_barriers) = barriers(true);
+    let dst_stage = if separate_family {
+        vk::PipelineStageFlags::BOTTOM_OF_PIPE
+    } else {
+        vk::PipelineStageFlags::ALL_COMMANDS
+    };
+    let staging = &mut data.uploader.staging[data.uploader.current];
+    device.cmd_pipeline_barrier(
+        staging.transfer_commands,
+        vk::PipelineStageFlags::TRANSFER,
+        dst_stage,
+        vk::DependencyFlags::empty(),
+        &[] as &[vk::MemoryBarrier],
+        &buffer_barriers,
+        &image_barriers,
+    );
+    if separate_family {
+        let (buffer_barriers, image_barriers) = barriers(false);
+        staging.buffer_acquires.extend(buffer_barriers);
+        staging.image_acquires.extend(image_barriers);
+    }
+}
+
+/// Copies `bytes` into the current staging buffer, moving on to the next
+/// one if they do not fit, and returns their offset in it.
+unsafe fn stage(device: &Device, data: &mut AppData, bytes: &[u8]) -> Result<vk::DeviceSize> {
+    let size = bytes.len() as vk::DeviceSize;
+    if size > data.uploader.capacity {
+        return Err(anyhow!("{} bytes do not fit a staging buffer.", size));
+    }
+
+    begin(device, data)?;
+    let uploader = &data.uploader;
+    let staging = &uploader.staging[uploader.current];
+    if align_up(staging.used, uploader.alignment) + size > uploader.capacity {
+        flush(device, data)?;
+        begin(device, data)?;
+    }
+
+    let uploader = &mut data.uploader;
+    let alignment = uploader.alignment;
+    let staging = &mut uploader.staging[uploader.current];
+    let offset = align_up(staging.used, alignment);
+    let mapped = staging.allocation.mapped.ok_or_else(|| anyhow!("Staging buffer is not mapped."))?;
+    memcpy(bytes.as_ptr(), mapped.as_ptr().add(offset as usize), bytes.len());
+    staging.used = offset + size;
+    staging.copies += 1;
+    Ok(offset)
+}
+
+/// Starts recording into the current staging buffer unless it already is,
+/// waiting for the batch previously recorded into it to complete.
+unsafe fn begin(device: &Device, data: &mut AppData) -> Result<()> {
+    let uploader = &mut data.uploader;
+    let batch = uploader.batch;
+    let staging = &mut uploader.staging[uploader.current];
+    if staging.recording {
+        return Ok(());
+    }
+
+    device.wait_for_fences(&[staging.fence], true, u64::MAX)?;
+    device.reset_fences(&[staging.fence])?;
+    let info = vk::CommandBufferBeginInfo::builder().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+    device.begin_command_buffer(staging.transfer_commands, &info)?;
+    staging.batch = batch;
+    staging.recording = true;
+    staging.used = 0;
+    staging.copies = 0;
+    Ok(())
+}
+
+/// Submits the batch being recorded, if it holds any copies. Its ticket
+/// completes once the copies finished and the graphics queue owns the data.
+pub unsafe fn flush(device: &Device, data: &mut AppData) -> Result<()> {
+    let (transfer_queue, graphics_queue) = (data.transfer_queue, data.graphics_queue);
+    let uploader = &mut data.uploader;
+    let staging = &mut uploader.staging[uploader.current];
+    if !staging.recording || staging.copies == 0 {
+        return Ok(());
+    }
+
+    device.end_command_buffer(staging.transfer_commands)?;
+    let transfer_commands = &[staging.transfer_commands];
+    if staging.transferred.is_null() {
+        let info = vk::SubmitInfo::builder().command_buffers(transfer_commands);
+        device.queue_submit(transfer_queue, &[info], staging.fence)?;
+    } else {
+        let begin_info = vk::CommandBufferBeginInfo::builder().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        device.begin_command_buffer(staging.acquire_commands, &begin_info)?;
+        device.cmd_pipeline_barrier(
+            staging.acquire_commands,
+            vk::PipelineStageFlags::TOP_OF_PIPE,
+            vk::PipelineStageFlags::ALL_COMMANDS,
+            vk::DependencyFlags::empty(),
+            &[] as &[vk::MemoryBarrier],
+            &staging.buffer_acquires,
+            &staging.image_acquires,
+        );
+        device.end_command_buffer(staging.acquire_commands)?;
+        staging.buffer_acquires.clear();
+        staging.image_acquires.clear();
+
+        let transferred = &[staging.transferred];
+        let info = vk::SubmitInfo::builder().command_buffers(transfer_commands).signal_semaphores(transferred);
+        device.queue_submit(transfer_queue, &[info], vk::Fence::null())?;
+
+        let acquire_commands = &[staging.acquire_commands];
+        let wait_stages = &[vk::PipelineStageFlags::ALL_COMMANDS];
+        let info = vk::SubmitInfo::builder()
+            .wait_semaphores(transferred)
+            .wait_dst_stage_mask(wait_stages)
+            .command_buffers(acquire_commands);
+        device.queue_submit(graphics_queue, &[info], staging.fence)?;
+    }
+
+    staging.recording = false;
+    uploader.batch += 1;
+    uploader.current = (uploader.current + 1) % uploader.staging.len();
+    Ok(())
+}
+
+/// Where the batch of a ticket stands.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum TicketState {
+    /// Still being recorded, it only completes after a flush.
+    Recording,
+    /// Submitted; completes with the fence of this staging buffer.
+    Submitted(usize),
+    /// The staging buffer was reused by a later batch, so this one is done.
+    Complete,
+}
+
+impl Uploader {
+    fn ticket_state(&self, ticket: UploadTicket) -> TicketState {
+        if ticket.0 >= self.batch {
+            return TicketState::Recording;
+        }
+        match self.staging.iter().position(|s| s.batch == ticket.0) {
+            Some(index) => TicketState::Submitted(index),
+            None => TicketState::Complete,
+        }
+    }
+}
+
+/// Whether the batch of `ticket` (and every one before it) completed.
+/// Batches still being recorded are not complete until flushed.
+pub unsafe fn is_complete(device: &Device, data: &AppData, ticket: UploadTicket) -> Result<bool> {
+    match data.uploader.ticket_state(ticket) {
+        TicketState::Recording => Ok(false),
+        TicketState::Submitted(index) => {
+            Ok(device.get_fence_status(data.uploader.staging[index].fence)? == vk::SuccessCode::SUCCESS)
+        }
+        TicketState::Complete => Ok(true),
+    }
+}
+
+/// Flushes the batch of `ticket` if it is still being recorded and waits
+/// for it to complete.
+pub unsafe fn wait(device: &Device, data: &mut AppData, ticket: UploadTicket) -> Result<()> {
+    if data.uploader.ticket_state(ticket) == TicketState::Recording {
+        flush(device, data)?;
+    }
+    if let TicketState::Submitted(index) = data.uploader.ticket_state(ticket) {
+        device.wait_for_fences(&[data.uploader.staging[index].fence], true, u64::MAX)?;
+    }
+    Ok(())
+}
+
+/// Creates a device local buffer with `usage` and starts uploading `bytes`
+/// into it. The buffer may only be used once the ticket completed.
+pub unsafe fn create_device_local_buffer(
+    device: &Device,
+    data: &mut AppData,
+    bytes: &[u8],
+    usage: vk::BufferUsageFlags,
+) -> Result<(vk::Buffer, Allocation, UploadTicket)> {
+    let (buffer, allocation) = create_buffer(
+        device,
+        data,
+        bytes.len() as vk::DeviceSize,
+        usage | vk::BufferUsageFlags::TRANSFER_DST,
+        AllocationRequest::device_local(ResourceKind::Linear),
+    )?;
+    match upload_buffer(device, data, bytes, buffer, 0) {
+        Ok(ticket) => Ok((buffer, allocation, ticket)),
+        Err(e) => {
+            destroy_buffer(device, data, buffer, &allocation);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_fitting_one_chunk() {
+        assert_eq!(split_rows(16, 256, 4096), [(0, 16)]);
+        assert_eq!(split_rows(16, 256, 1 << 20), [(0, 16)]);
+    }
+
+    #[test]
+    fn rows_split_into_chunks() {
+        assert_eq!(split_rows(10, 100, 400), [(0, 4), (4, 4), (8, 2)]);
+        assert_eq!(split_rows(8, 100, 399), [(0, 3), (3, 3), (6, 2)]);
+    }
+
+    #[test]
+    fn rows_larger_than_a_chunk_are_split_one_by_one() {
+        assert_eq!(split_rows(3, 1000, 10), [(0, 1), (1, 1), (2, 1)]);
+    }
+
+    #[test]
+    fn no_rows() {
+        assert!(split_rows(0, 256, 4096).is_empty());
+    }
+
+    #[test]
+    fn split_rows_cover_every_row_once() {
+        for (height, row_bytes, capacity) in [(1, 4, 4), (37, 12, 100), (1024, 4096, 16 << 20), (7, 0, 10)] {
+            let chunks = split_rows(height, row_bytes, capacity);
+            let mut next = 0;
+            for (first, count) in chunks {
+                assert_eq!(first, next);
+                assert!(count > 0 && (count == 1 || count as u64 * row_bytes <= capacity));
+                next += count;
+            }
+            assert_eq!(next, height);
+        }
+    }
+
+    fn uploader(batch: u64, staging_batches: &[u64]) -> Uploader {
+        let staging = staging_batches.iter().map(|&batch| StagingBuffer { batch, ..Default::default() }).collect();
+        Uploader { staging, batch, ..Default::default() }
+    }
+
+    #[test]
+    fn ticket_states() {
+        // Batches 3 and 4 were submitted from buffers 0 and 1, 5 is recording into buffer 2
+        let uploader = uploader(5, &[3, 4, 5]);
+        assert_eq!(uploader.ticket_state(UploadTicket(5)), TicketState::Recording);
+        assert_eq!(uploader.ticket_state(UploadTicket(4)), TicketState::Submitted(1));
+        assert_eq!(uploader.ticket_state(UploadTicket(3)), TicketState::Submitted(0));
+        assert_eq!(uploader.ticket_state(UploadTicket(2)), TicketState::Complete);
+        assert_eq!(uploader.ticket_state(UploadTicket(1)), TicketState::Complete);
+    }
+
+    #[test]
+    fn tickets_of_later_batches_are_recording() {
+        let uploader = uploader(1, &[1, 0, 0]);
+        assert_eq!(uploader.ticket_state(UploadTicket(1)), TicketState::Recording);
+        assert_eq!(uploader.ticket_state(UploadTicket(7)), TicketState::Recording);
+    }
+
+    #[test]
+    fn tickets_order_by_batch() {
+        let (first, second) = (UploadTicket(1), UploadTicket(2));
+        assert!(first < second);
+        assert_eq!(first.max(second), second);
+        assert_eq!([UploadTicket(3), first, second].into_iter().max(), Some(UploadTicket(3)));
+        assert!(UploadTicket::default() < first);
+    }
+}